@@ -1,12 +1,14 @@
 pub use anyhow::{Error, Result};
 pub use async_trait::async_trait;
-pub use futures::stream::Stream;
+pub use futures::stream::{BoxStream, Stream};
 pub use libipld::block::Block;
 pub use libipld::cid::Cid;
 pub use libipld::multihash::MultihashDigest;
 pub use libipld::store::{Store, StoreParams};
+pub use libp2p_core::identity::Keypair;
 pub use libp2p_core::{Multiaddr, PeerId};
 use std::collections::HashSet;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum NetworkEvent {
@@ -17,36 +19,153 @@ pub enum NetworkEvent {
     StartProvidingFailed(Cid),
     ReceivedBlock(PeerId, Cid, Vec<u8>),
     ReceivedWant(PeerId, Cid, i32),
+    Discovered(PeerId),
+    /// Dialing the peer failed on all of its known addresses.
+    ConnectFailed(PeerId),
+    /// The first connection to the peer was established.
+    PeerConnected(PeerId),
+    /// The last connection to the peer was closed.
+    PeerDisconnected(PeerId),
 }
 
+#[async_trait]
 pub trait Network<S: StoreParams>: Send + Sync + 'static {
     type Subscription: Stream<Item = NetworkEvent> + Send + Unpin;
-    fn local_peer_id(&self) -> &PeerId;
+    fn local_peer_id(&self) -> PeerId;
     fn external_addresses(&self) -> Vec<Multiaddr>;
+    /// Replaces the node identity. Existing connections are closed and peers
+    /// have to rediscover the node under the new peer id. Provider records are
+    /// republished under the new peer id.
+    async fn rotate_identity(&self, keypair: Keypair) -> Result<()>;
     fn providers(&self, cid: &Cid);
     fn provide(&self, cid: &Cid);
     fn unprovide(&self, cid: &Cid);
-    fn connect(&self, peer_id: PeerId);
+    /// Dials the peer. When dials are limited, queued dials with a higher priority
+    /// are started first.
+    fn connect(&self, peer_id: PeerId, priority: i32);
+    /// Dials the address, resolving once a connection is established. Resolves
+    /// right away when the address contains the peer id of a connected peer.
+    async fn connect_addr(&self, addr: Multiaddr) -> Result<()>;
+    /// Adds the nodes to the routing table and bootstraps from them.
+    fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]);
     fn want(&self, cid: Cid, priority: i32);
     fn cancel(&self, cid: Cid);
     fn send_to(&self, peer_id: PeerId, cid: Cid, data: Vec<u8>);
     fn send(&self, cid: Cid, data: Vec<u8>);
+    /// Subscribes to network events. Counts towards the subscriber limit, but
+    /// is never rejected.
     fn subscribe(&self) -> Self::Subscription;
+    /// Subscribes to network events. Fails when the subscriber limit is reached.
+    fn try_subscribe(&self) -> Result<Self::Subscription>;
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum StorageEvent {
+    /// A block that was already stored when subscribing.
+    Stored(Cid),
+    /// A block was written. Inserts of a block that is already stored don't emit
+    /// an event, even when they race with the write.
     Insert(Cid),
     Remove(Cid),
+    /// The subscriber fell behind and this many events were dropped.
+    Lagged(u64),
+}
+
+/// Context marking an error as transient, so that retrying the failed operation
+/// may succeed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Transient;
+
+impl std::fmt::Display for Transient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("transient error")
+    }
+}
+
+/// A block has more links than a dag walk follows per block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TooManyLinks(pub Cid);
+
+impl std::fmt::Display for TooManyLinks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Block {} has too many links.", self.0)
+    }
+}
+
+impl std::error::Error for TooManyLinks {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PinInfo {
+    pub alias: Vec<u8>,
+    pub cid: Cid,
+    pub blocks: usize,
+    pub size: Option<u64>,
 }
 
 #[async_trait]
 pub trait Storage<S: StoreParams>: Send + Sync + 'static {
     type Subscription: Stream<Item = StorageEvent> + Send + Unpin;
     fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>>;
-    fn insert(&self, block: &Block<S>) -> Result<()>;
+    /// Checks if a block is stored without reading it.
+    fn contains(&self, cid: &Cid) -> Result<bool>;
+    /// Marks a block as recently used without reading it.
+    fn touch(&self, cid: &Cid) -> Result<()>;
+    /// Inserts a block, returning `false` if it was already stored.
+    fn insert(&self, block: &Block<S>) -> Result<bool>;
     async fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()>;
+    async fn pin_depth<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: &Cid,
+        depth: usize,
+    ) -> Result<()>;
+    /// Points each alias to its cid in a single transaction.
+    async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()>;
+    /// Pins the dag rooted at `cid` without an alias. Repeated pins are counted.
+    async fn pin(&self, cid: &Cid) -> Result<()>;
+    /// Removes a pin added with `pin`.
+    async fn unpin(&self, cid: &Cid) -> Result<()>;
+    /// Pins the block `cid` without its links. Pinning it again does nothing.
+    async fn auto_pin(&self, cid: &Cid) -> Result<()>;
+    /// Removes a pin added with `auto_pin`.
+    async fn remove_auto_pin(&self, cid: &Cid) -> Result<()>;
+    /// Pins `cid` like `pin` until the returned lease is released or expires
+    /// after `ttl`. Expired leases are released periodically.
+    async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64>;
+    /// Releases a lease, doing nothing if it was already released.
+    async fn release(&self, lease: u64) -> Result<()>;
     fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>>;
+    fn resolve_many<T: AsRef<[u8]> + Send + Sync>(&self, aliases: &[T])
+        -> Result<Vec<Option<Cid>>>;
     async fn pinned(&self, cid: &Cid) -> Result<Option<bool>>;
+    /// Removes an unpinned block, returning `false` if it isn't stored. Fails if
+    /// the block is pinned.
+    async fn remove(&self, cid: &Cid) -> Result<bool>;
+    /// Returns the cids of all blocks reachable from `cid` that aren't stored.
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>>;
+    /// Tags a stored block. Tags don't affect garbage collection and are removed
+    /// together with the block.
+    fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()>;
+    /// Returns the cids of the stored blocks with the tag.
+    fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>>;
+    fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>>;
+    /// Streams all aliases and the cids they point to.
+    fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>>;
+    /// Streams the cids of all stored blocks.
+    fn blocks(&self) -> BoxStream<'static, Result<Cid>>;
+    /// Records that `peer_id` served us a block.
+    fn record_provider(&self, peer_id: &PeerId) -> Result<()>;
+    /// Returns up to `n` peers that served us blocks, preferring peers that did so
+    /// often and recently.
+    fn good_providers(&self, n: usize) -> Result<Vec<PeerId>>;
+    /// Records the number of providers found for `cid`.
+    fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()>;
+    /// Returns the recorded number of providers of `cid`, oldest first, with the
+    /// time of the sample in milliseconds since the unix epoch.
+    fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>>;
+    /// Subscribes to storage events. Counts towards the subscriber limit, but
+    /// is never rejected.
     fn subscribe(&self) -> Self::Subscription;
+    /// Subscribes to storage events. Fails when the subscriber limit is reached.
+    fn try_subscribe(&self) -> Result<Self::Subscription>;
 }
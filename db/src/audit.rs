@@ -0,0 +1,151 @@
+use futures::future::Future;
+use futures::stream::Stream;
+use ipfs_embed_core::{Cid, Result};
+use sled::Tree;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditAction {
+    Pin,
+    Unpin,
+    Evict,
+    Remove,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEvent {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub action: AuditAction,
+    pub cid: Cid,
+}
+
+impl AuditEvent {
+    fn encode(&self) -> Vec<u8> {
+        let action = match self.action {
+            AuditAction::Pin => 0,
+            AuditAction::Unpin => 1,
+            AuditAction::Evict => 2,
+            AuditAction::Remove => 3,
+        };
+        let mut buf = vec![action];
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.cid.to_bytes());
+        buf
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 9 {
+            return None;
+        }
+        let action = match bytes[0] {
+            0 => AuditAction::Pin,
+            1 => AuditAction::Unpin,
+            2 => AuditAction::Evict,
+            3 => AuditAction::Remove,
+            _ => return None,
+        };
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&bytes[1..9]);
+        let cid = Cid::try_from(&bytes[9..]).ok()?;
+        Some(Self {
+            timestamp: u64::from_be_bytes(timestamp),
+            action,
+            cid,
+        })
+    }
+}
+
+/// Append only log of pins, unpins and evictions. Nothing is recorded unless
+/// it's enabled.
+#[derive(Clone)]
+pub struct Audit {
+    db: sled::Db,
+    // id -> event
+    log: Tree,
+    enabled: bool,
+}
+
+impl Audit {
+    pub fn open(db: &sled::Db, enabled: bool) -> Result<Self> {
+        Ok(Self {
+            db: db.clone(),
+            log: db.open_tree("audit")?,
+            enabled,
+        })
+    }
+
+    /// Records an action that was already committed. Failing to record it is
+    /// only logged, the action itself can't be undone.
+    pub fn record(&self, action: AuditAction, cid: &Cid) {
+        if let Err(err) = self.try_record(action, cid) {
+            log::warn!(
+                "failed to record {:?} {} in the audit log: {}",
+                action,
+                cid,
+                err
+            );
+        }
+    }
+
+    fn try_record(&self, action: AuditAction, cid: &Cid) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_millis() as u64)
+            .unwrap_or_default();
+        let event = AuditEvent {
+            timestamp,
+            action,
+            cid: *cid,
+        };
+        let id = self.db.generate_id()?;
+        self.log.insert(&id.to_be_bytes(), event.encode())?;
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> AuditLog {
+        AuditLog {
+            events: Some(self.log.iter()),
+            subscriber: self.log.watch_prefix([]),
+        }
+    }
+}
+
+/// Stream of all recorded audit events followed by new ones as they are recorded.
+pub struct AuditLog {
+    events: Option<sled::Iter>,
+    subscriber: sled::Subscriber,
+}
+
+impl Stream for AuditLog {
+    type Item = AuditEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(events) = self.events.as_mut() {
+            for (_, value) in events.flatten() {
+                if let Some(event) = AuditEvent::decode(&value) {
+                    return Poll::Ready(Some(event));
+                }
+            }
+        }
+        self.events = None;
+        loop {
+            match Pin::new(&mut self.subscriber).poll(cx) {
+                Poll::Ready(Some(sled::Event::Insert { value, .. })) => {
+                    if let Some(event) = AuditEvent::decode(&value) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Poll::Ready(Some(sled::Event::Remove { .. })) => {}
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use ipfs_embed_core::{Cid, Result};
+use sled::Tree;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of samples kept per cid. Older samples are dropped.
+const MAX_SAMPLES: usize = 256;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[bytes.len() - 8..]);
+    Some(u64::from_be_bytes(buf))
+}
+
+/// Number of providers found for a cid over time.
+#[derive(Clone)]
+pub struct Availability {
+    // cid ++ timestamp -> number of providers
+    tree: Tree,
+}
+
+impl Availability {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("availability")?,
+        })
+    }
+
+    pub fn record(&self, cid: &Cid, providers: usize) -> Result<()> {
+        let prefix = cid.to_bytes();
+        let mut key = prefix.clone();
+        key.extend_from_slice(&now().to_be_bytes());
+        self.tree.insert(key, &(providers as u64).to_be_bytes())?;
+        let nsamples = self.tree.scan_prefix(&prefix).count();
+        if nsamples > MAX_SAMPLES {
+            for res in self
+                .tree
+                .scan_prefix(&prefix)
+                .keys()
+                .take(nsamples - MAX_SAMPLES)
+            {
+                self.tree.remove(res?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the samples of `cid`, oldest first, as milliseconds since the unix
+    /// epoch and the number of providers.
+    pub fn history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>> {
+        let mut samples = Vec::new();
+        for res in self.tree.scan_prefix(cid.to_bytes()) {
+            let (key, value) = res?;
+            if let (Some(timestamp), Some(providers)) = (decode_u64(&key), decode_u64(&value)) {
+                samples.push((timestamp, providers as usize));
+            }
+        }
+        Ok(samples)
+    }
+}
@@ -1,19 +1,28 @@
-use crate::id::{Id, Ids, LiveSet};
+use crate::audit::{Audit, AuditAction, AuditLog};
+use crate::config::{OverflowPolicy, StorageConfig};
+use crate::id::{Id, IdNotFound, Ids, IdsEncoding, LiveSet};
 use async_std::sync::Mutex;
-use fnv::FnvHashSet;
+use async_std::task;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::mpsc;
 use futures::future::Future;
 use futures::stream::Stream;
-use ipfs_embed_core::{Block, Cid, Error, Result, StorageEvent, StoreParams};
+use ipfs_embed_core::{
+    Block, Cid, Error, PinInfo, Result, StorageEvent, StoreParams, TooManyLinks,
+};
 use libipld::codec::Decode;
 use libipld::error::BlockNotFound;
 use libipld::ipld::Ipld;
-use sled::transaction::TransactionError;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::{IVec, Transactional, Tree};
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 fn map_tx_error(e: TransactionError<Error>) -> Error {
@@ -24,8 +33,105 @@ fn map_tx_error(e: TransactionError<Error>) -> Error {
 }
 
 #[derive(Debug, Error)]
-#[error("Id {0:?} not found.")]
-pub struct IdNotFound(Id);
+#[error("Block {0} exceeds the ipld nesting or size limit.")]
+pub struct IpldTooComplex(pub Cid);
+
+#[derive(Debug, Error)]
+#[error("Block {0} is pinned.")]
+pub struct BlockPinned(pub Cid);
+
+#[derive(Debug, Error)]
+#[error("The maximum of {0} aliases is reached.")]
+pub struct TooManyAliases(pub usize);
+
+#[derive(Debug, Error)]
+#[error("Too many subscribers, the limit is {0}.")]
+pub struct TooManySubscribers(pub usize);
+
+/// Collects the links in `ipld`. Fails when the ipld is nested more than
+/// `max_depth` levels deep, has more than `max_nodes` nodes or more than
+/// `max_links` distinct links, so that adversarial blocks can't exhaust
+/// resources while walking a dag.
+fn references(
+    cid: &Cid,
+    ipld: &Ipld,
+    max_depth: usize,
+    max_nodes: usize,
+    max_links: usize,
+) -> Result<FnvHashSet<Cid>> {
+    let mut refs = FnvHashSet::default();
+    let mut nodes = 0;
+    let mut stack = vec![(ipld, 0)];
+    while let Some((ipld, depth)) = stack.pop() {
+        nodes += 1;
+        if depth > max_depth || nodes > max_nodes {
+            return Err(IpldTooComplex(*cid).into());
+        }
+        match ipld {
+            Ipld::List(list) => stack.extend(list.iter().map(|ipld| (ipld, depth + 1))),
+            Ipld::Map(map) => stack.extend(map.values().map(|ipld| (ipld, depth + 1))),
+            Ipld::Link(link) => {
+                refs.insert(*link);
+                if refs.len() > max_links {
+                    return Err(TooManyLinks(*cid).into());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(refs)
+}
+
+/// Tags are stored as a length prefixed string, so that a tag is never the prefix
+/// of another tag.
+fn tag_prefix(tag: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(tag.len() + 12);
+    key.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    key.extend_from_slice(tag.as_bytes());
+    key
+}
+
+/// Splits the concatenated tag prefixes of a block.
+fn split_tags(mut tags: &[u8]) -> Vec<&[u8]> {
+    let mut prefixes = Vec::new();
+    while tags.len() >= 4 {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&tags[..4]);
+        let end = (4 + u32::from_be_bytes(len) as usize).min(tags.len());
+        prefixes.push(&tags[..end]);
+        tags = &tags[end..];
+    }
+    prefixes
+}
+
+/// The closure of a depth limited pin is stored under the root id followed by
+/// the depth, so that it doesn't collide with the full closure of the root.
+fn closure_key(id: &Id, depth: Option<u64>) -> IVec {
+    if let Some(depth) = depth {
+        let mut key = id.as_ref().to_vec();
+        key.extend_from_slice(&depth.to_be_bytes());
+        key.into()
+    } else {
+        id.into()
+    }
+}
+
+/// Milliseconds since the unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Key of the total size of all blocks in the meta tree.
+const BYTES_KEY: &[u8] = b"bytes";
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
 
 #[derive(Clone)]
 pub struct Blocks<S: StoreParams> {
@@ -42,21 +148,106 @@ pub struct Blocks<S: StoreParams> {
     atime: Tree,
     // atime -> id
     lru: Tree,
+    // tag prefix ++ id -> ()
+    tags: Tree,
+    // id -> tag prefixes
+    block_tags: Tree,
+    // BYTES_KEY -> total size of all blocks
+    meta: Tree,
+    // total size of all blocks
+    bytes: Arc<AtomicU64>,
+    // recently inserted blocks that aren't evicted yet
+    fresh: Arc<std::sync::Mutex<FnvHashMap<Id, Instant>>>,
+    min_residency: Duration,
+    max_ipld_depth: usize,
+    max_ipld_nodes: usize,
+    max_links: usize,
+    ids: &'static dyn IdsEncoding,
+    fanout: Fanout,
+    // number of open subscriptions
+    subscribers: Arc<AtomicUsize>,
+    max_subscribers: Option<usize>,
+}
+
+type Buffered = (mpsc::Sender<sled::Event>, Arc<AtomicU64>);
+
+/// Forwards the events of the lookup tree to the subscriptions with a `Drop`
+/// overflow policy. A single task drains the tree subscriber, so that writers
+/// never block on lagging subscriptions. `None` until the first such
+/// subscription starts the task.
+#[derive(Clone, Default)]
+struct Fanout(Arc<std::sync::Mutex<Option<Vec<Buffered>>>>);
+
+impl Fanout {
+    fn subscribe(&self, lookup: &Tree, buffered: Buffered) {
+        let mut subscriptions = self.0.lock().unwrap();
+        if subscriptions.is_none() {
+            let mut subscriber = lookup.watch_prefix([]);
+            let fanout = self.0.clone();
+            task::spawn(async move {
+                while let Some(event) = (&mut subscriber).await {
+                    let mut subscriptions = fanout.lock().unwrap();
+                    let subscriptions = subscriptions.get_or_insert_with(Vec::new);
+                    let mut i = 0;
+                    while i < subscriptions.len() {
+                        let (tx, dropped) = &mut subscriptions[i];
+                        if let Err(err) = tx.try_send(event.clone()) {
+                            if err.is_disconnected() {
+                                subscriptions.swap_remove(i);
+                                continue;
+                            }
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                            log::warn!("storage subscriber lagging, dropping event");
+                        }
+                        i += 1;
+                    }
+                }
+            });
+        }
+        subscriptions.get_or_insert_with(Vec::new).push(buffered);
+    }
 }
 
 impl<S: StoreParams> Blocks<S>
 where
     Ipld: Decode<S::Codecs>,
 {
-    pub fn open(db: &sled::Db) -> Result<Self> {
+    pub fn open(db: &sled::Db, config: &StorageConfig) -> Result<Self> {
+        let data = db.open_tree("data")?;
+        let meta = db.open_tree("meta")?;
+        let bytes = if let Some(bytes) = meta.get(BYTES_KEY)? {
+            decode_u64(&bytes)
+        } else {
+            // the store was created before the size was persisted
+            let mut bytes = 0;
+            for res in data.iter() {
+                let (_, value) = res?;
+                bytes += value.len() as u64;
+            }
+            meta.insert(BYTES_KEY, &bytes.to_be_bytes())?;
+            bytes
+        };
         Ok(Self {
             _marker: PhantomData,
             lookup: db.open_tree("lookup")?,
             cid: db.open_tree("cid")?,
-            data: db.open_tree("data")?,
+            data,
+            meta,
             refs: db.open_tree("refs")?,
             atime: db.open_tree("atime")?,
             lru: db.open_tree("lru")?,
+            tags: db.open_tree("tags")?,
+            block_tags: db.open_tree("block_tags")?,
+            bytes: Arc::new(AtomicU64::new(bytes)),
+            fresh: Default::default(),
+            min_residency: config.min_residency,
+            max_ipld_depth: config.max_ipld_depth,
+            max_ipld_nodes: config.max_ipld_nodes,
+            max_links: config.max_links,
+            ids: config.ids_format.encoding(),
+            fanout: Default::default(),
+            subscribers: Default::default(),
+            max_subscribers: config.max_subscribers,
         })
     }
 
@@ -68,6 +259,11 @@ where
         self.lookup.len()
     }
 
+    /// Total size of all blocks in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
     pub fn lookup_id(&self, cid: &Cid) -> Result<Option<Id>> {
         Ok(self.lookup.get(&cid.to_bytes())?.map(From::from))
     }
@@ -80,39 +276,105 @@ where
         }
     }
 
+    fn references(&self, block: &Block<S>) -> Result<FnvHashSet<Cid>> {
+        references(
+            block.cid(),
+            &block.ipld()?,
+            self.max_ipld_depth,
+            self.max_ipld_nodes,
+            self.max_links,
+        )
+    }
+
+    pub fn encode_ids(&self, ids: &Ids) -> IVec {
+        self.ids.encode(ids)
+    }
+
+    pub fn decode_ids(&self, bytes: IVec) -> Result<Ids> {
+        self.ids.decode(bytes)
+    }
+
     pub fn refs(&self, id: &Id) -> Result<Ids> {
-        if let Some(refs) = self.refs.get(id)?.map(From::from) {
-            return Ok(refs);
+        if let Some(refs) = self.refs.get(id)? {
+            return self.decode_ids(refs);
         }
         let cid = self.cid(id)?.ok_or_else(|| IdNotFound(id.clone()))?;
         let data = self.data.get(id)?.ok_or_else(|| IdNotFound(id.clone()))?;
         let block = Block::<S>::new_unchecked(cid, data.to_vec());
-        let cid_refs = block.ipld()?.references();
+        let cid_refs = self.references(&block)?;
         let mut refs = Vec::with_capacity(cid_refs.len() * 8);
         for cid in &cid_refs {
             let id = self.lookup_id(cid)?.ok_or_else(|| BlockNotFound(*cid))?;
             refs.extend_from_slice(id.as_ref());
         }
         let ids = Ids::from(IVec::from(refs));
-        self.refs.insert(id, &ids)?;
+        self.refs.insert(id, self.encode_ids(&ids))?;
         Ok(ids)
     }
 
-    pub fn closure(&self, cid: &Cid) -> Result<Ids> {
+    /// Returns the ids of all blocks reachable from `cid`. When a `depth` is given
+    /// only blocks at most `depth` links away from `cid` are included.
+    pub fn closure(&self, cid: &Cid, depth: Option<u64>) -> Result<Ids> {
         let id = self.lookup_id(cid)?.ok_or_else(|| BlockNotFound(*cid))?;
         let mut refs = FnvHashSet::default();
-        let mut todo = Vec::new();
-        todo.push(id);
-        while let Some(id) = todo.pop() {
+        // breadth first, so that a block is always reached on its shortest path
+        let mut todo = VecDeque::new();
+        todo.push_back((id, 0));
+        while let Some((id, level)) = todo.pop_front() {
             if refs.contains(&id) {
                 continue;
             }
-            todo.extend(self.refs(&id)?.iter());
+            if depth.map(|depth| level < depth).unwrap_or(true) {
+                todo.extend(self.refs(&id)?.iter().map(|id| (id, level + 1)));
+            }
             refs.insert(id);
         }
         Ok(Ids::from(&refs))
     }
 
+    /// Returns the cids of all blocks reachable from `cid` that aren't stored.
+    /// Unlike `closure` this doesn't stop at the first missing block.
+    pub fn missing(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        let mut seen = FnvHashSet::default();
+        let mut missing = Vec::new();
+        let mut todo = vec![*cid];
+        while let Some(cid) = todo.pop() {
+            if !seen.insert(cid) {
+                continue;
+            }
+            let data = self
+                .lookup_id(&cid)?
+                .map(|id| self.data.get(&id))
+                .transpose()?
+                .flatten();
+            if let Some(data) = data {
+                let block = Block::<S>::new_unchecked(cid, data.to_vec());
+                todo.extend(self.references(&block)?);
+            } else {
+                missing.push(cid);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn size(&self, ids: &Ids) -> Result<u64> {
+        let mut size = 0;
+        for id in ids.iter() {
+            if let Some(data) = self.data.get(&id)? {
+                size += data.len() as u64;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Returns the blocks inserted within the `min_residency`.
+    pub fn fresh(&self) -> FnvHashSet<Id> {
+        let mut fresh = self.fresh.lock().unwrap();
+        let min_residency = self.min_residency;
+        fresh.retain(|_, inserted| inserted.elapsed() < min_residency);
+        fresh.keys().cloned().collect()
+    }
+
     pub fn lru(&self) -> impl Iterator<Item = Result<Id>> {
         self.lru
             .iter()
@@ -120,20 +382,46 @@ where
             .map(|v| v.map(Into::into).map_err(Into::into))
     }
 
+    /// Unlike `get` this doesn't count as an access for the lru.
+    pub fn contains_cid(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.lookup.contains_key(&cid.to_bytes())?)
+    }
+
+    /// Iterates over the cids of all blocks without reading their data.
+    pub fn cids(&self) -> impl Iterator<Item = Result<Cid>> + Send {
+        self.lookup.iter().keys().map(|res| {
+            let cid = res?;
+            Ok(Cid::try_from(&cid[..])?)
+        })
+    }
+
+    /// Marks the block as the most recently used one.
+    fn touch(&self, id: &Id) -> Result<()> {
+        (&self.atime, &self.lru)
+            .transaction(|(tatime, tlru)| {
+                let atime: Id = tlru.generate_id()?.into();
+                if let Some(atime) = tatime.remove(id)? {
+                    tlru.remove(atime)?;
+                }
+                tlru.insert(&atime, id)?;
+                tatime.insert(id, &atime)?;
+                Ok(())
+            })
+            .map_err(map_tx_error)
+    }
+
+    /// Counts as an access for the lru without reading the block.
+    pub fn touch_cid(&self, cid: &Cid) -> Result<()> {
+        if let Some(id) = self.lookup_id(cid)? {
+            self.touch(&id)?;
+        }
+        Ok(())
+    }
+
     pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
         if let Some(id) = self.lookup_id(cid)? {
             if let Some(data) = self.data.get(&id)? {
-                (&self.atime, &self.lru)
-                    .transaction(|(tatime, tlru)| {
-                        let atime: Id = tlru.generate_id()?.into();
-                        if let Some(atime) = tatime.remove(&id)? {
-                            tlru.remove(atime)?;
-                        }
-                        tlru.insert(&atime, &id)?;
-                        tatime.insert(&id, &atime)?;
-                        Ok(())
-                    })
-                    .map_err(map_tx_error)?;
+                self.touch(&id)?;
                 log::debug!("hit {}", id);
                 return Ok(Some(data.to_vec()));
             }
@@ -142,13 +430,20 @@ where
         Ok(None)
     }
 
-    pub fn insert(&self, block: &Block<S>) -> Result<()> {
+    pub fn insert(&self, block: &Block<S>) -> Result<bool> {
         let cid = IVec::from(block.cid().to_bytes());
         let data = block.data();
-        let id = (&self.lookup, &self.cid, &self.data, &self.atime, &self.lru)
-            .transaction(|(tlookup, tcid, tdata, tatime, tlru)| {
+        let (id, inserted) = (
+            &self.lookup,
+            &self.cid,
+            &self.data,
+            &self.atime,
+            &self.lru,
+            &self.meta,
+        )
+            .transaction(|(tlookup, tcid, tdata, tatime, tlru, tmeta)| {
                 if let Some(id) = tlookup.get(&cid)? {
-                    return Ok(Id::from(id));
+                    return Ok((Id::from(id), false));
                 }
                 let id: Id = tlookup.generate_id()?.into();
                 let atime: Id = tlru.generate_id()?.into();
@@ -157,51 +452,190 @@ where
                 tdata.insert(&id, data)?;
                 tatime.insert(&id, &atime)?;
                 tlru.insert(&atime, &id)?;
-                Ok(id)
+                let bytes = tmeta.get(BYTES_KEY)?.map(|b| decode_u64(&b)).unwrap_or(0);
+                tmeta.insert(BYTES_KEY, &(bytes + data.len() as u64).to_be_bytes())?;
+                Ok((id, true))
             })
             .map_err(map_tx_error)?;
+        if inserted {
+            self.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            if self.min_residency > Duration::from_secs(0) {
+                self.fresh
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), Instant::now());
+            }
+        }
         log::debug!("insert {}", id);
-        Ok(())
+        Ok(inserted)
     }
 
-    pub fn remove(&self, id: &Id) -> Result<()> {
-        (
+    pub fn remove(&self, id: &Id) -> Result<Option<Cid>> {
+        let (cid, len) = (
             &self.lookup,
             &self.cid,
             &self.data,
             &self.refs,
             &self.atime,
             &self.lru,
+            &self.tags,
+            &self.block_tags,
+            &self.meta,
         )
-            .transaction(|(tlookup, tcid, tdata, trefs, tatime, tlru)| {
-                if let Some(cid) = tcid.remove(id)? {
-                    tdata.remove(id)?;
-                    tlookup.remove(&cid)?;
-                    trefs.remove(id)?;
-                    if let Some(atime) = tatime.remove(id)? {
-                        tlru.remove(atime)?;
+            .transaction(
+                |(tlookup, tcid, tdata, trefs, tatime, tlru, ttags, tblock_tags, tmeta)| {
+                    let cid = tcid.remove(id)?;
+                    let mut len = 0;
+                    if let Some(cid) = cid.as_ref() {
+                        if let Some(data) = tdata.remove(id)? {
+                            len = data.len() as u64;
+                            let bytes = tmeta.get(BYTES_KEY)?.map(|b| decode_u64(&b));
+                            let bytes = bytes.unwrap_or(0).saturating_sub(len);
+                            tmeta.insert(BYTES_KEY, &bytes.to_be_bytes())?;
+                        }
+                        tlookup.remove(cid)?;
+                        trefs.remove(id)?;
+                        if let Some(atime) = tatime.remove(id)? {
+                            tlru.remove(atime)?;
+                        }
+                        if let Some(tags) = tblock_tags.remove(id)? {
+                            for prefix in split_tags(&tags) {
+                                let mut key = prefix.to_vec();
+                                key.extend_from_slice(id.as_ref());
+                                ttags.remove(key)?;
+                            }
+                        }
                     }
+                    Ok((cid, len))
+                },
+            )
+            .map_err(map_tx_error)?;
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+        log::debug!("remove {}", id);
+        if let Some(cid) = cid {
+            Ok(Some(Cid::try_from(&cid[..])?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Tags a stored block. Tags are removed together with the block.
+    pub fn tag(&self, cid: &Cid, tag: &str) -> Result<()> {
+        let id = self.lookup_id(cid)?.ok_or_else(|| BlockNotFound(*cid))?;
+        let prefix = tag_prefix(tag);
+        let mut key = prefix.clone();
+        key.extend_from_slice(id.as_ref());
+        (&self.cid, &self.tags, &self.block_tags)
+            .transaction(|(tcid, ttags, tblock_tags)| {
+                // the block may have been removed in the meantime
+                if tcid.get(&id)?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(
+                        BlockNotFound(*cid).into(),
+                    ));
+                }
+                if ttags.insert(key.clone(), &[][..])?.is_none() {
+                    let mut tags = tblock_tags
+                        .get(&id)?
+                        .map(|tags| tags.to_vec())
+                        .unwrap_or_default();
+                    tags.extend_from_slice(&prefix);
+                    tblock_tags.insert(&id, tags)?;
                 }
                 Ok(())
             })
-            .map_err(map_tx_error)?;
-        log::debug!("remove {}", id);
-        Ok(())
+            .map_err(map_tx_error)
+    }
+
+    /// Returns the cids of the stored blocks with the tag.
+    pub fn by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        let prefix = tag_prefix(tag);
+        let mut cids = Vec::new();
+        for res in self.tags.scan_prefix(&prefix) {
+            let (key, _) = res?;
+            let id = Id::from(IVec::from(&key[prefix.len()..]));
+            if let Some(cid) = self.cid(&id)? {
+                cids.push(cid);
+            }
+        }
+        Ok(cids)
+    }
+
+    /// Number of open subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.load(Ordering::SeqCst)
+    }
+
+    /// Subscribes to storage events. Counts towards `max_subscribers`, but is
+    /// never rejected.
+    pub fn subscribe(&self, overflow: OverflowPolicy) -> Subscription {
+        self.subscribers.fetch_add(1, Ordering::SeqCst);
+        self.subscription(overflow)
     }
 
-    pub fn subscribe(&self) -> Subscription {
-        let subscriber = self.lookup.watch_prefix([]);
+    /// Subscribes to storage events. Fails when `max_subscribers` subscriptions
+    /// are open.
+    pub fn try_subscribe(&self, overflow: OverflowPolicy) -> Result<Subscription> {
+        if let Some(max) = self.max_subscribers {
+            self.subscribers
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    if n < max {
+                        Some(n + 1)
+                    } else {
+                        None
+                    }
+                })
+                .map_err(|_| TooManySubscribers(max))?;
+        } else {
+            self.subscribers.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(self.subscription(overflow))
+    }
+
+    fn subscription(&self, overflow: OverflowPolicy) -> Subscription {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let events = match overflow {
+            OverflowPolicy::Block => Events::Direct(self.lookup.watch_prefix([])),
+            OverflowPolicy::Drop(capacity) => {
+                let (tx, rx) = mpsc::channel(capacity);
+                self.fanout.subscribe(&self.lookup, (tx, dropped.clone()));
+                Events::Buffered(rx)
+            }
+        };
         let keys = self.lookup.scan_prefix([]);
         Subscription {
             keys: Some(keys),
-            subscriber,
+            events,
+            dropped,
+            reported: 0,
+            subscribers: self.subscribers.clone(),
         }
     }
 }
 
+enum Events {
+    Direct(sled::Subscriber),
+    Buffered(mpsc::Receiver<sled::Event>),
+}
+
 pub struct Subscription {
     keys: Option<sled::Iter>,
-    subscriber: sled::Subscriber,
+    events: Events,
+    dropped: Arc<AtomicU64>,
+    reported: u64,
+    subscribers: Arc<AtomicUsize>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Subscription {
+    /// Returns the number of events dropped because the subscriber fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
 }
 
 impl Stream for Subscription {
@@ -211,12 +645,22 @@ impl Stream for Subscription {
         if let Some(keys) = self.keys.as_mut() {
             if let Some(Ok((key, _))) = keys.next() {
                 let cid = Cid::try_from(&key[..]).unwrap();
-                let entry = StorageEvent::Insert(cid);
+                let entry = StorageEvent::Stored(cid);
                 return Poll::Ready(Some(entry));
             }
         }
         self.keys = None;
-        match Pin::new(&mut self.subscriber).poll(cx) {
+        let dropped = self.dropped();
+        if dropped > self.reported {
+            let lagged = dropped - self.reported;
+            self.reported = dropped;
+            return Poll::Ready(Some(StorageEvent::Lagged(lagged)));
+        }
+        let event = match &mut self.events {
+            Events::Direct(subscriber) => Pin::new(subscriber).poll(cx),
+            Events::Buffered(rx) => Pin::new(rx).poll_next(cx),
+        };
+        match event {
             Poll::Ready(Some(sled::Event::Insert { key, .. })) => {
                 let cid = Cid::try_from(&key[..]).unwrap();
                 let entry = StorageEvent::Insert(cid);
@@ -238,32 +682,96 @@ pub struct Aliases<S: StoreParams> {
     blocks: Blocks<S>,
     // [u8] -> id
     alias: Tree,
+    // [u8] -> depth
+    depth: Tree,
+    // id -> number of pins without an alias
+    pins: Tree,
+    // id -> (), blocks pinned without their links
+    auto_pins: Tree,
+    // lease -> expiry ++ cid
+    leases: Tree,
     // live
     filter: Arc<Mutex<LiveSet>>,
-    // id -> [u64]
+    // id | id ++ depth -> [u64]
     closure: Tree,
+    // id | id ++ depth -> number of aliases and pins using the closure, updated
+    // while holding the filter lock
+    closure_users: Arc<std::sync::Mutex<FnvHashMap<IVec, u64>>>,
+    audit: Audit,
+    flush: bool,
+    max_aliases: Option<usize>,
+    // number of entries in `alias`, updated while holding the filter lock
+    count: Arc<AtomicUsize>,
 }
 
 impl<S: StoreParams> Aliases<S>
 where
     Ipld: Decode<S::Codecs>,
 {
-    pub fn open(db: &sled::Db) -> Result<Self> {
-        let blocks = Blocks::open(db)?;
+    pub fn open(db: &sled::Db, config: &StorageConfig) -> Result<Self> {
+        let blocks = Blocks::open(db, config)?;
         let alias = db.open_tree("alias")?;
+        let depth = db.open_tree("depth")?;
         let closure = db.open_tree("closure")?;
+        let pins = db.open_tree("pins")?;
+        let auto_pins = db.open_tree("auto_pins")?;
         let mut filter = LiveSet::new();
-        for res in alias.iter().values() {
-            let id = res?;
-            for id in Ids::from(closure.get(&id)?.unwrap()).iter() {
-                filter.add(&id)?;
+        let mut closure_users: FnvHashMap<IVec, u64> = FnvHashMap::default();
+        for res in auto_pins.iter() {
+            let (id, _) = res?;
+            filter.add(&Id::from(id));
+        }
+        for res in pins.iter() {
+            let (id, _) = res?;
+            let id = Id::from(id);
+            let ids = if let Some(ids) = closure.get(&id)? {
+                blocks.decode_ids(ids)?
+            } else {
+                log::warn!("recomputing closure of {}", id);
+                let cid = blocks.cid(&id)?.ok_or_else(|| IdNotFound(id.clone()))?;
+                let ids = blocks.closure(&cid, None)?;
+                closure.insert(&id, blocks.encode_ids(&ids))?;
+                ids
+            };
+            *closure_users.entry(closure_key(&id, None)).or_default() += 1;
+            for id in ids.iter() {
+                filter.add(&id);
+            }
+        }
+        for res in alias.iter() {
+            let (key, id) = res?;
+            let id = Id::from(id);
+            let pin_depth = depth.get(&key)?.map(|d| decode_u64(&d));
+            let key = closure_key(&id, pin_depth);
+            let ids = if let Some(ids) = closure.get(&key)? {
+                blocks.decode_ids(ids)?
+            } else {
+                // the alias was written without its closure, recompute it
+                log::warn!("recomputing closure of {}", id);
+                let cid = blocks.cid(&id)?.ok_or_else(|| IdNotFound(id.clone()))?;
+                let ids = blocks.closure(&cid, pin_depth)?;
+                closure.insert(&key, blocks.encode_ids(&ids))?;
+                ids
+            };
+            *closure_users.entry(key).or_default() += 1;
+            for id in ids.iter() {
+                filter.add(&id);
             }
         }
         Ok(Self {
             blocks,
             alias,
+            depth,
+            pins,
+            auto_pins,
+            leases: db.open_tree("leases")?,
             closure,
+            closure_users: Arc::new(std::sync::Mutex::new(closure_users)),
             filter: Arc::new(Mutex::new(filter)),
+            audit: Audit::open(db, config.audit)?,
+            flush: config.flush_aliases,
+            max_aliases: config.max_aliases,
+            count: Arc::new(AtomicUsize::new(alias.len())),
         })
     }
 
@@ -271,34 +779,104 @@ where
         self.blocks.get(cid)
     }
 
-    pub fn insert(&self, block: &Block<S>) -> Result<()> {
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.blocks.contains_cid(cid)
+    }
+
+    pub fn touch(&self, cid: &Cid) -> Result<()> {
+        self.blocks.touch_cid(cid)
+    }
+
+    pub fn insert(&self, block: &Block<S>) -> Result<bool> {
         self.blocks.insert(block)
     }
 
+    /// Number of aliases and pins using the closure stored under `key`. Must be
+    /// called while holding the filter lock.
+    fn closure_users(&self, key: &IVec) -> u64 {
+        let users = self.closure_users.lock().unwrap();
+        users.get(key).copied().unwrap_or_default()
+    }
+
+    /// Records that the closures stored under `added` gained a user and the
+    /// ones under `removed` lost one. Must be called while holding the filter
+    /// lock, after the closures were written.
+    fn update_closure_users(&self, added: &[IVec], removed: &[IVec]) {
+        let mut users = self.closure_users.lock().unwrap();
+        for key in added {
+            *users.entry(key.clone()).or_default() += 1;
+        }
+        for key in removed {
+            if let Some(n) = users.get_mut(key) {
+                *n = n.saturating_sub(1);
+                if *n == 0 {
+                    users.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Fails when adding `added` aliases exceeds `max_aliases`. Must be called
+    /// while holding the filter lock.
+    fn check_count(&self, added: usize) -> Result<()> {
+        if let Some(max) = self.max_aliases {
+            if added > 0 && self.count.load(Ordering::SeqCst) + added > max {
+                return Err(TooManyAliases(max).into());
+            }
+        }
+        Ok(())
+    }
+
     pub async fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
-        let closure = if let Some(cid) = cid {
-            self.blocks.closure(cid)?
+        self.alias_depth(alias, cid, None).await
+    }
+
+    /// Points the alias to `cid`, keeping all blocks at most `depth` links away
+    /// from `cid` alive. Without a `depth` the whole dag is kept alive.
+    pub async fn alias_depth(
+        &self,
+        alias: &[u8],
+        cid: Option<&Cid>,
+        depth: Option<u64>,
+    ) -> Result<()> {
+        let (id, closure) = if let Some(cid) = cid {
+            let id = self
+                .blocks
+                .lookup_id(cid)?
+                .ok_or_else(|| BlockNotFound(*cid))?;
+            (Some(id), self.blocks.closure(cid, depth)?)
         } else {
-            Default::default()
+            (None, Default::default())
         };
-        let id = closure.iter().next();
-        log::debug!("alias {:?} {:?}", alias, id.as_ref());
+        log::debug!("alias {:?} {:?} {:?}", alias, id.as_ref(), depth);
 
+        // the previous alias is read under the lock, so that concurrent updates
+        // of the alias don't both release its closure
+        let mut filter = self.filter.lock().await;
         let prev_id = self.alias.get(alias)?.map(Id::from);
+        let prev_depth = self.depth.get(alias)?.map(|d| decode_u64(&d));
         let prev_closure = if let Some(id) = prev_id.as_ref() {
-            self.closure.get(id)?.map(Ids::from).unwrap_or_default()
+            self.closure
+                .get(closure_key(id, prev_depth))?
+                .map(|ids| self.blocks.decode_ids(ids))
+                .transpose()?
+                .unwrap_or_default()
         } else {
             Default::default()
         };
 
-        let mut filter = self.filter.lock().await;
+        let added = prev_id.is_none() && id.is_some();
+        let removed = prev_id.is_some() && id.is_none();
+        if added {
+            self.check_count(1)?;
+        }
         for id in closure.iter() {
             if !self.blocks.contains(&id)? {
                 return Err(IdNotFound(id).into());
             }
         }
         for id in closure.iter() {
-            filter.add(&id).unwrap();
+            filter.add(&id);
             log::debug!("pinned {}", id);
         }
         for id in prev_closure.iter() {
@@ -306,23 +884,30 @@ where
             log::debug!("unpinned {}", id);
         }
 
-        let rm_closure = if let Some(id) = prev_id.as_ref() {
-            !filter.contains(id)
-        } else {
-            false
-        };
+        // the closure may still be used by other aliases or pins
+        let prev_key = prev_id.as_ref().map(|id| closure_key(id, prev_depth));
+        let rm_closure = prev_key
+            .as_ref()
+            .map(|key| self.closure_users(key) <= 1)
+            .unwrap_or_default();
+        let key = id.as_ref().map(|id| closure_key(id, depth));
 
-        let res = (&self.alias, &self.closure)
-            .transaction(|(talias, tclosure)| {
-                if let Some(id) = prev_id.as_ref() {
+        let encoded = self.blocks.encode_ids(&closure);
+        let res = (&self.alias, &self.depth, &self.closure)
+            .transaction(|(talias, tdepth, tclosure)| {
+                if let Some(prev_key) = prev_key.as_ref() {
                     talias.remove(alias)?;
+                    tdepth.remove(alias)?;
                     if rm_closure {
-                        tclosure.remove(id)?;
+                        tclosure.remove(prev_key)?;
                     }
                 }
-                if let Some(id) = id.as_ref() {
+                if let (Some(id), Some(key)) = (id.as_ref(), key.as_ref()) {
                     talias.insert(alias, id)?;
-                    tclosure.insert(id, &closure)?;
+                    if let Some(depth) = depth {
+                        tdepth.insert(alias, &depth.to_be_bytes())?;
+                    }
+                    tclosure.insert(key, encoded.clone())?;
                 }
                 Ok(())
             })
@@ -330,17 +915,364 @@ where
 
         if res.is_err() {
             for id in prev_closure.iter() {
-                filter.add(&id).unwrap();
+                filter.add(&id);
                 log::debug!("pinned {}", id);
             }
             for id in closure.iter() {
                 filter.delete(&id);
                 log::debug!("unpinned {}", id);
             }
+        } else {
+            let added_keys: Vec<IVec> = key.into_iter().collect();
+            let removed_keys: Vec<IVec> = prev_key.into_iter().collect();
+            self.update_closure_users(&added_keys, &removed_keys);
+            if added {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            } else if removed {
+                self.count.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        drop(filter);
+
+        res?;
+        if self.flush {
+            self.alias.flush_async().await?;
+        }
+        if let Some(id) = prev_id.as_ref() {
+            if let Ok(Some(cid)) = self.blocks.cid(id) {
+                self.audit.record(AuditAction::Unpin, &cid);
+            }
+        }
+        if let Some(cid) = cid {
+            self.audit.record(AuditAction::Pin, cid);
+        }
+        Ok(())
+    }
+
+    /// Points each alias to its cid, keeping the full dags alive. Either all or
+    /// none of the aliases are updated. When an alias occurs more than once the
+    /// last cid wins.
+    pub async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()> {
+        let mut last = FnvHashMap::default();
+        for (i, (alias, _)) in pins.iter().enumerate() {
+            last.insert(&alias[..], i);
+        }
+        let mut new = Vec::with_capacity(last.len());
+        let mut closures = Vec::with_capacity(last.len());
+        let mut prev = Vec::with_capacity(last.len());
+        let mut prev_closures = Vec::with_capacity(last.len());
+        // the previous aliases are read under the lock, see `alias_depth`
+        let mut filter = self.filter.lock().await;
+        for (i, (alias, cid)) in pins.iter().enumerate() {
+            if last.get(&alias[..]) != Some(&i) {
+                continue;
+            }
+            let id = self
+                .blocks
+                .lookup_id(cid)?
+                .ok_or_else(|| BlockNotFound(*cid))?;
+            closures.push(self.blocks.closure(cid, None)?);
+            new.push((&alias[..], id));
+
+            if let Some(id) = self.alias.get(alias)?.map(Id::from) {
+                let depth = self.depth.get(alias)?.map(|d| decode_u64(&d));
+                prev_closures.push(
+                    self.closure
+                        .get(closure_key(&id, depth))?
+                        .map(|ids| self.blocks.decode_ids(ids))
+                        .transpose()?
+                        .unwrap_or_default(),
+                );
+                prev.push((&alias[..], id, depth));
+            }
+        }
+        let closure = Ids::concat(&closures);
+        let prev_closure = Ids::concat(&prev_closures);
+        log::debug!("pin many {}", new.len());
+
+        let added = new.len() - prev.len();
+        self.check_count(added)?;
+        for id in closure.iter() {
+            if !self.blocks.contains(&id)? {
+                return Err(IdNotFound(id).into());
+            }
+        }
+        for id in closure.iter() {
+            filter.add(&id);
+        }
+        for id in prev_closure.iter() {
+            filter.delete(&id);
+        }
+        // a closure is removed once all of its users are replaced
+        let prev_keys: Vec<IVec> = prev
+            .iter()
+            .map(|(_, id, depth)| closure_key(id, *depth))
+            .collect();
+        let mut replaced: FnvHashMap<&IVec, u64> = FnvHashMap::default();
+        for key in &prev_keys {
+            *replaced.entry(key).or_default() += 1;
+        }
+        let rm_closure: Vec<bool> = prev_keys
+            .iter()
+            .map(|key| self.closure_users(key) <= replaced[key])
+            .collect();
+        let new_keys: Vec<IVec> = new.iter().map(|(_, id)| closure_key(id, None)).collect();
+
+        let encoded: Vec<IVec> = closures
+            .iter()
+            .map(|ids| self.blocks.encode_ids(ids))
+            .collect();
+        let res = (&self.alias, &self.depth, &self.closure)
+            .transaction(|(talias, tdepth, tclosure)| {
+                for (((alias, _, _), key), rm) in prev.iter().zip(&prev_keys).zip(&rm_closure) {
+                    talias.remove(*alias)?;
+                    tdepth.remove(*alias)?;
+                    if *rm {
+                        tclosure.remove(key)?;
+                    }
+                }
+                for (((alias, id), key), closure) in new.iter().zip(&new_keys).zip(&encoded) {
+                    talias.insert(*alias, id)?;
+                    tclosure.insert(key, closure.clone())?;
+                }
+                Ok(())
+            })
+            .map_err(map_tx_error);
+
+        if res.is_err() {
+            for id in prev_closure.iter() {
+                filter.add(&id);
+            }
+            for id in closure.iter() {
+                filter.delete(&id);
+            }
+        } else {
+            self.update_closure_users(&new_keys, &prev_keys);
+            self.count.fetch_add(added, Ordering::SeqCst);
+        }
+        drop(filter);
+
+        res?;
+        if self.flush {
+            self.alias.flush_async().await?;
+        }
+        for (_, id, _) in prev.iter() {
+            if let Ok(Some(cid)) = self.blocks.cid(id) {
+                self.audit.record(AuditAction::Unpin, &cid);
+            }
+        }
+        for (_, id) in new.iter() {
+            if let Ok(Some(cid)) = self.blocks.cid(id) {
+                self.audit.record(AuditAction::Pin, &cid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pins the dag rooted at `cid` without an alias. Pins are counted, the dag
+    /// stays pinned until it was unpinned as often as it was pinned.
+    pub async fn pin(&self, cid: &Cid) -> Result<()> {
+        let id = self
+            .blocks
+            .lookup_id(cid)?
+            .ok_or_else(|| BlockNotFound(*cid))?;
+        let mut filter = self.filter.lock().await;
+        let count = self.pins.get(&id)?.map(|c| decode_u64(&c)).unwrap_or(0);
+        if count > 0 {
+            self.pins.insert(&id, &(count + 1).to_be_bytes())?;
+            return Ok(());
+        }
+        let closure = self.blocks.closure(cid, None)?;
+        for id in closure.iter() {
+            if !self.blocks.contains(&id)? {
+                return Err(IdNotFound(id).into());
+            }
+        }
+        log::debug!("pin {}", id);
+        let encoded = self.blocks.encode_ids(&closure);
+        let key = closure_key(&id, None);
+        (&self.pins, &self.closure)
+            .transaction(|(tpins, tclosure)| {
+                tpins.insert(&id, &1u64.to_be_bytes())?;
+                tclosure.insert(&key, encoded.clone())?;
+                Ok(())
+            })
+            .map_err(map_tx_error)?;
+        self.update_closure_users(&[key], &[]);
+        for id in closure.iter() {
+            filter.add(&id);
+        }
+        drop(filter);
+
+        if self.flush {
+            self.pins.flush_async().await?;
+        }
+        self.audit.record(AuditAction::Pin, cid);
+        Ok(())
+    }
+
+    /// Pins the block `cid` without its links. Unlike `pin` this isn't counted,
+    /// pinning a block that is already auto-pinned does nothing.
+    pub async fn auto_pin(&self, cid: &Cid) -> Result<()> {
+        let id = self
+            .blocks
+            .lookup_id(cid)?
+            .ok_or_else(|| BlockNotFound(*cid))?;
+        let mut filter = self.filter.lock().await;
+        if self.auto_pins.insert(&id, &[])?.is_some() {
+            return Ok(());
+        }
+        filter.add(&id);
+        drop(filter);
+
+        if self.flush {
+            self.auto_pins.flush_async().await?;
+        }
+        self.audit.record(AuditAction::Pin, cid);
+        Ok(())
+    }
+
+    /// Removes a pin added with `auto_pin`. Does nothing if `cid` isn't
+    /// auto-pinned.
+    pub async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+        let id = if let Some(id) = self.blocks.lookup_id(cid)? {
+            id
+        } else {
+            return Ok(());
+        };
+        let mut filter = self.filter.lock().await;
+        if self.auto_pins.remove(&id)?.is_none() {
+            return Ok(());
+        }
+        filter.delete(&id);
+        drop(filter);
+
+        if self.flush {
+            self.auto_pins.flush_async().await?;
+        }
+        self.audit.record(AuditAction::Unpin, cid);
+        Ok(())
+    }
+
+    /// Removes a pin added with `pin`. Does nothing if `cid` isn't pinned without
+    /// an alias.
+    pub async fn unpin(&self, cid: &Cid) -> Result<()> {
+        let id = if let Some(id) = self.blocks.lookup_id(cid)? {
+            id
+        } else {
+            return Ok(());
+        };
+        let mut filter = self.filter.lock().await;
+        let count = self.pins.get(&id)?.map(|c| decode_u64(&c)).unwrap_or(0);
+        if count == 0 {
+            return Ok(());
+        }
+        if count > 1 {
+            self.pins.insert(&id, &(count - 1).to_be_bytes())?;
+            return Ok(());
+        }
+        let key = closure_key(&id, None);
+        let closure = self
+            .closure
+            .get(&key)?
+            .map(|ids| self.blocks.decode_ids(ids))
+            .transpose()?
+            .unwrap_or_default();
+        log::debug!("unpin {}", id);
+        for id in closure.iter() {
+            filter.delete(&id);
+        }
+        // the closure is shared with aliases pointing to the same block
+        let rm_closure = self.closure_users(&key) <= 1;
+        let res = (&self.pins, &self.closure)
+            .transaction(|(tpins, tclosure)| {
+                tpins.remove(&id)?;
+                if rm_closure {
+                    tclosure.remove(&key)?;
+                }
+                Ok(())
+            })
+            .map_err(map_tx_error);
+        if res.is_err() {
+            for id in closure.iter() {
+                filter.add(&id);
+            }
+        } else {
+            self.update_closure_users(&[], &[key]);
         }
         drop(filter);
 
-        res
+        res?;
+        if self.flush {
+            self.pins.flush_async().await?;
+        }
+        self.audit.record(AuditAction::Unpin, cid);
+        Ok(())
+    }
+
+    /// Pins `cid` until the returned lease is released or expires after `ttl`.
+    pub async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64> {
+        self.pin(cid).await?;
+        let expiry = now() + ttl.as_millis() as u64;
+        let mut value = expiry.to_be_bytes().to_vec();
+        value.extend_from_slice(&cid.to_bytes());
+        let res = self
+            .leases
+            .transaction(|tleases| {
+                let lease = tleases.generate_id()?;
+                tleases.insert(&lease.to_be_bytes(), value.clone())?;
+                Ok(lease)
+            })
+            .map_err(map_tx_error);
+        match res {
+            Ok(lease) => {
+                log::debug!("lease {} of {}", lease, cid.to_string());
+                Ok(lease)
+            }
+            Err(err) => {
+                self.unpin(cid).await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Releases a lease, unpinning its cid. Does nothing if the lease was already
+    /// released.
+    pub async fn release(&self, lease: u64) -> Result<()> {
+        if let Some(value) = self.leases.remove(&lease.to_be_bytes())? {
+            let cid = Cid::try_from(&value[8..])?;
+            log::debug!("release {} of {}", lease, cid.to_string());
+            self.unpin(&cid).await?;
+        }
+        Ok(())
+    }
+
+    /// Releases the expired leases.
+    pub async fn reap_leases(&self) -> Result<()> {
+        let now = now();
+        let mut expired = Vec::new();
+        for res in self.leases.iter() {
+            let (lease, value) = res?;
+            if decode_u64(&value) <= now {
+                expired.push(decode_u64(&lease));
+            }
+        }
+        for lease in expired {
+            self.release(lease).await?;
+        }
+        Ok(())
+    }
+
+    pub fn missing(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.blocks.missing(cid)
+    }
+
+    pub fn tag(&self, cid: &Cid, tag: &str) -> Result<()> {
+        self.blocks.tag(cid, tag)
+    }
+
+    pub fn by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        self.blocks.by_tag(tag)
     }
 
     pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
@@ -351,6 +1283,75 @@ where
         }
     }
 
+    /// Resolves each alias with a point lookup in the alias tree. Aliases that
+    /// point to the same block only look up its cid once.
+    pub fn resolve_many<T: AsRef<[u8]>>(&self, aliases: &[T]) -> Result<Vec<Option<Cid>>> {
+        let mut cids: FnvHashMap<Id, Option<Cid>> = FnvHashMap::default();
+        let mut resolved = Vec::with_capacity(aliases.len());
+        for alias in aliases {
+            let id = if let Some(id) = self.alias.get(alias.as_ref())? {
+                Id::from(id)
+            } else {
+                resolved.push(None);
+                continue;
+            };
+            let cid = match cids.get(&id) {
+                Some(cid) => *cid,
+                None => {
+                    let cid = self.blocks.cid(&id)?;
+                    cids.insert(id, cid);
+                    cid
+                }
+            };
+            resolved.push(cid);
+        }
+        Ok(resolved)
+    }
+
+    pub fn aliases(&self) -> impl Iterator<Item = Result<(Vec<u8>, Cid)>> + Send {
+        let blocks = self.blocks.clone();
+        self.alias.iter().map(move |res| {
+            let (alias, id) = res?;
+            let id = Id::from(id);
+            let cid = blocks.cid(&id)?.ok_or_else(|| IdNotFound(id.clone()))?;
+            Ok((alias.to_vec(), cid))
+        })
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = Result<Cid>> + Send {
+        self.blocks.cids()
+    }
+
+    pub fn iter_pins(&self, size: bool) -> impl Iterator<Item = Result<PinInfo>> + Send {
+        let this = self.clone();
+        self.alias.iter().map(move |res| {
+            let (alias, id) = res?;
+            let id = Id::from(id);
+            let cid = this
+                .blocks
+                .cid(&id)?
+                .ok_or_else(|| IdNotFound(id.clone()))?;
+            let depth = this.depth.get(&alias)?.map(|d| decode_u64(&d));
+            let ids = this
+                .closure
+                .get(closure_key(&id, depth))?
+                .map(|ids| this.blocks.decode_ids(ids))
+                .transpose()?
+                .unwrap_or_default();
+            let size = if size {
+                Some(this.blocks.size(&ids)?)
+            } else {
+                None
+            };
+            Ok(PinInfo {
+                alias: alias.to_vec(),
+                cid,
+                blocks: ids.iter().count(),
+                size,
+            })
+        })
+    }
+
     pub async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
         if let Some(id) = self.blocks.lookup_id(cid)? {
             let filter = self.filter.lock().await;
@@ -360,30 +1361,93 @@ where
         }
     }
 
+    /// Removes an unpinned block. Pinned blocks are never removed, so that the
+    /// pinned dags stay complete.
+    pub async fn remove(&self, cid: &Cid) -> Result<bool> {
+        let filter = self.filter.lock().await;
+        let id = if let Some(id) = self.blocks.lookup_id(cid)? {
+            id
+        } else {
+            return Ok(false);
+        };
+        if filter.contains(&id) {
+            return Err(BlockPinned(*cid).into());
+        }
+        if let Some(cid) = self.blocks.remove(&id)? {
+            self.audit.record(AuditAction::Remove, &cid);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     pub async fn evict(&self, cache_size: usize) -> Result<()> {
         let filter = self.filter.lock().await;
         let nblocks = self.blocks.len();
         let nlive = filter.len();
-        let ncache = nblocks - nlive;
+        let ncache = nblocks.saturating_sub(nlive);
         if ncache <= cache_size {
             return Ok(());
         }
         let mut nevict = ncache - cache_size;
         log::debug!("evicting {} blocks", nevict);
+        let fresh = self.blocks.fresh();
         for res in self.blocks.lru() {
             if nevict < 1 {
                 break;
             }
             let id = res?;
-            if !filter.contains(&id) {
-                self.blocks.remove(&id)?;
+            if !filter.contains(&id) && !fresh.contains(&id) {
+                if let Some(cid) = self.blocks.remove(&id)? {
+                    self.audit.record(AuditAction::Evict, &cid);
+                }
                 nevict -= 1;
             }
         }
         Ok(())
     }
 
-    pub fn subscribe(&self) -> Subscription {
-        self.blocks.subscribe()
+    /// Total size of all blocks in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.blocks.bytes()
+    }
+
+    /// Evicts unpinned blocks, least recently used first, until all blocks take
+    /// up at most `target` bytes.
+    pub async fn evict_bytes(&self, target: u64) -> Result<()> {
+        let filter = self.filter.lock().await;
+        if self.blocks.bytes() <= target {
+            return Ok(());
+        }
+        log::debug!("evicting {} bytes", self.blocks.bytes() - target);
+        let fresh = self.blocks.fresh();
+        for res in self.blocks.lru() {
+            if self.blocks.bytes() <= target {
+                break;
+            }
+            let id = res?;
+            if !filter.contains(&id) && !fresh.contains(&id) {
+                if let Some(cid) = self.blocks.remove(&id)? {
+                    self.audit.record(AuditAction::Evict, &cid);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn subscribe(&self, overflow: OverflowPolicy) -> Subscription {
+        self.blocks.subscribe(overflow)
+    }
+
+    pub fn try_subscribe(&self, overflow: OverflowPolicy) -> Result<Subscription> {
+        self.blocks.try_subscribe(overflow)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.blocks.subscriber_count()
+    }
+
+    pub fn audit_events(&self) -> AuditLog {
+        self.audit.subscribe()
     }
 }
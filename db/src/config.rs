@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+/// Encoding of the sets of block ids stored for references and pins. It can't
+/// be changed for an existing database.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdsFormat {
+    /// Concatenated big endian u64s.
+    FixedWidth,
+    /// Concatenated unsigned varints. More compact while ids are small.
+    Varint,
+}
+
+/// What a storage subscription does when the subscriber falls behind.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Block writers until the subscriber catches up. Up to 1024 events are
+    /// buffered per subscriber.
+    Block,
+    /// Buffer up to this many events and drop the rest with a warning. The
+    /// subscriber receives a `StorageEvent::Lagged` with the number of dropped
+    /// events.
+    Drop(usize),
+}
+
+/// Storage configuration.
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    /// Number of unpinned blocks to keep. With a cache size of zero only pinned
+    /// blocks are kept, unpinned blocks are removed by the next sweep.
+    pub cache_size: usize,
+    /// Interval between garbage collections.
+    pub sweep_interval: Duration,
+    /// Flush to disk after each alias update, so that an alias is durable once
+    /// `alias` returns.
+    pub flush_aliases: bool,
+    /// Record pins, unpins and evictions in an audit log.
+    pub audit: bool,
+    /// Collect garbage as soon as the blocks take up more than this many bytes.
+    pub gc_high_watermark: Option<u64>,
+    /// Number of bytes to collect down to once the high watermark is crossed.
+    /// Defaults to the high watermark.
+    pub gc_low_watermark: Option<u64>,
+    /// Maximum nesting of the ipld in a block when extracting its links.
+    pub max_ipld_depth: usize,
+    /// Maximum number of ipld nodes in a block when extracting its links.
+    pub max_ipld_nodes: usize,
+    /// Maximum number of distinct links of a block followed when walking a dag.
+    /// Blocks with more links fail with `TooManyLinks`.
+    pub max_links: usize,
+    /// Encoding of the stored block id sets.
+    pub ids_format: IdsFormat,
+    /// Serialize alias updates of the same alias, so that they are applied in
+    /// the order they were started. The pins stay consistent when this is
+    /// disabled, but concurrent updates of an alias may be applied in any order.
+    pub serialize_aliases: bool,
+    /// Overflow policy of subscriptions created with `subscribe`.
+    pub subscription_overflow: OverflowPolicy,
+    /// Maximum number of open subscriptions. `try_subscribe` fails with
+    /// `TooManySubscribers` beyond it. Subscriptions created with `subscribe`
+    /// count towards the limit, but aren't rejected.
+    pub max_subscribers: Option<usize>,
+    /// Maximum length of an alias in bytes. Defaults to 256, longer aliases that
+    /// were created by earlier versions can still be resolved and removed.
+    pub max_alias_key_len: usize,
+    /// Maximum number of aliases. Creating a new alias beyond it fails with
+    /// `TooManyAliases`, updating or removing an existing one always succeeds.
+    pub max_aliases: Option<usize>,
+    /// Unpinned blocks inserted less than this long ago aren't evicted, so that
+    /// a block fetched from the network isn't fetched again when it is read a
+    /// second time shortly after.
+    pub min_residency: Duration,
+}
+
+impl StorageConfig {
+    /// Creates a new storage configuration.
+    pub fn new(cache_size: usize, sweep_interval: Duration) -> Self {
+        Self {
+            cache_size,
+            sweep_interval,
+            flush_aliases: false,
+            audit: false,
+            gc_high_watermark: None,
+            gc_low_watermark: None,
+            max_ipld_depth: 128,
+            max_ipld_nodes: 1 << 20,
+            max_links: 1 << 16,
+            ids_format: IdsFormat::FixedWidth,
+            serialize_aliases: true,
+            subscription_overflow: OverflowPolicy::Block,
+            max_subscribers: None,
+            max_alias_key_len: 256,
+            max_aliases: None,
+            min_residency: Duration::from_secs(0),
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use cuckoofilter::CuckooFilter;
-use fnv::FnvHasher;
+use crate::config::IdsFormat;
+use fnv::FnvHashMap;
 use ipfs_embed_core::Result;
 use sled::IVec;
 use std::collections::HashSet;
 use std::hash::{BuildHasherDefault, Hasher};
+use thiserror::Error;
 
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Id(IVec);
@@ -135,39 +136,124 @@ impl<'a> Iterator for IdsIter<'a> {
     }
 }
 
+#[derive(Debug, Error)]
+#[error("Id {0:?} not found.")]
+pub struct IdNotFound(pub(crate) Id);
+
+/// Encoding of `Ids` in the database.
+pub trait IdsEncoding: Send + Sync {
+    fn encode(&self, ids: &Ids) -> IVec;
+    fn decode(&self, bytes: IVec) -> Result<Ids>;
+}
+
+/// Concatenated big endian u64s, the in-memory representation of `Ids`.
+pub struct FixedWidth;
+
+impl IdsEncoding for FixedWidth {
+    fn encode(&self, ids: &Ids) -> IVec {
+        ids.into()
+    }
+
+    fn decode(&self, bytes: IVec) -> Result<Ids> {
+        let rest = &bytes[bytes.len() / 8 * 8..];
+        if !rest.is_empty() {
+            // the truncated trailing id doesn't refer to any block
+            let mut buf = [0u8; 8];
+            buf[..rest.len()].copy_from_slice(rest);
+            return Err(IdNotFound(Id::from(u64::from_be_bytes(buf))).into());
+        }
+        Ok(bytes.into())
+    }
+}
+
+/// Concatenated unsigned varints.
+pub struct Varint;
+
+impl IdsEncoding for Varint {
+    fn encode(&self, ids: &Ids) -> IVec {
+        let mut buf = Vec::with_capacity(ids.as_ref().len());
+        for id in ids.iter() {
+            let mut n = u64::from(&id);
+            while n >= 0x80 {
+                buf.push(n as u8 | 0x80);
+                n >>= 7;
+            }
+            buf.push(n as u8);
+        }
+        buf.into()
+    }
+
+    fn decode(&self, bytes: IVec) -> Result<Ids> {
+        let mut buf = Vec::with_capacity(bytes.len() * 8);
+        let mut n = 0u64;
+        let mut shift = 0;
+        for b in bytes.iter() {
+            // the last byte of a u64 only has one bit left
+            if shift > 63 || (shift == 63 && b & 0x7f > 1) {
+                return Err(IdNotFound(Id::from(n)).into());
+            }
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                buf.extend_from_slice(&n.to_be_bytes());
+                n = 0;
+                shift = 0;
+            } else {
+                shift += 7;
+            }
+        }
+        if shift != 0 {
+            return Err(IdNotFound(Id::from(n)).into());
+        }
+        Ok(IVec::from(buf).into())
+    }
+}
+
+impl IdsFormat {
+    pub(crate) fn encoding(self) -> &'static dyn IdsEncoding {
+        match self {
+            Self::FixedWidth => &FixedWidth,
+            Self::Varint => &Varint,
+        }
+    }
+}
+
+/// Ids of the pinned blocks, counted once for every pinned dag containing them.
+///
+/// Every `delete` must be balanced by an earlier `add` of the same id. An
+/// unbalanced delete would unpin a block that is still referenced by another
+/// pin, so it is a bug that panics in debug builds and is ignored otherwise.
+#[derive(Default)]
 pub struct LiveSet {
-    filter: CuckooFilter<FnvHasher>,
-    distinct: usize,
+    refs: FnvHashMap<Id, u32>,
 }
 
 impl LiveSet {
     pub fn new() -> Self {
-        Self {
-            filter: CuckooFilter::with_capacity(cuckoofilter::DEFAULT_CAPACITY),
-            distinct: 0,
-        }
+        Self::default()
     }
 
+    /// Number of distinct ids.
     pub fn len(&self) -> usize {
-        self.distinct
+        self.refs.len()
     }
 
     pub fn contains(&self, id: &Id) -> bool {
-        self.filter.contains(id)
+        self.refs.contains_key(id)
     }
 
-    pub fn add(&mut self, id: &Id) -> Result<()> {
-        if !self.filter.contains(id) {
-            self.distinct += 1;
-        }
-        self.filter.add(id)?;
-        Ok(())
+    pub fn add(&mut self, id: &Id) {
+        *self.refs.entry(id.clone()).or_default() += 1;
     }
 
     pub fn delete(&mut self, id: &Id) {
-        self.filter.delete(id);
-        if !self.filter.contains(id) {
-            self.distinct -= 1;
+        if let Some(count) = self.refs.get_mut(id) {
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(id);
+            }
+        } else {
+            debug_assert!(false, "unbalanced delete of {}", id);
+            log::error!("unbalanced delete of {}", id);
         }
     }
 }
@@ -1,18 +1,71 @@
-use crate::blocks::{Aliases, Subscription};
+use crate::availability::Availability;
+use crate::blocks::Aliases;
+use crate::locks::KeyedLocks;
+use crate::providers::Providers;
 use async_std::stream::interval;
 use async_std::task;
-use futures::stream::StreamExt;
-use ipfs_embed_core::{async_trait, Block, Cid, Result, Storage, StoreParams};
+use futures::channel::mpsc;
+use futures::future::Future;
+use futures::stream::{self, StreamExt};
+use ipfs_embed_core::{
+    async_trait, Block, BoxStream, Cid, Error, PeerId, PinInfo, Result, Storage, StoreParams,
+    Transient,
+};
 use libipld::codec::Decode;
 use libipld::ipld::Ipld;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
 
+mod audit;
+mod availability;
 mod blocks;
+mod config;
 mod id;
+mod locks;
+mod providers;
+
+pub use audit::{AuditAction, AuditEvent, AuditLog};
+pub use blocks::Subscription;
+pub use blocks::{BlockPinned, IpldTooComplex, TooManyAliases, TooManySubscribers};
+pub use config::{IdsFormat, OverflowPolicy, StorageConfig};
+
+#[derive(Debug, Error)]
+#[error("Alias of {0} bytes exceeds the maximum alias length.")]
+pub struct AliasKeyTooLong(pub usize);
+
+#[derive(Debug, Error)]
+#[error("Can't alias {0}, its codec isn't supported by the store.")]
+pub struct UnsupportedAliasCid(pub Cid);
+
+/// Marks io errors that may go away when the operation is retried as transient.
+fn classify(err: Error) -> Error {
+    let transient = match err.downcast_ref::<sled::Error>() {
+        Some(sled::Error::Io(err)) => matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    };
+    if transient {
+        err.context(Transient)
+    } else {
+        err
+    }
+}
 
 pub struct StorageService<S: StoreParams> {
     store: Aliases<S>,
-    cache_size: usize,
+    providers: Providers,
+    availability: Availability,
+    config: StorageConfig,
+    gc: mpsc::UnboundedSender<()>,
+    // set while a triggered collection hasn't started yet
+    gc_pending: Arc<AtomicBool>,
+    locks: KeyedLocks,
 }
 
 impl<S: StoreParams> StorageService<S>
@@ -24,20 +77,102 @@ where
         cache_size: usize,
         sweep_interval: Duration,
     ) -> Result<Self> {
-        let db = config.open()?;
-        let store = Aliases::open(&db)?;
+        Self::open_with_config(config, StorageConfig::new(cache_size, sweep_interval))
+    }
+
+    pub fn open_with_config(sled_config: &sled::Config, config: StorageConfig) -> Result<Self> {
+        let db = sled_config.open()?;
+        let store = Aliases::open(&db, &config)?;
+        let providers = Providers::open(&db)?;
+        let availability = Availability::open(&db)?;
         let gc = store.clone();
+        let (gc_tx, gc_rx) = mpsc::unbounded();
+        let gc_pending = Arc::new(AtomicBool::new(false));
+        let pending = gc_pending.clone();
+        let cache_size = config.cache_size;
+        let sweep_interval = config.sweep_interval;
+        let high_watermark = config.gc_high_watermark;
+        let low_watermark = config.gc_low_watermark.or(high_watermark);
         task::spawn(async move {
-            let mut stream = interval(sweep_interval);
+            let mut stream = stream::select(interval(sweep_interval), gc_rx);
             while let Some(()) = stream.next().await {
+                pending.store(false, Ordering::SeqCst);
+                if let Err(err) = gc.reap_leases().await {
+                    log::error!("failed to release expired leases {:?}", err);
+                }
                 gc.evict(cache_size).await.ok();
+                if let Some(low_watermark) = low_watermark {
+                    gc.evict_bytes(low_watermark).await.ok();
+                }
             }
         });
-        Ok(Self { cache_size, store })
+        Ok(Self {
+            config,
+            store,
+            providers,
+            availability,
+            gc: gc_tx,
+            gc_pending,
+            locks: Default::default(),
+        })
+    }
+
+    fn check_alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        if let Some(cid) = cid {
+            if alias.len() > self.config.max_alias_key_len {
+                return Err(AliasKeyTooLong(alias.len()).into());
+            }
+            if S::Codecs::try_from(cid.codec()).is_err() {
+                return Err(UnsupportedAliasCid(*cid).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` while holding the locks of the aliases when
+    /// `StorageConfig::serialize_aliases` is enabled.
+    async fn locked<'a, T>(
+        &self,
+        aliases: impl IntoIterator<Item = &'a [u8]>,
+        f: impl Future<Output = T>,
+    ) -> T {
+        let locks = if self.config.serialize_aliases {
+            self.locks.get(aliases)
+        } else {
+            Default::default()
+        };
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in &locks {
+            guards.push(lock.lock().await);
+        }
+        f.await
+    }
+
+    /// Subscribes to storage events with a different overflow policy than the
+    /// configured one.
+    pub fn subscribe_with(&self, overflow: OverflowPolicy) -> Subscription {
+        self.store.subscribe(overflow)
+    }
+
+    /// Like `subscribe_with`, but fails with `TooManySubscribers` when
+    /// `max_subscribers` subscriptions are open.
+    pub fn try_subscribe_with(&self, overflow: OverflowPolicy) -> Result<Subscription> {
+        self.store.try_subscribe(overflow)
+    }
+
+    /// Number of open subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        self.store.subscriber_count()
     }
 
     pub async fn evict(&self) -> Result<()> {
-        self.store.evict(self.cache_size).await
+        self.store.evict(self.config.cache_size).await
+    }
+
+    /// Streams the recorded audit events, starting with the oldest one. Events are
+    /// only recorded when `StorageConfig::audit` is enabled.
+    pub fn audit_events(&self) -> AuditLog {
+        self.store.audit_events()
     }
 }
 
@@ -49,37 +184,165 @@ where
     type Subscription = Subscription;
 
     fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        self.store.get(cid)
+        self.store.get(cid).map_err(classify)
+    }
+
+    fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.store.contains(cid).map_err(classify)
+    }
+
+    fn touch(&self, cid: &Cid) -> Result<()> {
+        self.store.touch(cid).map_err(classify)
     }
 
-    fn insert(&self, block: &Block<S>) -> Result<()> {
-        self.store.insert(block)
+    fn insert(&self, block: &Block<S>) -> Result<bool> {
+        let inserted = self.store.insert(block).map_err(classify)?;
+        if let Some(high_watermark) = self.config.gc_high_watermark {
+            // triggers are coalesced until the collection starts, pinned blocks
+            // alone may keep the store above the watermark
+            if self.store.bytes() > high_watermark && !self.gc_pending.swap(true, Ordering::SeqCst)
+            {
+                self.gc.unbounded_send(()).ok();
+            }
+        }
+        Ok(inserted)
     }
 
     async fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        self.store.alias(alias.as_ref(), cid).await
+        let alias = alias.as_ref();
+        self.check_alias(alias, cid)?;
+        self.locked(Some(alias), self.store.alias(alias, cid))
+            .await
+            .map_err(classify)
+    }
+
+    async fn pin_depth<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: &Cid,
+        depth: usize,
+    ) -> Result<()> {
+        let alias = alias.as_ref();
+        self.check_alias(alias, Some(cid))?;
+        let f = self.store.alias_depth(alias, Some(cid), Some(depth as u64));
+        self.locked(Some(alias), f).await.map_err(classify)
+    }
+
+    async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()> {
+        for (alias, cid) in pins {
+            self.check_alias(alias, Some(cid))?;
+        }
+        let aliases = pins.iter().map(|(alias, _)| &alias[..]);
+        self.locked(aliases, self.store.pin_many(pins))
+            .await
+            .map_err(classify)
+    }
+
+    async fn pin(&self, cid: &Cid) -> Result<()> {
+        self.store.pin(cid).await.map_err(classify)
+    }
+
+    async fn unpin(&self, cid: &Cid) -> Result<()> {
+        self.store.unpin(cid).await.map_err(classify)
+    }
+
+    async fn auto_pin(&self, cid: &Cid) -> Result<()> {
+        self.store.auto_pin(cid).await.map_err(classify)
+    }
+
+    async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+        self.store.remove_auto_pin(cid).await.map_err(classify)
+    }
+
+    async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64> {
+        self.store.lease(cid, ttl).await.map_err(classify)
+    }
+
+    async fn release(&self, lease: u64) -> Result<()> {
+        self.store.release(lease).await.map_err(classify)
     }
 
     fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
         self.store.resolve(alias.as_ref())
     }
 
+    fn resolve_many<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        aliases: &[T],
+    ) -> Result<Vec<Option<Cid>>> {
+        self.store.resolve_many(aliases)
+    }
+
     async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
         self.store.pinned(cid).await
     }
 
+    async fn remove(&self, cid: &Cid) -> Result<bool> {
+        self.store.remove(cid).await
+    }
+
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.store.missing(cid)
+    }
+
+    fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()> {
+        self.store.tag(cid, tag)
+    }
+
+    fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        self.store.by_tag(tag)
+    }
+
+    fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>> {
+        stream::iter(self.store.iter_pins(size)).boxed()
+    }
+
+    fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>> {
+        stream::iter(self.store.aliases()).boxed()
+    }
+
+    fn blocks(&self) -> BoxStream<'static, Result<Cid>> {
+        stream::iter(self.store.blocks()).boxed()
+    }
+
+    fn record_provider(&self, peer_id: &PeerId) -> Result<()> {
+        self.providers.record(peer_id)
+    }
+
+    fn good_providers(&self, n: usize) -> Result<Vec<PeerId>> {
+        self.providers.best(n)
+    }
+
+    fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()> {
+        self.availability.record(cid, providers)
+    }
+
+    fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>> {
+        self.availability.history(cid)
+    }
+
     fn subscribe(&self) -> Self::Subscription {
-        self.store.subscribe()
+        self.store.subscribe(self.config.subscription_overflow)
+    }
+
+    fn try_subscribe(&self) -> Result<Self::Subscription> {
+        self.store.try_subscribe(self.config.subscription_overflow)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::future::FutureExt;
+    use futures::stream::TryStreamExt;
+    use ipfs_embed_core::{StorageEvent, TooManyLinks};
     use libipld::cbor::DagCborCodec;
-    use libipld::multihash::SHA2_256;
+    use libipld::error::BlockNotFound;
+    use libipld::multihash::{BLAKE2B_256, SHA2_256};
     use libipld::store::DefaultStoreParams;
     use libipld::{alias, ipld};
+    use std::collections::HashSet;
+    use std::sync::Arc;
 
     fn create_block(ipld: &Ipld) -> Block<DefaultStoreParams> {
         Block::encode(DagCborCodec, SHA2_256, ipld).unwrap()
@@ -132,6 +395,28 @@ mod tests {
         assert_unpinned!(&store, &blocks[3]);
     }
 
+    #[async_std::test]
+    async fn test_audit_evict() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(2, Duration::from_millis(10000));
+        config.audit = true;
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let blocks = [
+            create_block(&ipld!(0)),
+            create_block(&ipld!(1)),
+            create_block(&ipld!(2)),
+        ];
+        store.insert(&blocks[0]).unwrap();
+        store.insert(&blocks[1]).unwrap();
+        store.insert(&blocks[2]).unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, &blocks[0]);
+        let event = store.audit_events().next().await.unwrap();
+        assert_eq!(event.action, AuditAction::Evict);
+        assert_eq!(event.cid, *blocks[0].cid());
+    }
+
     #[async_std::test]
     #[allow(clippy::many_single_char_names)]
     async fn test_store_unpin() {
@@ -184,4 +469,675 @@ mod tests {
         assert_unpinned!(&store, &a);
         assert_unpinned!(&store, &b);
     }
+
+    #[async_std::test]
+    async fn test_store_pin() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.pin(b.cid()).await.unwrap();
+        store.pin(b.cid()).await.unwrap();
+        store.alias(x, Some(b.cid())).await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        store.alias(x, None).await.unwrap();
+        store.unpin(b.cid()).await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        store.unpin(b.cid()).await.unwrap();
+        assert_unpinned!(&store, &a);
+        assert_unpinned!(&store, &b);
+        // unpinning a block that isn't pinned does nothing
+        store.unpin(b.cid()).await.unwrap();
+        assert_unpinned!(&store, &b);
+    }
+
+    #[async_std::test]
+    async fn test_lease_expiry() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        let short = store
+            .lease(b.cid(), Duration::from_millis(50))
+            .await
+            .unwrap();
+        let long = store.lease(a.cid(), Duration::from_secs(60)).await.unwrap();
+        assert_pinned!(&store, &b);
+        while store.pinned(b.cid()).await.unwrap() == Some(true) {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_pinned!(&store, &a);
+        // releasing an expired lease does nothing
+        store.release(short).await.unwrap();
+        store.release(long).await.unwrap();
+        assert_unpinned!(&store, &a);
+    }
+
+    #[async_std::test]
+    #[allow(clippy::many_single_char_names)]
+    async fn test_store_pin_depth() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [b.cid()] }));
+        let x = alias!(x);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.insert(&c).unwrap();
+        store.pin_depth(x, c.cid(), 1).await.unwrap();
+        assert_eq!(store.resolve(x).unwrap(), Some(*c.cid()));
+        assert_unpinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_pinned!(&store, &c);
+        store.alias(x, None).await.unwrap();
+        assert_unpinned!(&store, &a);
+        assert_unpinned!(&store, &b);
+        assert_unpinned!(&store, &c);
+    }
+
+    #[async_std::test]
+    async fn test_recover_closure() {
+        env_logger::try_init().ok();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = StorageConfig::new(2, Duration::from_millis(10000));
+        let store = Aliases::<DefaultStoreParams>::open(&db, &config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(x.as_bytes(), Some(b.cid())).await.unwrap();
+        // simulate a crash after writing the alias but before writing the closure
+        db.open_tree("closure").unwrap().clear().unwrap();
+        let store = Aliases::<DefaultStoreParams>::open(&db, &config).unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+    }
+
+    #[async_std::test]
+    async fn test_closures_removed_per_depth() {
+        env_logger::try_init().ok();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let config = StorageConfig::new(2, Duration::from_millis(10000));
+        let store = Aliases::<DefaultStoreParams>::open(&db, &config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store
+            .alias_depth(b"x", Some(b.cid()), Some(1))
+            .await
+            .unwrap();
+        store
+            .alias_depth(b"y", Some(b.cid()), Some(2))
+            .await
+            .unwrap();
+        store.alias(b"z", Some(b.cid())).await.unwrap();
+        store.alias(b"w", Some(b.cid())).await.unwrap();
+        store.alias(b"y", None).await.unwrap();
+        store.alias(b"x", None).await.unwrap();
+        store.alias(b"z", None).await.unwrap();
+        let closure = db.open_tree("closure").unwrap();
+        assert_eq!(closure.len(), 1);
+        store.alias(b"w", None).await.unwrap();
+        assert!(closure.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_mixed_hashes() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let ipld = ipld!({ "mixed": "hashes" });
+        let a = create_block(&ipld);
+        let b = Block::<DefaultStoreParams>::encode(DagCborCodec, BLAKE2B_256, &ipld).unwrap();
+        assert_ne!(a.cid(), b.cid());
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        assert_eq!(store.get(b.cid()).unwrap().as_deref(), Some(b.data()));
+        store.alias(alias!(a), Some(a.cid())).await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_unpinned!(&store, &b);
+    }
+
+    #[test]
+    fn test_good_providers() {
+        env_logger::try_init().ok();
+        let tmp = tempdir::TempDir::new("test_good_providers").unwrap();
+        let a = PeerId::random();
+        let b = PeerId::random();
+        {
+            let db = sled::Config::new().path(tmp.path()).open().unwrap();
+            let providers = Providers::open(&db).unwrap();
+            providers.record(&a).unwrap();
+            providers.record(&b).unwrap();
+            providers.record(&b).unwrap();
+            db.flush().unwrap();
+        }
+        let db = sled::Config::new().path(tmp.path()).open().unwrap();
+        let providers = Providers::open(&db).unwrap();
+        assert_eq!(providers.best(2).unwrap(), vec![b.clone(), a.clone()]);
+        assert_eq!(providers.best(1).unwrap(), vec![b.clone()]);
+        // entries with a corrupted score are skipped
+        let c = PeerId::random();
+        let mut entry = [0u8; 16];
+        entry[..8].copy_from_slice(&f64::NAN.to_be_bytes());
+        let tree = db.open_tree("providers").unwrap();
+        tree.insert(c.as_bytes(), &entry[..]).unwrap();
+        assert_eq!(providers.best(3).unwrap(), vec![b, a]);
+    }
+
+    #[test]
+    fn test_availability() {
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::<DefaultStoreParams>::open(&config, 0, Duration::from_secs(10))
+            .unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        assert!(store.availability_history(a.cid()).unwrap().is_empty());
+        store.record_availability(a.cid(), 3).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        store.record_availability(a.cid(), 1).unwrap();
+        store.record_availability(b.cid(), 5).unwrap();
+        let history = store.availability_history(a.cid()).unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].0 < history[1].0);
+        assert_eq!(history[0].1, 3);
+        assert_eq!(history[1].1, 1);
+    }
+
+    #[async_std::test]
+    async fn test_gc_watermarks() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(100, Duration::from_millis(10000));
+        config.gc_high_watermark = Some(1000);
+        config.gc_low_watermark = Some(500);
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        for i in 0..20 {
+            store
+                .insert(&create_block(&Ipld::Bytes(vec![i; 64])))
+                .unwrap();
+        }
+        while store.store.bytes() > 500 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(store.store.bytes() > 0);
+    }
+
+    #[async_std::test]
+    async fn test_bytes_persisted() {
+        env_logger::try_init().ok();
+        let tmp = tempdir::TempDir::new("test_bytes_persisted").unwrap();
+        let config = StorageConfig::new(100, Duration::from_millis(10000));
+        let a = create_block(&Ipld::Bytes(vec![0; 64]));
+        let b = create_block(&Ipld::Bytes(vec![1; 32]));
+        let bytes = {
+            let db = sled::Config::new().path(tmp.path()).open().unwrap();
+            let store = Aliases::<DefaultStoreParams>::open(&db, &config).unwrap();
+            store.insert(&a).unwrap();
+            store.insert(&b).unwrap();
+            store.remove(a.cid()).await.unwrap();
+            db.flush().unwrap();
+            store.bytes()
+        };
+        assert_eq!(bytes, b.data().len() as u64);
+        let db = sled::Config::new().path(tmp.path()).open().unwrap();
+        let store = Aliases::<DefaultStoreParams>::open(&db, &config).unwrap();
+        assert_eq!(store.bytes(), bytes);
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_alias() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 10, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        let ops = (0..100).map(|i| {
+            let cid = match i % 3 {
+                0 => Some(a.cid()),
+                1 => Some(b.cid()),
+                _ => None,
+            };
+            store.alias(b"x", cid)
+        });
+        for res in futures::future::join_all(ops).await {
+            res.unwrap();
+        }
+        // whichever operation ran last, only its block is pinned
+        let pinned = store.resolve(b"x").unwrap();
+        for block in &[&a, &b] {
+            let expected = Some(pinned.as_ref() == Some(block.cid()));
+            assert_eq!(store.pinned(block.cid()).await.unwrap(), expected);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_tags() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 0, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.insert(&c).unwrap();
+        store.alias(b"c", Some(c.cid())).await.unwrap();
+        store.tag_block(a.cid(), "message").unwrap();
+        store.tag_block(b.cid(), "message").unwrap();
+        store.tag_block(b.cid(), "messages").unwrap();
+        store.tag_block(c.cid(), "message").unwrap();
+        store.tag_block(c.cid(), "message").unwrap();
+        let tagged: HashSet<Cid> = store
+            .blocks_by_tag("message")
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            tagged,
+            [*a.cid(), *b.cid(), *c.cid()].iter().copied().collect()
+        );
+        assert_eq!(store.blocks_by_tag("messages").unwrap(), vec![*b.cid()]);
+        assert!(store.blocks_by_tag("avatar").unwrap().is_empty());
+
+        // tags are removed with the block
+        store.evict().await.unwrap();
+        assert_eq!(store.blocks_by_tag("message").unwrap(), vec![*c.cid()]);
+        assert!(store.blocks_by_tag("messages").unwrap().is_empty());
+        let d = create_block(&ipld!({ "d": [] }));
+        let err = store.tag_block(d.cid(), "message").unwrap_err();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_zero_cache_size() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 0, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(b"b", Some(b.cid())).await.unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, a);
+        assert_pinned!(&store, b);
+    }
+
+    #[test]
+    fn test_live_set() {
+        use crate::id::{Id, LiveSet};
+        let mut live = LiveSet::new();
+        let a = Id::from(1u64);
+        let b = Id::from(2u64);
+        live.add(&a);
+        live.add(&a);
+        live.add(&b);
+        assert_eq!(live.len(), 2);
+        live.delete(&a);
+        assert!(live.contains(&a));
+        live.delete(&a);
+        assert!(!live.contains(&a));
+        assert!(live.contains(&b));
+        assert_eq!(live.len(), 1);
+    }
+
+    #[test]
+    fn test_live_set_balanced() {
+        use crate::id::{Id, LiveSet};
+        let mut live = LiveSet::new();
+        live.add(&Id::from(1u64));
+        let len = live.len();
+        let a = Id::from(2u64);
+        live.add(&a);
+        live.add(&a);
+        assert_eq!(live.len(), len + 1);
+        live.delete(&a);
+        live.delete(&a);
+        assert_eq!(live.len(), len);
+    }
+
+    #[async_std::test]
+    async fn test_min_residency() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(0, Duration::from_millis(10000));
+        config.min_residency = Duration::from_millis(100);
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(&a).unwrap();
+        store.evict().await.unwrap();
+        assert_unpinned!(&store, a);
+        task::sleep(Duration::from_millis(100)).await;
+        store.insert(&b).unwrap();
+        store.evict().await.unwrap();
+        assert_evicted!(&store, a);
+        assert_unpinned!(&store, b);
+    }
+
+    #[test]
+    fn test_ids_format() {
+        use crate::id::{Id, IdNotFound, Ids};
+        use fnv::FnvHashSet;
+
+        let set: FnvHashSet<Id> = [0u64, 1, 127, 128, 300, u64::MAX]
+            .iter()
+            .map(|id| Id::from(*id))
+            .collect();
+        let ids = Ids::from(&set);
+        for format in &[IdsFormat::FixedWidth, IdsFormat::Varint] {
+            let encoding = format.encoding();
+            let decoded = encoding.decode(encoding.encode(&ids)).unwrap();
+            assert_eq!(
+                decoded.iter().collect::<Vec<_>>(),
+                ids.iter().collect::<Vec<_>>()
+            );
+        }
+        let varint = IdsFormat::Varint.encoding();
+        assert!(varint.encode(&ids).len() < ids.as_ref().len());
+        assert!(varint.decode(sled::IVec::from(&[0x80][..])).is_err());
+
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        let decoded = varint.decode(sled::IVec::from(max.clone())).unwrap();
+        assert_eq!(decoded.iter().collect::<Vec<_>>(), vec![Id::from(u64::MAX)]);
+        *max.last_mut().unwrap() = 0x02;
+        let err = varint.decode(sled::IVec::from(max)).unwrap_err();
+        assert!(err.downcast_ref::<IdNotFound>().is_some());
+
+        let fixed = IdsFormat::FixedWidth.encoding();
+        let err = fixed.decode(sled::IVec::from(&[0; 7][..])).unwrap_err();
+        assert!(err.downcast_ref::<IdNotFound>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_varint_store() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(2, Duration::from_millis(10000));
+        config.ids_format = IdsFormat::Varint;
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(b"b", Some(b.cid())).await.unwrap();
+        assert_pinned!(&store, a);
+        assert_pinned!(&store, b);
+        store.alias(b"b", None).await.unwrap();
+        assert_unpinned!(&store, a);
+    }
+
+    #[async_std::test]
+    async fn test_max_alias_key_len() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(2, Duration::from_millis(10000));
+        config.max_alias_key_len = 8;
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        store.insert(&a).unwrap();
+        store.alias(b"12345678", Some(a.cid())).await.unwrap();
+        let err = store.alias(b"123456789", Some(a.cid())).await.unwrap_err();
+        assert!(err.downcast_ref::<AliasKeyTooLong>().is_some());
+        let pins = vec![(b"123456789".to_vec(), *a.cid())];
+        let err = store.pin_many(&pins).await.unwrap_err();
+        assert!(err.downcast_ref::<AliasKeyTooLong>().is_some());
+        assert_eq!(store.resolve(b"123456789").unwrap(), None);
+        // 0x90 is the eth-block codec, which isn't in the default store params
+        let unsupported = Cid::new_v1(0x90, *a.cid().hash());
+        let err = store.alias(b"a", Some(&unsupported)).await.unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedAliasCid>().is_some());
+        assert_eq!(store.resolve(b"a").unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_max_aliases() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(2, Duration::from_millis(10000));
+        config.max_aliases = Some(2);
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(b"a", Some(a.cid())).await.unwrap();
+        store.alias(b"b", Some(a.cid())).await.unwrap();
+        let err = store.alias(b"c", Some(a.cid())).await.unwrap_err();
+        assert!(err.downcast_ref::<TooManyAliases>().is_some());
+        let pins = vec![(b"c".to_vec(), *a.cid())];
+        let err = store.pin_many(&pins).await.unwrap_err();
+        assert!(err.downcast_ref::<TooManyAliases>().is_some());
+        assert_eq!(store.resolve(b"c").unwrap(), None);
+
+        store.alias(b"b", Some(b.cid())).await.unwrap();
+        let pins = vec![(b"a".to_vec(), *b.cid())];
+        store.pin_many(&pins).await.unwrap();
+        assert_eq!(store.resolve(b"a").unwrap(), Some(*b.cid()));
+
+        store.alias(b"a", None).await.unwrap();
+        store.alias(b"c", Some(a.cid())).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_subscription_overflow() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(100, Duration::from_millis(10000));
+        config.subscription_overflow = OverflowPolicy::Drop(4);
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let mut subscription = store.subscribe();
+        for i in 0..100 {
+            store.insert(&create_block(&ipld!(i))).unwrap();
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let mut inserted = 0;
+        let mut lagged = 0;
+        while let Some(Some(event)) = subscription.next().now_or_never() {
+            match event {
+                StorageEvent::Insert(_) => inserted += 1,
+                StorageEvent::Lagged(n) => lagged += n,
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert!(inserted < 100);
+        assert_eq!(inserted + lagged, 100);
+        assert_eq!(subscription.dropped(), lagged);
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_insert_single_event() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let store = Arc::new(
+            StorageService::open(&sled_config, 100, Duration::from_millis(10000)).unwrap(),
+        );
+        let mut subscription = store.subscribe();
+        let block = create_block(&ipld!("concurrent"));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let block = block.clone();
+                std::thread::spawn(move || store.insert(&block).unwrap())
+            })
+            .collect();
+        let inserted = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|inserted| *inserted)
+            .count();
+        assert_eq!(inserted, 1);
+        task::sleep(Duration::from_millis(100)).await;
+        let mut events = vec![];
+        while let Some(Some(event)) = subscription.next().now_or_never() {
+            events.push(event);
+        }
+        assert_eq!(events, vec![StorageEvent::Insert(*block.cid())]);
+    }
+
+    #[test]
+    fn test_ipld_limits() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(2, Duration::from_millis(10000));
+        config.max_ipld_depth = 8;
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let mut nested = ipld!([a.cid()]);
+        for _ in 0..7 {
+            nested = Ipld::List(vec![nested]);
+        }
+        let b = create_block(&nested);
+        nested = Ipld::List(vec![nested]);
+        let c = create_block(&nested);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.insert(&c).unwrap();
+        assert!(store.missing_blocks(b.cid()).unwrap().is_empty());
+        let err = store.missing_blocks(c.cid()).unwrap_err();
+        assert!(err.downcast_ref::<IpldTooComplex>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_max_links() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(100, Duration::from_millis(10000));
+        config.max_links = 4;
+        let store = StorageService::open_with_config(&sled_config, config).unwrap();
+        let leaves: Vec<_> = (0..5).map(|i| create_block(&ipld!(i))).collect();
+        for leaf in &leaves {
+            store.insert(leaf).unwrap();
+        }
+        let links =
+            |n: usize| Ipld::List(leaves[..n].iter().map(|b| Ipld::Link(*b.cid())).collect());
+        let a = create_block(&links(4));
+        let b = create_block(&links(5));
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(alias!(a), Some(a.cid())).await.unwrap();
+        let err = store.alias(alias!(b), Some(b.cid())).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TooManyLinks>(),
+            Some(&TooManyLinks(*b.cid()))
+        );
+        let err = store.missing_blocks(b.cid()).unwrap_err();
+        assert!(err.downcast_ref::<TooManyLinks>().is_some());
+    }
+
+    #[test]
+    fn test_missing_blocks() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        let c = create_block(&ipld!({ "c": [a.cid(), b.cid()] }));
+        let d = create_block(&ipld!({ "d": [c.cid(), a.cid()] }));
+        store.insert(&b).unwrap();
+        store.insert(&d).unwrap();
+        let missing: HashSet<Cid> = store.missing_blocks(d.cid()).unwrap().into_iter().collect();
+        assert_eq!(missing, [*a.cid(), *c.cid()].iter().copied().collect());
+        store.insert(&c).unwrap();
+        assert_eq!(store.missing_blocks(d.cid()).unwrap(), vec![*a.cid()]);
+        store.insert(&a).unwrap();
+        assert!(store.missing_blocks(d.cid()).unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_pin_many() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [a.cid()] }));
+        let d = create_block(&ipld!({ "d": [] }));
+        for block in &[&a, &b, &c, &d] {
+            store.insert(block).unwrap();
+        }
+        store.alias(b"x", Some(d.cid())).await.unwrap();
+        let pins = vec![
+            (b"x".to_vec(), *b.cid()),
+            (b"y".to_vec(), *c.cid()),
+            (b"z".to_vec(), *c.cid()),
+        ];
+        store.pin_many(&pins).await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_pinned!(&store, &c);
+        assert_unpinned!(&store, &d);
+        assert_eq!(
+            store.resolve_many(&[b"x", b"y", b"z"]).unwrap(),
+            vec![Some(*b.cid()), Some(*c.cid()), Some(*c.cid())]
+        );
+
+        store.alias(b"y", None).await.unwrap();
+        assert_pinned!(&store, &c);
+        store.alias(b"z", None).await.unwrap();
+        assert_unpinned!(&store, &c);
+        assert_pinned!(&store, &a);
+    }
+
+    #[async_std::test]
+    async fn test_iter_pins() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(x, Some(b.cid())).await.unwrap();
+        let pins: Vec<PinInfo> = store.iter_pins(true).try_collect().await.unwrap();
+        let size = (a.data().len() + b.data().len()) as u64;
+        assert_eq!(
+            pins,
+            vec![PinInfo {
+                alias: x.as_bytes().to_vec(),
+                cid: *b.cid(),
+                blocks: 2,
+                size: Some(size),
+            }]
+        );
+        let pins: Vec<PinInfo> = store.iter_pins(false).try_collect().await.unwrap();
+        assert_eq!(pins[0].size, None);
+    }
+
+    #[async_std::test]
+    async fn test_resolve_many() {
+        env_logger::try_init().ok();
+        let config = sled::Config::new().temporary(true);
+        let store = StorageService::open(&config, 2, Duration::from_millis(10000)).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        let x = alias!(x);
+        let y = alias!(y);
+        let z = alias!(z);
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        store.alias(x, Some(a.cid())).await.unwrap();
+        store.alias(z, Some(b.cid())).await.unwrap();
+        let cids = store.resolve_many(&[x, y, z]).unwrap();
+        assert_eq!(cids, vec![Some(*a.cid()), None, Some(*b.cid())]);
+    }
 }
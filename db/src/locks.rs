@@ -0,0 +1,26 @@
+use async_std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+/// Async locks keyed by alias, so that operations on the same alias are
+/// serialized while operations on different aliases proceed concurrently.
+#[derive(Default)]
+pub struct KeyedLocks {
+    locks: SyncMutex<HashMap<Vec<u8>, Arc<Mutex<()>>>>,
+}
+
+impl KeyedLocks {
+    /// Returns the locks of the keys, sorted by key so that they are always
+    /// acquired in the same order.
+    pub fn get<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Vec<Arc<Mutex<()>>> {
+        let mut keys: Vec<_> = keys.into_iter().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let mut locks = self.locks.lock().unwrap();
+        // drop the locks nobody is holding or waiting for
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        keys.into_iter()
+            .map(|key| locks.entry(key.to_vec()).or_default().clone())
+            .collect()
+    }
+}
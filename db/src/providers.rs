@@ -0,0 +1,107 @@
+use ipfs_embed_core::{PeerId, Result};
+use sled::Tree;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A score halves every day a peer doesn't serve us any blocks.
+const HALF_LIFE_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    score: f64,
+    /// Milliseconds since the unix epoch.
+    last_seen: u64,
+}
+
+impl Entry {
+    fn score_at(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last_seen) as f64;
+        self.score * 0.5f64.powf(age / HALF_LIFE_MS)
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[..8].copy_from_slice(&self.score.to_be_bytes());
+        buf[8..].copy_from_slice(&self.last_seen.to_be_bytes());
+        buf
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 {
+            return None;
+        }
+        let mut score = [0u8; 8];
+        score.copy_from_slice(&bytes[..8]);
+        let mut last_seen = [0u8; 8];
+        last_seen.copy_from_slice(&bytes[8..]);
+        let score = f64::from_be_bytes(score);
+        // a corrupted score would break the ordering of the peers
+        if !score.is_finite() {
+            return None;
+        }
+        Some(Self {
+            score,
+            last_seen: u64::from_be_bytes(last_seen),
+        })
+    }
+}
+
+/// History of peers that served us blocks, weighted by how recently they did.
+#[derive(Clone)]
+pub struct Providers {
+    // peer id -> entry
+    tree: Tree,
+}
+
+impl Providers {
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("providers")?,
+        })
+    }
+
+    pub fn record(&self, peer_id: &PeerId) -> Result<()> {
+        let now = now();
+        self.tree.fetch_and_update(peer_id.as_bytes(), |old| {
+            let score = old
+                .and_then(Entry::decode)
+                .map(|entry| entry.score_at(now))
+                .unwrap_or_default();
+            let entry = Entry {
+                score: score + 1.0,
+                last_seen: now,
+            };
+            Some(entry.encode().to_vec())
+        })?;
+        Ok(())
+    }
+
+    /// Returns up to `n` peers with the highest score.
+    pub fn best(&self, n: usize) -> Result<Vec<PeerId>> {
+        let now = now();
+        let mut peers = Vec::new();
+        for res in self.tree.iter() {
+            let (key, value) = res?;
+            let entry = if let Some(entry) = Entry::decode(&value) {
+                entry
+            } else {
+                continue;
+            };
+            if let Ok(peer_id) = PeerId::from_bytes(key.to_vec()) {
+                peers.push((entry.score_at(now), peer_id));
+            }
+        }
+        peers.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        Ok(peers
+            .into_iter()
+            .take(n)
+            .map(|(_, peer_id)| peer_id)
+            .collect())
+    }
+}
@@ -0,0 +1,195 @@
+use futures::future::Future;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_timer::Delay;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Total number of bytes sent and received over all connections.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BandwidthStats {
+    pub total_inbound: u64,
+    pub total_outbound: u64,
+}
+
+struct Bucket {
+    tokens: u64,
+    last: Instant,
+}
+
+/// Token bucket holding up to one second worth of bytes.
+struct Limiter {
+    rate: Option<u64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Limiter {
+    fn new(rate: Option<u64>) -> Self {
+        let rate = rate.map(|rate| rate.max(1));
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate.unwrap_or_default(),
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes up to `want` tokens. When the bucket is empty it returns the time to
+    /// wait before trying again.
+    fn take(&self, want: usize) -> Result<usize, Duration> {
+        let rate = if let Some(rate) = self.rate {
+            rate
+        } else {
+            return Ok(want);
+        };
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = (now - bucket.last).as_micros() * rate as u128 / 1_000_000;
+        if refill > 0 {
+            bucket.tokens = (bucket.tokens as u128 + refill).min(rate as u128) as u64;
+            bucket.last = now;
+        }
+        if bucket.tokens == 0 {
+            let wait = (1_000_000 / rate).max(1_000);
+            return Err(Duration::from_micros(wait));
+        }
+        let n = bucket.tokens.min(want as u64);
+        bucket.tokens -= n;
+        Ok(n as usize)
+    }
+
+    fn refund(&self, n: usize) {
+        if let Some(rate) = self.rate {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.tokens = (bucket.tokens + n as u64).min(rate);
+        }
+    }
+
+    fn poll_take(&self, cx: &mut Context, delay: &mut Option<Delay>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(timer) = delay.as_mut() {
+                if Pin::new(timer).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                *delay = None;
+            }
+            match self.take(want) {
+                Ok(n) => return Poll::Ready(n),
+                Err(wait) => *delay = Some(Delay::new(wait)),
+            }
+        }
+    }
+}
+
+/// Bandwidth shared by all connections.
+pub struct Bandwidth {
+    up: Limiter,
+    down: Limiter,
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl Bandwidth {
+    pub fn new(max_up: Option<u64>, max_down: Option<u64>) -> Self {
+        Self {
+            up: Limiter::new(max_up),
+            down: Limiter::new(max_down),
+            inbound: AtomicU64::new(0),
+            outbound: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        BandwidthStats {
+            total_inbound: self.inbound.load(Ordering::Relaxed),
+            total_outbound: self.outbound.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Connection that accounts for and limits the bytes it transfers.
+pub struct Throttled<S> {
+    inner: S,
+    bandwidth: Arc<Bandwidth>,
+    read_delay: Option<Delay>,
+    write_delay: Option<Delay>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, bandwidth: Arc<Bandwidth>) -> Self {
+        Self {
+            inner,
+            bandwidth,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = match this
+            .bandwidth
+            .down
+            .poll_take(cx, &mut this.read_delay, buf.len())
+        {
+            Poll::Ready(n) => n,
+            Poll::Pending => return Poll::Pending,
+        };
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..n]);
+        let read = match &res {
+            Poll::Ready(Ok(read)) => *read,
+            _ => 0,
+        };
+        this.bandwidth.down.refund(n - read);
+        this.bandwidth
+            .inbound
+            .fetch_add(read as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = match this
+            .bandwidth
+            .up
+            .poll_take(cx, &mut this.write_delay, buf.len())
+        {
+            Poll::Ready(n) => n,
+            Poll::Pending => return Poll::Pending,
+        };
+        let res = Pin::new(&mut this.inner).poll_write(cx, &buf[..n]);
+        let written = match &res {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        this.bandwidth.up.refund(n - written);
+        this.bandwidth
+            .outbound
+            .fetch_add(written as u64, Ordering::Relaxed);
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
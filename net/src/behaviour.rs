@@ -1,21 +1,20 @@
-use crate::config::NetworkConfig;
-use ip_network::IpNetwork;
+use crate::config::{is_global, AddressFilter, NetworkConfig};
+use crate::mdns::{FilteredMdns, MdnsDiscovered};
+use crate::protect::Protect;
 use ipfs_embed_core::{Cid, MultihashDigest, NetworkEvent, Result};
-use libp2p::core::PeerId;
+use libp2p::core::{Multiaddr, PeerId};
 use libp2p::identify::{Identify, IdentifyEvent};
 use libp2p::kad::record::store::MemoryStore;
 use libp2p::kad::{
     BootstrapError, BootstrapOk, GetProvidersOk, Kademlia, KademliaEvent, QueryResult,
 };
-use libp2p::mdns::{Mdns, MdnsEvent};
-use libp2p::multiaddr::Protocol;
 use libp2p::ping::{Ping, PingEvent};
 use libp2p::swarm::toggle::Toggle;
 use libp2p::swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters};
 use libp2p::NetworkBehaviour;
 use libp2p_bitswap::{Bitswap, BitswapEvent};
 use std::collections::{HashMap, VecDeque};
-use std::convert::TryFrom;
+use std::convert::{Infallible, TryFrom};
 use std::task::{Context, Poll};
 use thiserror::Error;
 
@@ -33,25 +32,26 @@ pub struct NetworkBackendBehaviour<M: MultihashDigest> {
     kad: Kademlia<MemoryStore>,
     #[behaviour(ignore)]
     allow_non_globals_in_dht: bool,
+    #[behaviour(ignore)]
+    address_filter: AddressFilter,
 
-    mdns: Toggle<Mdns>,
+    mdns: Toggle<FilteredMdns>,
     ping: Toggle<Ping>,
     identify: Identify,
     bitswap: Bitswap<M>,
+    protect: Protect,
 
     #[behaviour(ignore)]
     events: VecDeque<NetworkEvent>,
 }
 
-impl<M: MultihashDigest> NetworkBehaviourEventProcess<MdnsEvent> for NetworkBackendBehaviour<M> {
-    fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(list) => {
-                for (peer, _) in list {
-                    self.bitswap().connect(peer);
-                }
-            }
-            MdnsEvent::Expired(_) => {}
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<MdnsDiscovered>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, MdnsDiscovered(list): MdnsDiscovered) {
+        for (peer, _) in list {
+            self.bitswap().connect(peer.clone());
+            self.events.push_back(NetworkEvent::Discovered(peer));
         }
     }
 }
@@ -155,17 +155,12 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<IdentifyEvent>
             log::info!("{}: has external address {}", self.node_name, observed_addr);
             self.peers
                 .insert(peer_id.clone(), info.agent_version.clone());
-            self.kad.add_address(&self.peer_id, observed_addr);
+            if self.address_filter.allows(&observed_addr) {
+                self.kad.add_address(&self.peer_id, observed_addr);
+            }
             for addr in info.listen_addrs {
-                let global = match addr.iter().next() {
-                    Some(Protocol::Ip4(ip)) => IpNetwork::from(ip).is_global(),
-                    Some(Protocol::Ip6(ip)) => IpNetwork::from(ip).is_global(),
-                    Some(Protocol::Dns(_)) => true,
-                    Some(Protocol::Dns4(_)) => true,
-                    Some(Protocol::Dns6(_)) => true,
-                    _ => false,
-                };
-                if self.allow_non_globals_in_dht || global {
+                let global = is_global(&addr);
+                if (self.allow_non_globals_in_dht || global) && self.address_filter.allows(&addr) {
                     log::info!(
                         "{}: adding kademlia address {} {}",
                         self.node_name,
@@ -186,6 +181,12 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<IdentifyEvent>
     }
 }
 
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<Infallible> for NetworkBackendBehaviour<M> {
+    fn inject_event(&mut self, event: Infallible) {
+        match event {}
+    }
+}
+
 impl<M: MultihashDigest> NetworkBehaviourEventProcess<BitswapEvent> for NetworkBackendBehaviour<M> {
     fn inject_event(&mut self, event: BitswapEvent) {
         // Propagate bitswap events to the swarm.
@@ -213,8 +214,9 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
     pub fn new(config: NetworkConfig) -> Result<Self> {
         let peer_id = config.peer_id();
 
-        let mdns = if config.enable_mdns {
-            Some(Mdns::new()?)
+        // mdns would leak our local addresses when using a proxy.
+        let mdns = if config.enable_mdns && config.proxy.is_none() {
+            Some(FilteredMdns::new(config.address_filter.clone())?)
         } else {
             None
         }
@@ -222,10 +224,15 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
 
         let store = MemoryStore::new(peer_id.clone());
         let mut kad = Kademlia::new(peer_id.clone(), store);
-        for (addr, peer_id) in &config.boot_nodes {
+        let boot_nodes: Vec<_> = config
+            .boot_nodes
+            .iter()
+            .filter(|(addr, _)| config.address_filter.allows(addr))
+            .collect();
+        for (addr, peer_id) in &boot_nodes {
             kad.add_address(peer_id, addr.to_owned());
         }
-        if !config.boot_nodes.is_empty() {
+        if !boot_nodes.is_empty() {
             kad.bootstrap().expect("bootstrap nodes not empty");
         }
 
@@ -245,11 +252,13 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
             node_name: config.node_name,
             peer_id,
             allow_non_globals_in_dht: config.allow_non_globals_in_dht,
+            address_filter: config.address_filter,
             mdns,
             kad,
             ping,
             identify,
             bitswap,
+            protect: Default::default(),
             events: Default::default(),
             peers: Default::default(),
         })
@@ -262,6 +271,13 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
             .unwrap_or_else(|| peer_id.to_string())
     }
 
+    /// Adds the address of a peer to the DHT if it passes the address filter.
+    pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
+        if self.address_filter.allows(&addr) {
+            self.kad.add_address(peer_id, addr);
+        }
+    }
+
     pub fn kad(&mut self) -> &mut Kademlia<MemoryStore> {
         &mut self.kad
     }
@@ -270,6 +286,10 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
         &mut self.bitswap
     }
 
+    pub fn protect(&mut self) -> &mut Protect {
+        &mut self.protect
+    }
+
     pub fn custom_poll<T>(
         &mut self,
         _: &mut Context,
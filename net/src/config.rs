@@ -1,5 +1,52 @@
+use ip_network::IpNetwork;
 use libp2p::core::{Multiaddr, PeerId};
 use libp2p::identity::{Keypair, PublicKey};
+use libp2p::multiaddr::Protocol;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Returns true if the address is a global ip address or a dns name.
+pub(crate) fn is_global(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => IpNetwork::from(ip).is_global(),
+        Some(Protocol::Ip6(ip)) => IpNetwork::from(ip).is_global(),
+        Some(Protocol::Dns(_)) => true,
+        Some(Protocol::Dns4(_)) => true,
+        Some(Protocol::Dns6(_)) => true,
+        _ => false,
+    }
+}
+
+/// Filters the addresses that are dialed and advertised.
+#[derive(Clone)]
+pub enum AddressFilter {
+    /// Only global addresses. Loopback, link-local and private addresses are
+    /// dropped.
+    GlobalOnly,
+    /// All addresses.
+    AllowPrivate,
+    /// Addresses for which the function returns true.
+    Custom(Arc<dyn Fn(&Multiaddr) -> bool + Send + Sync>),
+}
+
+impl AddressFilter {
+    /// Returns true if the address passes the filter.
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        match self {
+            Self::GlobalOnly => is_global(addr),
+            Self::AllowPrivate => true,
+            Self::Custom(f) => f(addr),
+        }
+    }
+}
+
+/// Socks5 proxy configuration.
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    /// Address of the socks5 proxy. For tor this is usually `127.0.0.1:9050`.
+    pub address: SocketAddr,
+}
 
 /// Network configuration.
 #[derive(Clone)]
@@ -20,6 +67,29 @@ pub struct NetworkConfig {
     pub enable_ping: bool,
     /// Should we insert non-global addresses into the DHT?
     pub allow_non_globals_in_dht: bool,
+    /// Dial all connections through a socks5 proxy. Disables listening and mdns.
+    pub proxy: Option<ProxyConfig>,
+    /// Maximum number of bytes per second sent over all connections.
+    pub max_bandwidth_up: Option<u64>,
+    /// Maximum number of bytes per second received over all connections.
+    pub max_bandwidth_down: Option<u64>,
+    /// Time allowed for negotiating the security and muxer protocols of a new
+    /// connection.
+    pub connection_upgrade_timeout: Duration,
+    /// Maximum number of dials in progress. Further dials are queued and started
+    /// in the order of their priority.
+    pub max_concurrent_dials: Option<usize>,
+    /// Maximum number of open event subscriptions. `try_subscribe` fails with
+    /// `TooManySubscribers` beyond it. Subscriptions created with `subscribe`
+    /// count towards the limit, but aren't rejected.
+    pub max_subscribers: Option<usize>,
+    /// Filters the addresses of peers added to the DHT or discovered with mdns,
+    /// and the addresses returned by `external_addresses`.
+    pub address_filter: AddressFilter,
+    /// Only accept connections to and from peers with one of these keys. The key
+    /// is verified by the noise handshake, connections of other peers are closed
+    /// before any protocol is negotiated on them.
+    pub allowed_peer_keys: Option<Vec<PublicKey>>,
 }
 
 impl NetworkConfig {
@@ -32,6 +102,14 @@ impl NetworkConfig {
             enable_mdns: true,
             enable_ping: true,
             allow_non_globals_in_dht: false,
+            proxy: None,
+            max_bandwidth_up: None,
+            max_bandwidth_down: None,
+            connection_upgrade_timeout: Duration::from_secs(15),
+            max_concurrent_dials: None,
+            max_subscribers: None,
+            address_filter: AddressFilter::AllowPrivate,
+            allowed_peer_keys: None,
             node_key: Keypair::generate_ed25519(),
             node_name: names::Generator::with_naming(names::Name::Numbered)
                 .next()
@@ -0,0 +1,113 @@
+use libp2p::core::PeerId;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+struct Queued {
+    priority: i32,
+    seq: Reverse<u64>,
+    peer_id: PeerId,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+/// Dials waiting for a free slot, ordered by priority and then by the time they
+/// were queued.
+pub(crate) struct DialQueue {
+    max: Option<usize>,
+    timeout: Duration,
+    seq: u64,
+    queue: BinaryHeap<Queued>,
+    /// Dials in progress with the time they were started.
+    pending: HashMap<PeerId, Instant>,
+}
+
+impl DialQueue {
+    /// Creates a queue allowing `max` dials in progress. A dial that neither
+    /// succeeded nor failed within `timeout` stops taking up a slot.
+    pub fn new(max: Option<usize>, timeout: Duration) -> Self {
+        Self {
+            max,
+            timeout,
+            seq: 0,
+            queue: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, peer_id: PeerId, priority: i32) {
+        self.seq += 1;
+        self.queue.push(Queued {
+            priority,
+            seq: Reverse(self.seq),
+            peer_id,
+        });
+    }
+
+    /// Returns the next peer to dial if there is a free slot, marking the dial as
+    /// in progress.
+    pub fn pop(&mut self) -> Option<PeerId> {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        self.pending.retain(|_, started| now - *started < timeout);
+        loop {
+            if let Some(max) = self.max {
+                if self.pending.len() >= max {
+                    return None;
+                }
+            }
+            let peer_id = self.queue.pop()?.peer_id;
+            if self.pending.contains_key(&peer_id) {
+                continue;
+            }
+            self.pending.insert(peer_id.clone(), now);
+            return Some(peer_id);
+        }
+    }
+
+    /// Frees the slot of a dial that completed.
+    pub fn done(&mut self, peer_id: &PeerId) {
+        self.pending.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dial_priority() {
+        let mut dials = DialQueue::new(Some(1), Duration::from_secs(10));
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+        dials.push(a.clone(), 0);
+        assert_eq!(dials.pop(), Some(a.clone()));
+        dials.push(b.clone(), 0);
+        dials.push(c.clone(), 1000);
+        assert_eq!(dials.pop(), None);
+        dials.done(&a);
+        assert_eq!(dials.pop(), Some(c.clone()));
+        dials.done(&c);
+        assert_eq!(dials.pop(), Some(b));
+        assert_eq!(dials.pop(), None);
+    }
+}
@@ -1,103 +1,332 @@
 use async_std::task;
-use futures::channel::mpsc;
-use futures::future::{Future, FutureExt};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Future, FutureExt};
 use futures::stream::Stream;
-use ipfs_embed_core::{Cid, MultihashDigest, Network, NetworkEvent, PeerId, Result, StoreParams};
+use ipfs_embed_core::{
+    async_trait, Cid, MultihashDigest, Network, NetworkEvent, PeerId, Result, StoreParams,
+};
+use libp2p::core::connection::ListenerId;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::muxing::StreamMuxerBox;
 use libp2p::core::transport::upgrade::Version;
 use libp2p::core::transport::Transport;
-use libp2p::core::Multiaddr;
+use libp2p::core::{ConnectedPoint, Multiaddr};
+use libp2p::identity::Keypair as NodeKeypair;
+use libp2p::kad::record::store::RecordStore;
 use libp2p::kad::record::Key;
 use libp2p::mplex::MplexConfig;
 use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
 use libp2p::swarm::{Swarm, SwarmEvent};
 use libp2p::tcp::TcpConfig;
 //use libp2p::yamux::Config as YamuxConfig;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use thiserror::Error;
 
+mod bandwidth;
 mod behaviour;
 mod config;
+mod dial;
+mod mdns;
+mod protect;
+mod proxy;
 
+pub use bandwidth::BandwidthStats;
+use bandwidth::{Bandwidth, Throttled};
 use behaviour::NetworkBackendBehaviour;
-pub use config::NetworkConfig;
+pub use config::{AddressFilter, NetworkConfig, ProxyConfig};
+use dial::DialQueue;
+use proxy::Socks5Config;
+
+#[derive(Debug, Error)]
+#[error("Too many subscribers, the limit is {0}.")]
+pub struct TooManySubscribers(pub usize);
+
+#[derive(Debug, Error)]
+#[error("Peer {0} isn't allowed.")]
+pub struct PeerNotAllowed(pub PeerId);
+
+#[derive(Debug, Error)]
+#[error("Dialing {0} failed: {1}")]
+pub struct DialFailed(pub Multiaddr, pub String);
+
+#[derive(Debug, Error)]
+#[error("The identity is already being rotated.")]
+pub struct RotationInProgress;
+
+/// Returns the peer id contained in the address.
+fn peer_id_of(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+type Subscriptions = Arc<Mutex<Vec<mpsc::UnboundedSender<NetworkEvent>>>>;
+
+type BackendSwarm<M> = Swarm<NetworkBackendBehaviour<M>>;
 
 pub struct NetworkService<S: StoreParams> {
     _marker: PhantomData<S>,
-    tx: mpsc::UnboundedSender<SwarmMsg>,
-    local_peer_id: PeerId,
-    external_addresses: Vec<Multiaddr>,
+    tx: mpsc::UnboundedSender<SwarmMsg<S::Hashes>>,
+    config: NetworkConfig,
+    local_peer_id: RwLock<PeerId>,
+    external_addresses: RwLock<Vec<Multiaddr>>,
+    bandwidth: Arc<Bandwidth>,
+    subscriptions: Subscriptions,
+    max_subscribers: Option<usize>,
 }
 
 impl<S: StoreParams> NetworkService<S> {
     pub fn new(config: NetworkConfig) -> Result<Self> {
-        let dh_key = Keypair::<X25519Spec>::new()
-            .into_authentic(&config.node_key)
-            .unwrap();
-        let transport = TcpConfig::new()
-            .nodelay(true)
-            .upgrade(Version::V1)
-            .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
-            .multiplex(MplexConfig::new())
-            .timeout(Duration::from_secs(5));
-
-        let peer_id = config.peer_id();
-        let behaviour = NetworkBackendBehaviour::<S::Hashes>::new(config.clone())?;
-        let mut swarm = Swarm::new(transport, behaviour, peer_id.clone());
-        for addr in config.listen_addresses {
-            Swarm::listen_on(&mut swarm, addr)?;
-        }
-        for addr in config.public_addresses {
-            Swarm::add_external_address(&mut swarm, addr);
-        }
-
-        let addr = loop {
-            match swarm.next_event().now_or_never() {
-                Some(SwarmEvent::NewListenAddr(addr)) => break addr,
-                Some(SwarmEvent::ListenerClosed { reason, .. }) => reason?,
-                _ => {}
+        let bandwidth = Arc::new(Bandwidth::new(
+            config.max_bandwidth_up,
+            config.max_bandwidth_down,
+        ));
+        let mut swarm = Self::build_swarm(config.clone(), bandwidth.clone())?;
+        let listeners = listen_on(&mut swarm, &config)?;
+        let listen_addr = if listeners.is_empty() {
+            None
+        } else {
+            loop {
+                match swarm.next_event().now_or_never() {
+                    Some(SwarmEvent::NewListenAddr(addr)) => break Some(addr),
+                    Some(SwarmEvent::ListenerClosed { reason, .. }) => reason?,
+                    _ => {}
+                }
             }
         };
+        let external_addresses = advertised(&config, listen_addr);
+        let peer_id = config.peer_id();
 
         let (tx, rx) = mpsc::unbounded();
+        let subscriptions = Subscriptions::default();
 
         task::spawn(NetworkWorker {
             swarm,
             rx,
-            subscriptions: Default::default(),
+            subscriptions: subscriptions.clone(),
+            listeners,
+            rotation: None,
+            wants: Default::default(),
+            protected: Default::default(),
+            connected: Default::default(),
+            addr_dials: Default::default(),
+            dials: DialQueue::new(
+                config.max_concurrent_dials,
+                // a dial taking longer than this has failed to upgrade
+                config.connection_upgrade_timeout * 2,
+            ),
+            config: config.clone(),
         });
 
         Ok(Self {
             _marker: PhantomData,
             tx,
-            local_peer_id: peer_id,
-            external_addresses: vec![addr],
+            local_peer_id: RwLock::new(peer_id),
+            external_addresses: RwLock::new(external_addresses),
+            bandwidth,
+            subscriptions,
+            max_subscribers: config.max_subscribers,
+            config,
         })
     }
+
+    fn build_swarm(
+        config: NetworkConfig,
+        bandwidth: Arc<Bandwidth>,
+    ) -> Result<BackendSwarm<S::Hashes>> {
+        let dh_key = Keypair::<X25519Spec>::new()
+            .into_authentic(&config.node_key)
+            .unwrap();
+        // the noise handshake proves that the peer owns the key its peer id is
+        // derived from, so checking the peer id checks the key
+        let allowed: Option<Arc<HashSet<PeerId>>> = config
+            .allowed_peer_keys
+            .as_ref()
+            .map(|keys| Arc::new(keys.iter().map(|key| key.clone().into_peer_id()).collect()));
+        let check_peer = move |(peer_id, muxer): (PeerId, StreamMuxerBox), _| {
+            let res = match allowed.as_ref() {
+                Some(allowed) if !allowed.contains(&peer_id) => {
+                    log::debug!("rejecting connection of {}", peer_id);
+                    let err = PeerNotAllowed(peer_id);
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, err))
+                }
+                _ => Ok((peer_id, muxer)),
+            };
+            future::ready(res)
+        };
+        let transport = if let Some(proxy) = config.proxy.as_ref() {
+            Socks5Config::new(proxy.address)
+                .map({
+                    let bandwidth = bandwidth.clone();
+                    move |stream, _| Throttled::new(stream, bandwidth.clone())
+                })
+                .upgrade(Version::V1)
+                .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
+                .multiplex(MplexConfig::new())
+                .timeout(config.connection_upgrade_timeout)
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+                .and_then(check_peer)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                .boxed()
+        } else {
+            TcpConfig::new()
+                .nodelay(true)
+                .map({
+                    let bandwidth = bandwidth.clone();
+                    move |stream, _| Throttled::new(stream, bandwidth.clone())
+                })
+                .upgrade(Version::V1)
+                .authenticate(NoiseConfig::xx(dh_key).into_authenticated())
+                .multiplex(MplexConfig::new())
+                .timeout(config.connection_upgrade_timeout)
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+                .and_then(check_peer)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                .boxed()
+        };
+
+        let peer_id = config.peer_id();
+        let behaviour = NetworkBackendBehaviour::<S::Hashes>::new(config.clone())?;
+        let mut swarm = Swarm::new(transport, behaviour, peer_id.clone());
+        for addr in config.public_addresses.iter() {
+            Swarm::add_external_address(&mut swarm, addr.clone());
+        }
+        Ok(swarm)
+    }
+
+    /// Number of open event subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| !s.is_closed());
+        subscriptions.len()
+    }
+
+    /// Subscribes to network events. Fails when `NetworkConfig::max_subscribers`
+    /// subscriptions are open. Subscriptions created with `subscribe` count towards
+    /// the limit, but aren't rejected.
+    pub fn try_subscribe(&self) -> Result<mpsc::UnboundedReceiver<NetworkEvent>> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| !s.is_closed());
+        if let Some(max) = self.max_subscribers {
+            if subscriptions.len() >= max {
+                return Err(TooManySubscribers(max).into());
+            }
+        }
+        let (tx, rx) = mpsc::unbounded();
+        subscriptions.push(tx);
+        Ok(rx)
+    }
+
+    pub fn bandwidth_usage(&self) -> BandwidthStats {
+        self.bandwidth.stats()
+    }
+
+    /// Tags the peer as protected. Connections to protected peers aren't closed
+    /// when they are idle or by `prune_peers`, and are reestablished when they
+    /// get closed anyway.
+    pub fn protect_peer(&self, peer_id: &PeerId, tag: &str) {
+        let msg = SwarmMsg::Protect(peer_id.clone(), tag.to_string());
+        self.tx.unbounded_send(msg).ok();
+    }
+
+    /// Removes a tag from the peer. The peer stays protected until all its tags
+    /// are removed.
+    pub fn unprotect_peer(&self, peer_id: &PeerId, tag: &str) {
+        let msg = SwarmMsg::Unprotect(peer_id.clone(), tag.to_string());
+        self.tx.unbounded_send(msg).ok();
+    }
+
+    /// Closes the connections to unprotected peers until at most `max_peers`
+    /// peers are connected.
+    pub fn prune_peers(&self, max_peers: usize) {
+        self.tx.unbounded_send(SwarmMsg::Prune(max_peers)).ok();
+    }
 }
 
-enum SwarmMsg {
+/// Starts listening on the configured addresses and returns the listeners.
+fn listen_on<M: MultihashDigest>(
+    swarm: &mut BackendSwarm<M>,
+    config: &NetworkConfig,
+) -> Result<Vec<ListenerId>> {
+    // Listening through a socks5 proxy isn't possible.
+    if config.proxy.is_some() {
+        return Ok(vec![]);
+    }
+    let mut listeners = Vec::with_capacity(config.listen_addresses.len());
+    for addr in &config.listen_addresses {
+        listeners.push(Swarm::listen_on(swarm, addr.clone())?);
+    }
+    Ok(listeners)
+}
+
+/// Returns the addresses to advertise once listening on `listen_addr`.
+fn advertised(config: &NetworkConfig, listen_addr: Option<Multiaddr>) -> Vec<Multiaddr> {
+    // Behind a socks5 proxy only the public addresses get advertised.
+    let addrs = if config.proxy.is_some() {
+        config.public_addresses.clone()
+    } else {
+        listen_addr.into_iter().collect()
+    };
+    addrs
+        .into_iter()
+        .filter(|addr| config.address_filter.allows(addr))
+        .collect()
+}
+
+enum SwarmMsg<M: MultihashDigest> {
+    /// Replaces the swarm after a change of identity. The old swarm stops
+    /// listening so that the new one can reuse its ports, and is dropped once
+    /// the new one listens.
+    Replace(
+        Box<BackendSwarm<M>>,
+        oneshot::Sender<Result<Vec<Multiaddr>>>,
+    ),
     Provide(Key),
     Unprovide(Key),
     Providers(Key),
-    Connect(PeerId),
+    Connect(PeerId, i32),
+    ConnectAddr(Multiaddr, oneshot::Sender<Result<()>>),
+    Bootstrap(Vec<(Multiaddr, PeerId)>),
     Want(Cid, i32),
     Cancel(Cid),
     SendTo(PeerId, Cid, Vec<u8>),
     Send(Cid, Vec<u8>),
-    Subscribe(mpsc::UnboundedSender<NetworkEvent>),
+    Protect(PeerId, String),
+    Unprotect(PeerId, String),
+    Prune(usize),
 }
 
+#[async_trait]
 impl<S: StoreParams + 'static> Network<S> for NetworkService<S> {
     type Subscription = mpsc::UnboundedReceiver<NetworkEvent>;
 
-    fn local_peer_id(&self) -> &PeerId {
-        &self.local_peer_id
+    fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id.read().unwrap().clone()
     }
 
     fn external_addresses(&self) -> Vec<Multiaddr> {
-        self.external_addresses.clone()
+        self.external_addresses.read().unwrap().clone()
+    }
+
+    async fn rotate_identity(&self, keypair: NodeKeypair) -> Result<()> {
+        let mut config = self.config.clone();
+        config.node_key = keypair;
+        let peer_id = config.peer_id();
+        let swarm = Self::build_swarm(config, self.bandwidth.clone())?;
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .unbounded_send(SwarmMsg::Replace(Box::new(swarm), tx))
+            .ok();
+        let external_addresses = rx.await??;
+        *self.local_peer_id.write().unwrap() = peer_id;
+        *self.external_addresses.write().unwrap() = external_addresses;
+        Ok(())
     }
 
     fn provide(&self, cid: &Cid) {
@@ -115,8 +344,20 @@ impl<S: StoreParams + 'static> Network<S> for NetworkService<S> {
         self.tx.unbounded_send(SwarmMsg::Providers(key)).ok();
     }
 
-    fn connect(&self, peer_id: PeerId) {
-        self.tx.unbounded_send(SwarmMsg::Connect(peer_id)).ok();
+    fn connect(&self, peer_id: PeerId, priority: i32) {
+        let msg = SwarmMsg::Connect(peer_id, priority);
+        self.tx.unbounded_send(msg).ok();
+    }
+
+    async fn connect_addr(&self, addr: Multiaddr) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.unbounded_send(SwarmMsg::ConnectAddr(addr, tx)).ok();
+        rx.await?
+    }
+
+    fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]) {
+        let msg = SwarmMsg::Bootstrap(nodes.to_vec());
+        self.tx.unbounded_send(msg).ok();
     }
 
     fn want(&self, cid: Cid, priority: i32) {
@@ -139,15 +380,208 @@ impl<S: StoreParams + 'static> Network<S> for NetworkService<S> {
 
     fn subscribe(&self) -> Self::Subscription {
         let (tx, rx) = mpsc::unbounded();
-        self.tx.unbounded_send(SwarmMsg::Subscribe(tx)).ok();
+        self.subscriptions.lock().unwrap().push(tx);
         rx
     }
+
+    fn try_subscribe(&self) -> Result<Self::Subscription> {
+        NetworkService::try_subscribe(self)
+    }
+}
+
+/// A swarm with a new identity that is waiting for its first listen address.
+struct Rotation<M: MultihashDigest> {
+    swarm: Box<BackendSwarm<M>>,
+    listeners: Vec<ListenerId>,
+    tx: oneshot::Sender<Result<Vec<Multiaddr>>>,
 }
 
 struct NetworkWorker<M: MultihashDigest> {
-    swarm: Swarm<NetworkBackendBehaviour<M>>,
-    rx: mpsc::UnboundedReceiver<SwarmMsg>,
-    subscriptions: Vec<mpsc::UnboundedSender<NetworkEvent>>,
+    swarm: BackendSwarm<M>,
+    rx: mpsc::UnboundedReceiver<SwarmMsg<M>>,
+    subscriptions: Subscriptions,
+    listeners: Vec<ListenerId>,
+    rotation: Option<Rotation<M>>,
+    /// Wanted blocks, wanted again from the new swarm after a rotation.
+    wants: HashMap<Cid, i32>,
+    protected: HashMap<PeerId, HashSet<String>>,
+    connected: HashSet<PeerId>,
+    /// Callers of `connect_addr` waiting for the dial of an address.
+    addr_dials: HashMap<Multiaddr, Vec<oneshot::Sender<Result<()>>>>,
+    dials: DialQueue,
+    config: NetworkConfig,
+}
+
+impl<M: MultihashDigest> NetworkWorker<M> {
+    fn emit(&self, ev: NetworkEvent) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|s| s.unbounded_send(ev.clone()).is_ok());
+    }
+
+    /// Starts queued dials while there are free slots, returning `true` if any
+    /// were started.
+    fn start_dials(&mut self) -> bool {
+        let mut started = false;
+        while let Some(peer_id) = self.dials.pop() {
+            if Swarm::is_connected(&self.swarm, &peer_id) {
+                self.dials.done(&peer_id);
+                continue;
+            }
+            self.swarm.bitswap().connect(peer_id);
+            started = true;
+        }
+        started
+    }
+
+    fn connect_addr(&mut self, addr: Multiaddr, tx: oneshot::Sender<Result<()>>) {
+        if let Some(peer_id) = peer_id_of(&addr) {
+            if Swarm::is_connected(&self.swarm, &peer_id) {
+                tx.send(Ok(())).ok();
+                return;
+            }
+        }
+        if let Some(waiting) = self.addr_dials.get_mut(&addr) {
+            waiting.push(tx);
+            return;
+        }
+        match Swarm::dial_addr(&mut self.swarm, addr.clone()) {
+            Ok(()) => {
+                self.addr_dials.insert(addr, vec![tx]);
+            }
+            Err(err) => {
+                tx.send(Err(DialFailed(addr, err.to_string()).into())).ok();
+            }
+        }
+    }
+
+    fn replace(
+        &mut self,
+        mut swarm: Box<BackendSwarm<M>>,
+        tx: oneshot::Sender<Result<Vec<Multiaddr>>>,
+    ) {
+        if self.rotation.is_some() {
+            tx.send(Err(RotationInProgress.into())).ok();
+            return;
+        }
+        for id in self.listeners.drain(..) {
+            Swarm::remove_listener(&mut self.swarm, id).ok();
+        }
+        match listen_on(&mut swarm, &self.config) {
+            Ok(listeners) if listeners.is_empty() => {
+                self.swap(*swarm);
+                tx.send(Ok(advertised(&self.config, None))).ok();
+            }
+            Ok(listeners) => {
+                self.rotation = Some(Rotation {
+                    swarm,
+                    listeners,
+                    tx,
+                });
+            }
+            Err(err) => {
+                self.relisten();
+                tx.send(Err(err)).ok();
+            }
+        }
+    }
+
+    /// Listens with the current swarm again after a failed rotation.
+    fn relisten(&mut self) {
+        match listen_on(&mut self.swarm, &self.config) {
+            Ok(listeners) => self.listeners = listeners,
+            Err(err) => log::error!("failed to listen after a failed rotation: {}", err),
+        }
+    }
+
+    /// Completes or aborts a rotation once the new swarm listens or fails to.
+    fn poll_rotation(&mut self, ctx: &mut Context) {
+        let mut rotation = if let Some(rotation) = self.rotation.take() {
+            rotation
+        } else {
+            return;
+        };
+        loop {
+            let ev = {
+                let next = rotation.swarm.next_event();
+                futures::pin_mut!(next);
+                match next.poll(ctx) {
+                    Poll::Ready(ev) => ev,
+                    Poll::Pending => {
+                        self.rotation = Some(rotation);
+                        return;
+                    }
+                }
+            };
+            match ev {
+                SwarmEvent::NewListenAddr(addr) => {
+                    let Rotation {
+                        swarm,
+                        listeners,
+                        tx,
+                    } = rotation;
+                    self.listeners = listeners;
+                    self.swap(*swarm);
+                    tx.send(Ok(advertised(&self.config, Some(addr)))).ok();
+                    return;
+                }
+                SwarmEvent::ListenerClosed {
+                    reason: Err(err), ..
+                } => {
+                    self.relisten();
+                    rotation.tx.send(Err(err.into())).ok();
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the swarm with one that already listens.
+    fn swap(&mut self, swarm: BackendSwarm<M>) {
+        let provided: Vec<Key> = self
+            .swarm
+            .kad()
+            .store_mut()
+            .provided()
+            .map(|record| record.key.clone())
+            .collect();
+        self.swarm = swarm;
+        let addrs: Vec<Multiaddr> = self.addr_dials.keys().cloned().collect();
+        for addr in addrs {
+            self.addr_dialed(&addr, Err("identity rotated".into()));
+        }
+        log::info!(
+            "rotated identity to {}, reproviding {} records",
+            Swarm::local_peer_id(&self.swarm),
+            provided.len()
+        );
+        for key in provided {
+            let _ = self.swarm.kad().start_providing(key);
+        }
+        let disconnected: Vec<PeerId> = self.connected.drain().collect();
+        for peer_id in disconnected {
+            self.emit(NetworkEvent::PeerDisconnected(peer_id));
+        }
+        for (cid, priority) in &self.wants {
+            self.swarm.bitswap().want_block(*cid, *priority);
+        }
+        let protected: Vec<PeerId> = self.protected.keys().cloned().collect();
+        for peer_id in protected {
+            self.swarm.protect().set_protected(peer_id.clone(), true);
+            Swarm::dial(&mut self.swarm, &peer_id).ok();
+        }
+    }
+
+    fn addr_dialed(&mut self, addr: &Multiaddr, res: std::result::Result<(), String>) {
+        for tx in self.addr_dials.remove(addr).unwrap_or_default() {
+            let res = res
+                .clone()
+                .map_err(|err| DialFailed(addr.clone(), err).into());
+            tx.send(res).ok();
+        }
+    }
 }
 
 impl<M: MultihashDigest> Future for NetworkWorker<M> {
@@ -161,6 +595,7 @@ impl<M: MultihashDigest> Future for NetworkWorker<M> {
                 Poll::Ready(None) => return Poll::Ready(()),
             };
             match cmd {
+                SwarmMsg::Replace(swarm, tx) => self.replace(swarm, tx),
                 SwarmMsg::Provide(cid) => {
                     let _ = self.swarm.kad().start_providing(cid);
                 }
@@ -168,26 +603,117 @@ impl<M: MultihashDigest> Future for NetworkWorker<M> {
                 SwarmMsg::Providers(cid) => {
                     let _ = self.swarm.kad().get_providers(cid);
                 }
-                SwarmMsg::Connect(peer_id) => self.swarm.bitswap().connect(peer_id),
-                SwarmMsg::Want(cid, priority) => self.swarm.bitswap().want_block(cid, priority),
-                SwarmMsg::Cancel(cid) => self.swarm.bitswap().cancel_block(&cid),
+                SwarmMsg::Connect(peer_id, priority) => self.dials.push(peer_id, priority),
+                SwarmMsg::ConnectAddr(addr, tx) => self.connect_addr(addr, tx),
+                SwarmMsg::Bootstrap(nodes) => {
+                    for (addr, peer_id) in nodes {
+                        self.swarm.add_address(&peer_id, addr);
+                    }
+                    self.swarm.kad().bootstrap().ok();
+                }
+                SwarmMsg::Want(cid, priority) => {
+                    self.wants.insert(cid, priority);
+                    self.swarm.bitswap().want_block(cid, priority);
+                }
+                SwarmMsg::Cancel(cid) => {
+                    self.wants.remove(&cid);
+                    self.swarm.bitswap().cancel_block(&cid);
+                }
                 SwarmMsg::SendTo(peer_id, cid, data) => {
                     self.swarm
                         .bitswap()
                         .send_block(&peer_id, cid, data.into_boxed_slice())
                 }
                 SwarmMsg::Send(cid, data) => self.swarm.bitswap().send_block_all(&cid, &data),
-                SwarmMsg::Subscribe(tx) => self.subscriptions.push(tx),
+                SwarmMsg::Protect(peer_id, tag) => {
+                    if !self.protected.contains_key(&peer_id) {
+                        self.swarm.protect().set_protected(peer_id.clone(), true);
+                    }
+                    self.protected.entry(peer_id).or_default().insert(tag);
+                }
+                SwarmMsg::Unprotect(peer_id, tag) => {
+                    if let Some(tags) = self.protected.get_mut(&peer_id) {
+                        tags.remove(&tag);
+                        if tags.is_empty() {
+                            self.protected.remove(&peer_id);
+                            self.swarm.protect().set_protected(peer_id, false);
+                        }
+                    }
+                }
+                SwarmMsg::Prune(max_peers) => {
+                    for peer_id in protect::prunable(&self.connected, &self.protected, max_peers) {
+                        log::debug!("pruning connection to {}", peer_id);
+                        // banning closes the connections
+                        Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+                        Swarm::unban_peer_id(&mut self.swarm, peer_id);
+                    }
+                }
             }
         }
+        self.poll_rotation(ctx);
+        self.start_dials();
         loop {
-            let ev = match Pin::new(&mut self.swarm).poll_next(ctx) {
-                Poll::Ready(Some(ev)) => ev,
-                Poll::Pending => break,
-                Poll::Ready(None) => return Poll::Ready(()),
+            let ev = {
+                let next = self.swarm.next_event();
+                futures::pin_mut!(next);
+                match next.poll(ctx) {
+                    Poll::Ready(ev) => ev,
+                    Poll::Pending => break,
+                }
             };
-            self.subscriptions
-                .retain(|s| s.unbounded_send(ev.clone()).is_ok())
+            match ev {
+                SwarmEvent::Behaviour(ev) => {
+                    if let NetworkEvent::ReceivedBlock(_, cid, _) = &ev {
+                        self.wants.remove(cid);
+                    }
+                    self.emit(ev)
+                }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint,
+                    num_established,
+                    ..
+                } => {
+                    if let ConnectedPoint::Dialer { address } = &endpoint {
+                        self.addr_dialed(address, Ok(()));
+                    }
+                    self.dials.done(&peer_id);
+                    if num_established.get() == 1 {
+                        self.connected.insert(peer_id.clone());
+                        self.emit(NetworkEvent::PeerConnected(peer_id));
+                    }
+                }
+                SwarmEvent::UnreachableAddr {
+                    peer_id,
+                    attempts_remaining: 0,
+                    ..
+                } => {
+                    self.dials.done(&peer_id);
+                    self.emit(NetworkEvent::ConnectFailed(peer_id));
+                }
+                SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
+                    self.addr_dialed(&address, Err(error.to_string()));
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established,
+                    ..
+                } => {
+                    if num_established == 0 {
+                        self.connected.remove(&peer_id);
+                        if self.protected.contains_key(&peer_id) {
+                            log::debug!("redialing protected peer {}", peer_id);
+                            Swarm::dial(&mut self.swarm, &peer_id).ok();
+                        }
+                        self.emit(NetworkEvent::PeerDisconnected(peer_id));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.start_dials() {
+            // poll the swarm again to start the dials
+            ctx.waker().wake_by_ref();
         }
         Poll::Pending
     }
@@ -0,0 +1,110 @@
+use crate::config::AddressFilter;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::mdns::{Mdns, MdnsEvent};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler};
+use std::io;
+use std::task::{Context, Poll};
+
+type Handler = <Mdns as NetworkBehaviour>::ProtocolsHandler;
+
+/// Peers discovered by mdns with their addresses that pass the address filter.
+pub(crate) struct MdnsDiscovered(pub Vec<(PeerId, Multiaddr)>);
+
+/// Mdns that only reports and dials the discovered addresses that pass the
+/// address filter.
+pub(crate) struct FilteredMdns {
+    mdns: Mdns,
+    address_filter: AddressFilter,
+}
+
+impl FilteredMdns {
+    pub fn new(address_filter: AddressFilter) -> io::Result<Self> {
+        Ok(Self {
+            mdns: Mdns::new()?,
+            address_filter,
+        })
+    }
+}
+
+/// Returns the addresses that pass the filter.
+fn filter<T>(
+    filter: &AddressFilter,
+    items: impl IntoIterator<Item = T>,
+    addr: impl Fn(&T) -> &Multiaddr,
+) -> Vec<T> {
+    items
+        .into_iter()
+        .filter(|item| filter.allows(addr(item)))
+        .collect()
+}
+
+impl NetworkBehaviour for FilteredMdns {
+    type ProtocolsHandler = Handler;
+    type OutEvent = MdnsDiscovered;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.mdns.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let addrs = self.mdns.addresses_of_peer(peer_id);
+        filter(&self.address_filter, addrs, |addr| addr)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.mdns.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.mdns.inject_disconnected(peer_id)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: <Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.mdns.inject_event(peer_id, connection, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<<Handler as ProtocolsHandler>::InEvent, MdnsDiscovered>> {
+        let address_filter = &self.address_filter;
+        self.mdns.poll(cx, params).map(|action| {
+            action.map_out(|event| match event {
+                MdnsEvent::Discovered(list) => {
+                    MdnsDiscovered(filter(address_filter, list, |(_, addr)| addr))
+                }
+                MdnsEvent::Expired(_) => MdnsDiscovered(vec![]),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_private_mdns_address() {
+        let peer = PeerId::random();
+        let private: Multiaddr = "/ip4/192.168.1.2/tcp/4001".parse().unwrap();
+        let global: Multiaddr = "/ip4/8.8.8.8/tcp/4001".parse().unwrap();
+        let list = vec![
+            (peer.clone(), private.clone()),
+            (peer.clone(), global.clone()),
+        ];
+
+        let allowed = filter(&AddressFilter::GlobalOnly, list.clone(), |(_, addr)| addr);
+        assert_eq!(allowed, vec![(peer.clone(), global)]);
+
+        let allowed = filter(&AddressFilter::AllowPrivate, list, |(_, addr)| addr);
+        assert_eq!(allowed.len(), 2);
+        assert!(allowed.contains(&(peer, private)));
+    }
+}
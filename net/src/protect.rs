@@ -0,0 +1,204 @@
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade};
+use libp2p::core::{ConnectedPoint, Multiaddr, PeerId};
+use libp2p::swarm::{
+    KeepAlive, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+    PollParameters, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+    SubstreamProtocol,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+
+/// Returns the connected peers to disconnect so that at most `max_peers` remain.
+/// Protected peers are never returned.
+pub(crate) fn prunable(
+    connected: &HashSet<PeerId>,
+    protected: &HashMap<PeerId, HashSet<String>>,
+    max_peers: usize,
+) -> Vec<PeerId> {
+    let excess = connected.len().saturating_sub(max_peers);
+    connected
+        .iter()
+        .filter(|peer_id| !protected.contains_key(peer_id))
+        .take(excess)
+        .cloned()
+        .collect()
+}
+
+/// Keeps the connections to protected peers alive, so that they aren't closed
+/// when they are idle.
+#[derive(Default)]
+pub(crate) struct Protect {
+    protected: HashSet<PeerId>,
+    events: VecDeque<NetworkBehaviourAction<bool, Infallible>>,
+}
+
+impl Protect {
+    pub fn set_protected(&mut self, peer_id: PeerId, protected: bool) {
+        if protected {
+            self.protected.insert(peer_id.clone());
+        } else {
+            self.protected.remove(&peer_id);
+        }
+        self.events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::All,
+                event: protected,
+            });
+    }
+}
+
+impl NetworkBehaviour for Protect {
+    type ProtocolsHandler = KeepAliveHandler;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        KeepAliveHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        vec![]
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        _: &ConnectedPoint,
+    ) {
+        if self.protected.contains(peer_id) {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: peer_id.clone(),
+                    handler: NotifyHandler::One(*connection),
+                    event: true,
+                });
+        }
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: Infallible) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<bool, Infallible>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Handler that keeps its connection alive while the peer is protected.
+#[derive(Default)]
+pub(crate) struct KeepAliveHandler {
+    protected: bool,
+}
+
+impl ProtocolsHandler for KeepAliveHandler {
+    type InEvent = bool;
+    type OutEvent = Infallible;
+    type Error = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type OutboundOpenInfo = Infallible;
+    type InboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        _: <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output,
+        _: Self::InboundOpenInfo,
+    ) {
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        _: <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        _: Self::OutboundOpenInfo,
+    ) {
+    }
+
+    fn inject_event(&mut self, protected: bool) {
+        self.protected = protected;
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        _: Self::OutboundOpenInfo,
+        _: ProtocolsHandlerUpgrErr<
+            <Self::OutboundProtocol as OutboundUpgrade<NegotiatedSubstream>>::Error,
+        >,
+    ) {
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.protected {
+            KeepAlive::Yes
+        } else {
+            KeepAlive::No
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ProtocolsHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_keeps_protected() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+        let connected: HashSet<PeerId> =
+            vec![a.clone(), b.clone(), c.clone()].into_iter().collect();
+        let mut protected: HashMap<PeerId, HashSet<String>> = HashMap::new();
+        protected
+            .entry(a.clone())
+            .or_default()
+            .insert("bootstrap".into());
+        let pruned = prunable(&connected, &protected, 0);
+        assert_eq!(pruned.len(), 2);
+        assert!(!pruned.contains(&a));
+        let pruned = prunable(&connected, &protected, 2);
+        assert_eq!(pruned.len(), 1);
+        assert!(!pruned.contains(&a));
+        assert!(prunable(&connected, &protected, 3).is_empty());
+    }
+
+    #[test]
+    fn test_protected_keep_alive() {
+        let mut handler = KeepAliveHandler::default();
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+        handler.inject_event(true);
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::Yes);
+        handler.inject_event(false);
+        assert_eq!(handler.connection_keep_alive(), KeepAlive::No);
+    }
+}
@@ -0,0 +1,128 @@
+use async_std::net::TcpStream;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::stream;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::transport::{ListenerEvent, Transport, TransportError};
+use libp2p::core::Multiaddr;
+use std::io;
+use std::net::SocketAddr;
+
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Tcp transport that dials through a socks5 proxy. Listening is not supported.
+#[derive(Clone, Debug)]
+pub struct Socks5Config {
+    proxy: SocketAddr,
+}
+
+impl Socks5Config {
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self { proxy }
+    }
+}
+
+impl Transport for Socks5Config {
+    type Output = TcpStream;
+    type Error = io::Error;
+    type Listener =
+        stream::Empty<Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+    type ListenerUpgrade = future::Ready<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let target = socks_addr(&addr).ok_or(TransportError::MultiaddrNotSupported(addr))?;
+        log::debug!("dialing through proxy {}", self.proxy);
+        Ok(connect(self.proxy, target).boxed())
+    }
+}
+
+/// Encodes the address type, address and port of a multiaddr as used in a socks5
+/// connect request. Domain names are resolved by the proxy.
+fn socks_addr(addr: &Multiaddr) -> Option<Vec<u8>> {
+    let mut iter = addr.iter();
+    let mut buf = match iter.next()? {
+        Protocol::Ip4(ip) => {
+            let mut buf = vec![ATYP_IPV4];
+            buf.extend_from_slice(&ip.octets());
+            buf
+        }
+        Protocol::Ip6(ip) => {
+            let mut buf = vec![ATYP_IPV6];
+            buf.extend_from_slice(&ip.octets());
+            buf
+        }
+        Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+            if name.len() > 255 {
+                return None;
+            }
+            let mut buf = vec![ATYP_DOMAIN, name.len() as u8];
+            buf.extend_from_slice(name.as_bytes());
+            buf
+        }
+        _ => return None,
+    };
+    match iter.next()? {
+        Protocol::Tcp(port) => buf.extend_from_slice(&port.to_be_bytes()),
+        _ => return None,
+    }
+    Some(buf)
+}
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+async fn connect(proxy: SocketAddr, target: Vec<u8>) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.set_nodelay(true)?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTH]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [SOCKS_VERSION, NO_AUTH] {
+        return Err(proxy_error(format!(
+            "proxy {} requires authentication",
+            proxy
+        )));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    request.extend_from_slice(&target);
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(proxy_error(format!("invalid socks version {}", reply[0])));
+    }
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "proxy {} connect failed with reply {}",
+            proxy, reply[1]
+        )));
+    }
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        atyp => return Err(proxy_error(format!("invalid address type {}", atyp))),
+    };
+    // skip the bound address and port
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
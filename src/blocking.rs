@@ -0,0 +1,63 @@
+//! Synchronous api for callers that can't use async, like ffi bindings.
+//!
+//! Every call blocks the calling thread until the operation completes. The
+//! ipfs task keeps running on the async-std executor in the meantime, so
+//! fetching blocks from the network works while a call is blocked. The methods
+//! must not be called from async code, where they would block an executor
+//! thread.
+use crate::Ipfs;
+use async_std::task;
+use ipfs_embed_core::{Block, Cid, Network, Result, Storage, StoreParams};
+use libipld::codec::Decode;
+use libipld::ipld::Ipld;
+use libipld::store::Store;
+
+/// Blocking wrapper around `Ipfs`.
+pub struct BlockingIpfs<P, S, N> {
+    ipfs: Ipfs<P, S, N>,
+}
+
+impl<P, S, N> Clone for BlockingIpfs<P, S, N> {
+    fn clone(&self) -> Self {
+        Self {
+            ipfs: self.ipfs.clone(),
+        }
+    }
+}
+
+impl<P, S, N> BlockingIpfs<P, S, N>
+where
+    P: StoreParams + Unpin + 'static,
+    S: Storage<P>,
+    N: Network<P>,
+    Ipld: Decode<P::Codecs>,
+{
+    pub fn new(ipfs: Ipfs<P, S, N>) -> Self {
+        Self { ipfs }
+    }
+
+    /// Returns the wrapped `Ipfs`.
+    pub fn inner(&self) -> &Ipfs<P, S, N> {
+        &self.ipfs
+    }
+
+    pub fn get(&self, cid: &Cid) -> Result<Block<P>> {
+        task::block_on(self.ipfs.get(cid))
+    }
+
+    pub fn insert(&self, block: &Block<P>) -> Result<()> {
+        task::block_on(self.ipfs.insert(block))
+    }
+
+    pub fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
+        task::block_on(self.ipfs.alias(alias, cid))
+    }
+
+    pub fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+        task::block_on(self.ipfs.resolve(alias))
+    }
+
+    pub fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
+        task::block_on(self.ipfs.pinned(cid))
+    }
+}
@@ -0,0 +1,87 @@
+//! In-memory cache of recently read blocks in front of the store.
+use ipfs_embed_core::{Block, Cid, StoreParams};
+use std::collections::{BTreeMap, HashMap};
+
+/// Number of reads served from the hot cache and from the store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Reads served from the in-memory hot cache.
+    pub hot_hits: u64,
+    /// Reads served from the store on disk.
+    pub cold_hits: u64,
+}
+
+impl CacheStats {
+    /// Fraction of the local reads served from the hot cache.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hot_hits + self.cold_hits;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hot_hits as f64 / total as f64
+    }
+}
+
+/// Least recently used blocks up to a fixed number of blocks.
+pub(crate) struct HotCache<P> {
+    capacity: usize,
+    // cid -> (sequence number of the last use, block)
+    blocks: HashMap<Cid, (u64, Block<P>)>,
+    // sequence number of the last use -> cid, least recently used first
+    order: BTreeMap<u64, Cid>,
+    seq: u64,
+}
+
+impl<P: StoreParams> HotCache<P> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: Default::default(),
+            order: Default::default(),
+            seq: 0,
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    pub fn get(&mut self, cid: &Cid) -> Option<Block<P>> {
+        let seq = self.next_seq();
+        let (last_use, block) = self.blocks.get_mut(cid)?;
+        self.order.remove(last_use);
+        self.order.insert(seq, *cid);
+        *last_use = seq;
+        Some(block.clone())
+    }
+
+    pub fn insert(&mut self, block: Block<P>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let cid = *block.cid();
+        let seq = self.next_seq();
+        if let Some((last_use, _)) = self.blocks.insert(cid, (seq, block)) {
+            self.order.remove(&last_use);
+        }
+        self.order.insert(seq, cid);
+        while self.blocks.len() > self.capacity {
+            let oldest = self.order.keys().next().copied();
+            if let Some(cid) = oldest.and_then(|seq| self.order.remove(&seq)) {
+                self.blocks.remove(&cid);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.order.clear();
+    }
+
+    pub fn remove(&mut self, cid: &Cid) {
+        if let Some((last_use, _)) = self.blocks.remove(cid) {
+            self.order.remove(&last_use);
+        }
+    }
+}
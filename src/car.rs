@@ -0,0 +1,54 @@
+//! Writing of CARv1 files.
+use futures::io::{AsyncWrite, AsyncWriteExt};
+use ipfs_embed_core::{Cid, Result};
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Writes the dag-cbor encoded header `{ "roots": [root], "version": 1 }`.
+pub async fn write_header<W: AsyncWrite + Unpin>(w: &mut W, root: &Cid) -> Result<()> {
+    let cid = root.to_bytes();
+    let mut header = Vec::with_capacity(cid.len() + 32);
+    // map with two entries
+    header.push(0xa2);
+    header.push(0x65);
+    header.extend_from_slice(b"roots");
+    // list with one entry
+    header.push(0x81);
+    // tag 42 followed by a byte string of the multibase identity prefix and cid
+    header.extend_from_slice(&[0xd8, 0x2a]);
+    let len = cid.len() + 1;
+    if len < 24 {
+        header.push(0x40 | len as u8);
+    } else {
+        header.push(0x58);
+        header.push(len as u8);
+    }
+    header.push(0x00);
+    header.extend_from_slice(&cid);
+    header.push(0x67);
+    header.extend_from_slice(b"version");
+    header.push(0x01);
+
+    let mut buf = Vec::with_capacity(header.len() + 2);
+    write_varint(&mut buf, header.len() as u64);
+    buf.extend_from_slice(&header);
+    w.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Writes a length prefixed block section.
+pub async fn write_block<W: AsyncWrite + Unpin>(w: &mut W, cid: &Cid, data: &[u8]) -> Result<()> {
+    let cid = cid.to_bytes();
+    let mut buf = Vec::with_capacity(cid.len() + 10);
+    write_varint(&mut buf, (cid.len() + data.len()) as u64);
+    buf.extend_from_slice(&cid);
+    w.write_all(&buf).await?;
+    w.write_all(data).await?;
+    Ok(())
+}
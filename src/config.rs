@@ -0,0 +1,206 @@
+use ipfs_embed_core::{Multiaddr, PeerId};
+use std::time::Duration;
+
+/// What to do with blocks received from peers that we didn't want.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnsolicitedPolicy {
+    /// Drop the block.
+    Drop,
+    /// Verify and insert the block into the store, where it is evicted like any
+    /// other unpinned block.
+    Cache,
+    /// Drop the block and stop serving the peer that sent it.
+    Penalize,
+}
+
+/// What `get` does when reading a block from the store fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadErrorPolicy {
+    /// Return the error.
+    Fail,
+    /// Fetch the block from the network if the error is transient. Other errors,
+    /// for example a corrupted database, are returned.
+    FetchOnTransient,
+}
+
+/// Which blocks read from the store are checked against their cid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyPolicy {
+    /// Trust the store.
+    Never,
+    /// Verify every block.
+    Always,
+    /// Verify this fraction of the blocks, spread evenly over the reads.
+    Sample(f64),
+}
+
+/// Retries of storage operations that failed with a transient error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries.
+    pub max_retries: usize,
+    /// Delay before the first retry. The delay doubles on every retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Ipfs configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Time to wait for a block to be received from the network.
+    pub timeout: Duration,
+    /// Number of peers that served us blocks in a previous session to dial on
+    /// startup.
+    pub predial_providers: usize,
+    /// A cid inserted again within this window is only provided once.
+    pub provide_dedup_window: Duration,
+    /// Handling of blocks that we didn't want.
+    pub unsolicited_policy: UnsolicitedPolicy,
+    /// Pin every inserted block, so that the store becomes an append-only
+    /// archive. Only the block itself is pinned, its links are pinned when they
+    /// are inserted. Inserting a block again doesn't pin it again. The store
+    /// grows without bounds unless blocks are unpinned with `remove_auto_pin`.
+    /// Blocks fetched from the network aren't pinned, not even when they are
+    /// inserted later.
+    pub auto_pin: bool,
+    /// Retries of inserts and alias updates.
+    pub retry: RetryPolicy,
+    /// Nodes to bootstrap from when no peers are discovered via mdns within the
+    /// `fallback_timeout`.
+    pub fallback_boot_nodes: Vec<(Multiaddr, PeerId)>,
+    /// Time to wait for mdns to discover a peer.
+    pub fallback_timeout: Duration,
+    /// Return blocks fetched from the network before they are written to the
+    /// store. Lowers the latency of `get`, but a block may not be stored yet when
+    /// `get` returns, and is lost if the process exits before the write.
+    pub persist_in_background: bool,
+    /// Ask the connected peers for a block for this long before looking up its
+    /// providers in the DHT. Without it both start at the same time.
+    pub local_first_timeout: Option<Duration>,
+    /// Handling of errors when reading a block from the store.
+    pub read_error_policy: ReadErrorPolicy,
+    /// Maximum number of concurrent provider queries in the DHT. Further queries
+    /// are queued until a running query completes.
+    pub max_provider_queries: Option<usize>,
+    /// Maximum number of distinct blocks fetched from the network at a time.
+    /// Further wants are queued until a running want completes. The timeout of a
+    /// queued want starts when it leaves the queue.
+    pub max_concurrent_wants: Option<usize>,
+    /// Provide all stored blocks on startup, so that they are discoverable
+    /// without waiting for them to be inserted again.
+    pub reprovide_on_start: bool,
+    /// Provides are queued until this many peers are connected, so that the
+    /// provider records reach enough nodes of the DHT.
+    pub min_peers_to_provide: usize,
+    /// Provides are queued until the DHT bootstrap completed, so that they don't
+    /// go into an empty routing table, but at most this long, so that nodes
+    /// without boot nodes still provide. `None` provides right away.
+    pub wait_for_bootstrap: Option<Duration>,
+    /// Timeouts of the retries of a want that timed out. A want is only given up
+    /// after the last retry timed out.
+    pub want_retries: Vec<Duration>,
+    /// Blocks smaller than this many bytes are stored and sent to peers that
+    /// want them, but not provided in the DHT.
+    pub min_provide_size: usize,
+    /// Number of discovered providers to fetch a block from concurrently. The
+    /// first valid block received wins and the want is canceled at the others.
+    pub parallel_providers: usize,
+    /// Number of discovered providers to connect to in addition to the ones the
+    /// block is fetched from, so that the following blocks of a dag can be
+    /// fetched from already open connections.
+    pub eager_connect: usize,
+    /// Time to wait for the ipfs task to accept a request to fetch blocks before
+    /// failing with `Busy`. Without it callers wait until the task catches up.
+    pub busy_timeout: Option<Duration>,
+    /// Retries of fetching a missing block of a dag that is being pinned. Blocks
+    /// fetched before a failure are stored, so a retry continues where the
+    /// failed attempt stopped.
+    pub fetch_retry: RetryPolicy,
+    /// Verification of blocks read from the store, to detect corruption of the
+    /// storage medium.
+    pub verify_on_read: VerifyPolicy,
+    /// Remove blocks that fail verification, unless they are pinned.
+    pub remove_corrupt: bool,
+    /// Interval at which the providers of aliased cids are looked up, to record
+    /// how available they are on the network. Disabled by default.
+    pub availability_interval: Option<Duration>,
+    /// Maximum number of distinct links of a block followed when walking a dag in
+    /// `sync`, `export_selective` and the other walks. Blocks with more links fail
+    /// with `TooManyLinks`, so that a single block can't enqueue an unbounded
+    /// number of fetches.
+    pub max_links: usize,
+    /// Number of cids remembered per peer for `peer_haves`.
+    pub max_peer_haves: usize,
+    /// Maximum estimated memory in bytes used by the blocks being fetched. New
+    /// fetches fail with `MemoryLimit` while it is exceeded. See `memory_usage`.
+    pub max_wanted_memory: Option<usize>,
+    /// Number of events buffered per subscription of `events`. A subscription
+    /// that falls further behind misses the oldest events.
+    pub max_buffered_events: usize,
+    /// Number of recently read blocks kept in memory in front of the store.
+    /// Blocks removed from the store are dropped from it once the removal is
+    /// reported by the store. Disabled by default.
+    pub hot_cache_size: usize,
+    /// Maximum number of blocks fetched at a time by `get_pipeline`.
+    pub pipeline_concurrency: usize,
+    /// Ignore the wants of peers for this long after startup, so that a freshly
+    /// started node can connect and fill its routing table before serving
+    /// blocks. Blocks are still fetched during the warmup.
+    pub serve_after: Option<Duration>,
+    /// Verify that the blocks received from peers match their cid. Invalid blocks
+    /// are dropped, and the peer is penalized with the `Penalize` unsolicited
+    /// policy. Only disable this when all peers are trusted.
+    pub verify_received: bool,
+}
+
+impl Config {
+    /// Creates a new ipfs configuration.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            predial_providers: 4,
+            provide_dedup_window: Duration::from_secs(60),
+            unsolicited_policy: UnsolicitedPolicy::Drop,
+            auto_pin: false,
+            retry: Default::default(),
+            fallback_boot_nodes: vec![],
+            fallback_timeout: Duration::from_secs(10),
+            persist_in_background: false,
+            local_first_timeout: None,
+            read_error_policy: ReadErrorPolicy::Fail,
+            max_provider_queries: None,
+            max_concurrent_wants: None,
+            reprovide_on_start: true,
+            min_peers_to_provide: 0,
+            wait_for_bootstrap: Some(Duration::from_secs(10)),
+            want_retries: vec![],
+            min_provide_size: 0,
+            parallel_providers: 3,
+            eager_connect: 0,
+            busy_timeout: None,
+            fetch_retry: RetryPolicy {
+                max_retries: 0,
+                backoff: Duration::from_secs(1),
+            },
+            verify_on_read: VerifyPolicy::Never,
+            remove_corrupt: false,
+            availability_interval: None,
+            max_links: 1 << 16,
+            max_peer_haves: 256,
+            max_wanted_memory: None,
+            max_buffered_events: 1024,
+            hot_cache_size: 0,
+            pipeline_concurrency: 16,
+            serve_after: None,
+            verify_received: true,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Human readable rendering of blocks.
+use libipld::ipld::Ipld;
+use std::fmt::Write;
+
+/// Number of bytes shown of a byte string.
+const MAX_BYTES: usize = 32;
+
+fn render_bytes(out: &mut String, bytes: &[u8]) {
+    write!(out, "<{} bytes: ", bytes.len()).unwrap();
+    for b in bytes.iter().take(MAX_BYTES) {
+        write!(out, "{:02x}", b).unwrap();
+    }
+    if bytes.len() > MAX_BYTES {
+        out.push_str("...");
+    }
+    out.push('>');
+}
+
+fn indent(out: &mut String, level: usize) {
+    for _ in 0..level {
+        out.push_str("  ");
+    }
+}
+
+fn render_ipld(out: &mut String, ipld: &Ipld, level: usize) {
+    match ipld {
+        Ipld::Null => out.push_str("null"),
+        Ipld::Bool(b) => write!(out, "{}", b).unwrap(),
+        Ipld::Integer(i) => write!(out, "{}", i).unwrap(),
+        Ipld::Float(f) => write!(out, "{}", f).unwrap(),
+        Ipld::String(s) => write!(out, "{:?}", s).unwrap(),
+        Ipld::Bytes(bytes) => render_bytes(out, bytes),
+        Ipld::Link(cid) => write!(out, "{{\"/\": \"{}\"}}", cid).unwrap(),
+        Ipld::List(list) => {
+            if list.is_empty() {
+                out.push_str("[]");
+                return;
+            }
+            out.push_str("[\n");
+            for (i, ipld) in list.iter().enumerate() {
+                indent(out, level + 1);
+                render_ipld(out, ipld, level + 1);
+                if i + 1 < list.len() {
+                    out.push(',');
+                }
+                out.push('\n');
+            }
+            indent(out, level);
+            out.push(']');
+        }
+        Ipld::Map(map) => {
+            if map.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+            out.push_str("{\n");
+            for (i, (key, ipld)) in map.iter().enumerate() {
+                indent(out, level + 1);
+                write!(out, "{:?}: ", key).unwrap();
+                render_ipld(out, ipld, level + 1);
+                if i + 1 < map.len() {
+                    out.push(',');
+                }
+                out.push('\n');
+            }
+            indent(out, level);
+            out.push('}');
+        }
+    }
+}
+
+/// Renders decoded ipld as json with links written as `{"/": cid}`. Byte strings
+/// are summarized by their length and a hex prefix, which is also how raw blocks
+/// are shown.
+pub(crate) fn render(ipld: &Ipld) -> String {
+    let mut out = String::new();
+    render_ipld(&mut out, ipld, 0);
+    out
+}
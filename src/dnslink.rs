@@ -0,0 +1,152 @@
+//! Resolution of dnslink records to cids.
+use async_std_resolver::proto::rr::rdata::TXT;
+use async_std_resolver::{resolver_from_system_conf, AsyncStdResolver, ResolveErrorKind};
+use async_trait::async_trait;
+use ipfs_embed_core::{Cid, Result};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DnsLinkError {
+    #[error("No dnslink record found for {0}.")]
+    NotFound(String),
+    #[error("Malformed dnslink record {0:?}.")]
+    Malformed(String),
+    #[error("Resolving ipns name {0} is not supported.")]
+    Ipns(String),
+}
+
+/// Looks up the txt records of a domain.
+#[async_trait]
+pub trait TxtResolver: Send + Sync {
+    /// Returns all txt records of `name`. A name without any txt records resolves
+    /// to an empty list.
+    async fn txt(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Txt resolver using the system dns configuration.
+pub struct SystemResolver {
+    resolver: AsyncStdResolver,
+}
+
+impl SystemResolver {
+    pub async fn new() -> Result<Self> {
+        let resolver = resolver_from_system_conf().await?;
+        Ok(Self { resolver })
+    }
+}
+
+#[async_trait]
+impl TxtResolver for SystemResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>> {
+        match self.resolver.txt_lookup(name).await {
+            Ok(lookup) => Ok(lookup.iter().map(txt_to_string).collect()),
+            Err(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
+                _ => Err(err.into()),
+            },
+        }
+    }
+}
+
+fn txt_to_string(txt: &TXT) -> String {
+    txt.txt_data()
+        .iter()
+        .map(|data| String::from_utf8_lossy(data))
+        .collect()
+}
+
+/// Parses the first `dnslink=` record in `records`.
+pub fn parse_dnslink(domain: &str, records: &[String]) -> Result<Cid> {
+    let record = records
+        .iter()
+        .find_map(|record| record.strip_prefix("dnslink="))
+        .ok_or_else(|| DnsLinkError::NotFound(domain.to_string()))?;
+    let malformed = || DnsLinkError::Malformed(record.to_string());
+    let mut parts = record.splitn(4, '/');
+    if parts.next() != Some("") {
+        return Err(malformed().into());
+    }
+    match (parts.next(), parts.next()) {
+        (Some("ipfs"), Some(cid)) => Ok(Cid::try_from(cid).map_err(|_| malformed())?),
+        (Some("ipns"), Some(name)) if !name.is_empty() => {
+            Err(DnsLinkError::Ipns(name.to_string()).into())
+        }
+        _ => Err(malformed().into()),
+    }
+}
+
+/// Resolves the dnslink record of `domain` with `resolver`.
+pub async fn resolve_dnslink<R: TxtResolver + ?Sized>(resolver: &R, domain: &str) -> Result<Cid> {
+    let name = format!("_dnslink.{}", domain);
+    let records = resolver.txt(&name).await?;
+    parse_dnslink(domain, &records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MockResolver(HashMap<&'static str, Vec<String>>);
+
+    #[async_trait]
+    impl TxtResolver for MockResolver {
+        async fn txt(&self, name: &str) -> Result<Vec<String>> {
+            Ok(self.0.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    const CID: &str = "QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u";
+
+    fn resolver() -> MockResolver {
+        let mut records = HashMap::new();
+        records.insert(
+            "_dnslink.example.com",
+            vec![
+                "v=spf1 -all".to_string(),
+                format!("dnslink=/ipfs/{}/index.html", CID),
+            ],
+        );
+        records.insert(
+            "_dnslink.ipns.example.com",
+            vec!["dnslink=/ipns/example.org".to_string()],
+        );
+        records.insert(
+            "_dnslink.broken.example.com",
+            vec!["dnslink=/ipfs/notacid".to_string()],
+        );
+        MockResolver(records)
+    }
+
+    #[async_std::test]
+    async fn test_resolve_dnslink() {
+        let resolver = resolver();
+        let cid = resolve_dnslink(&resolver, "example.com").await.unwrap();
+        assert_eq!(cid, Cid::try_from(CID).unwrap());
+
+        let err = resolve_dnslink(&resolver, "missing.example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsLinkError>(),
+            Some(DnsLinkError::NotFound(_))
+        ));
+
+        let err = resolve_dnslink(&resolver, "broken.example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsLinkError>(),
+            Some(DnsLinkError::Malformed(_))
+        ));
+
+        let err = resolve_dnslink(&resolver, "ipns.example.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsLinkError>(),
+            Some(DnsLinkError::Ipns(_))
+        ));
+    }
+}
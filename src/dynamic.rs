@@ -0,0 +1,462 @@
+//! Type erased storage and network backends, so that nodes with different
+//! backends can be held behind one type.
+use crate::Ipfs;
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use ipfs_embed_core::{
+    Block, BoxStream, Cid, Keypair, Multiaddr, Network, NetworkEvent, PeerId, PinInfo, Result,
+    Storage, StorageEvent, StoreParams,
+};
+use std::time::Duration;
+
+/// Ipfs with type erased backends.
+pub type DynIpfs<P> = Ipfs<P, DynStorage<P>, DynNetwork<P>>;
+
+/// Object safe version of `Storage`.
+#[async_trait]
+trait ErasedStorage<P: StoreParams>: Send + Sync + 'static {
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>>;
+    fn contains(&self, cid: &Cid) -> Result<bool>;
+    fn touch(&self, cid: &Cid) -> Result<()>;
+    fn insert(&self, block: &Block<P>) -> Result<bool>;
+    async fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()>;
+    async fn pin_depth(&self, alias: &[u8], cid: &Cid, depth: usize) -> Result<()>;
+    async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()>;
+    async fn pin(&self, cid: &Cid) -> Result<()>;
+    async fn unpin(&self, cid: &Cid) -> Result<()>;
+    async fn auto_pin(&self, cid: &Cid) -> Result<()>;
+    async fn remove_auto_pin(&self, cid: &Cid) -> Result<()>;
+    async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64>;
+    async fn release(&self, lease: u64) -> Result<()>;
+    fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>>;
+    fn resolve_many(&self, aliases: &[&[u8]]) -> Result<Vec<Option<Cid>>>;
+    async fn pinned(&self, cid: &Cid) -> Result<Option<bool>>;
+    async fn remove(&self, cid: &Cid) -> Result<bool>;
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>>;
+    fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()>;
+    fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>>;
+    fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>>;
+    fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>>;
+    fn blocks(&self) -> BoxStream<'static, Result<Cid>>;
+    fn record_provider(&self, peer_id: &PeerId) -> Result<()>;
+    fn good_providers(&self, n: usize) -> Result<Vec<PeerId>>;
+    fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()>;
+    fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>>;
+    fn subscribe(&self) -> BoxStream<'static, StorageEvent>;
+    fn try_subscribe(&self) -> Result<BoxStream<'static, StorageEvent>>;
+}
+
+#[async_trait]
+impl<P: StoreParams, S: Storage<P>> ErasedStorage<P> for S {
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Storage::get(self, cid)
+    }
+
+    fn contains(&self, cid: &Cid) -> Result<bool> {
+        Storage::contains(self, cid)
+    }
+
+    fn touch(&self, cid: &Cid) -> Result<()> {
+        Storage::touch(self, cid)
+    }
+
+    fn insert(&self, block: &Block<P>) -> Result<bool> {
+        Storage::insert(self, block)
+    }
+
+    async fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        Storage::alias(self, alias, cid).await
+    }
+
+    async fn pin_depth(&self, alias: &[u8], cid: &Cid, depth: usize) -> Result<()> {
+        Storage::pin_depth(self, alias, cid, depth).await
+    }
+
+    async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()> {
+        Storage::pin_many(self, pins).await
+    }
+
+    async fn pin(&self, cid: &Cid) -> Result<()> {
+        Storage::pin(self, cid).await
+    }
+
+    async fn unpin(&self, cid: &Cid) -> Result<()> {
+        Storage::unpin(self, cid).await
+    }
+
+    async fn auto_pin(&self, cid: &Cid) -> Result<()> {
+        Storage::auto_pin(self, cid).await
+    }
+
+    async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+        Storage::remove_auto_pin(self, cid).await
+    }
+
+    async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64> {
+        Storage::lease(self, cid, ttl).await
+    }
+
+    async fn release(&self, lease: u64) -> Result<()> {
+        Storage::release(self, lease).await
+    }
+
+    fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
+        Storage::resolve(self, alias)
+    }
+
+    fn resolve_many(&self, aliases: &[&[u8]]) -> Result<Vec<Option<Cid>>> {
+        Storage::resolve_many(self, aliases)
+    }
+
+    async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
+        Storage::pinned(self, cid).await
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<bool> {
+        Storage::remove(self, cid).await
+    }
+
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        Storage::missing_blocks(self, cid)
+    }
+
+    fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()> {
+        Storage::tag_block(self, cid, tag)
+    }
+
+    fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        Storage::blocks_by_tag(self, tag)
+    }
+
+    fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>> {
+        Storage::iter_pins(self, size)
+    }
+
+    fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>> {
+        Storage::aliases(self)
+    }
+
+    fn blocks(&self) -> BoxStream<'static, Result<Cid>> {
+        Storage::blocks(self)
+    }
+
+    fn record_provider(&self, peer_id: &PeerId) -> Result<()> {
+        Storage::record_provider(self, peer_id)
+    }
+
+    fn good_providers(&self, n: usize) -> Result<Vec<PeerId>> {
+        Storage::good_providers(self, n)
+    }
+
+    fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()> {
+        Storage::record_availability(self, cid, providers)
+    }
+
+    fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>> {
+        Storage::availability_history(self, cid)
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, StorageEvent> {
+        Storage::subscribe(self).boxed()
+    }
+
+    fn try_subscribe(&self) -> Result<BoxStream<'static, StorageEvent>> {
+        Ok(Storage::try_subscribe(self)?.boxed())
+    }
+}
+
+/// A type erased `Storage`.
+pub struct DynStorage<P: StoreParams>(Box<dyn ErasedStorage<P>>);
+
+impl<P: StoreParams> DynStorage<P> {
+    pub fn new<S: Storage<P>>(storage: S) -> Self {
+        Self(Box::new(storage))
+    }
+}
+
+#[async_trait]
+impl<P: StoreParams> Storage<P> for DynStorage<P> {
+    type Subscription = BoxStream<'static, StorageEvent>;
+
+    fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.0.get(cid)
+    }
+
+    fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.0.contains(cid)
+    }
+
+    fn touch(&self, cid: &Cid) -> Result<()> {
+        self.0.touch(cid)
+    }
+
+    fn insert(&self, block: &Block<P>) -> Result<bool> {
+        self.0.insert(block)
+    }
+
+    async fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
+        self.0.alias(alias.as_ref(), cid).await
+    }
+
+    async fn pin_depth<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: &Cid,
+        depth: usize,
+    ) -> Result<()> {
+        self.0.pin_depth(alias.as_ref(), cid, depth).await
+    }
+
+    async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()> {
+        self.0.pin_many(pins).await
+    }
+
+    async fn pin(&self, cid: &Cid) -> Result<()> {
+        self.0.pin(cid).await
+    }
+
+    async fn unpin(&self, cid: &Cid) -> Result<()> {
+        self.0.unpin(cid).await
+    }
+
+    async fn auto_pin(&self, cid: &Cid) -> Result<()> {
+        self.0.auto_pin(cid).await
+    }
+
+    async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+        self.0.remove_auto_pin(cid).await
+    }
+
+    async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64> {
+        self.0.lease(cid, ttl).await
+    }
+
+    async fn release(&self, lease: u64) -> Result<()> {
+        self.0.release(lease).await
+    }
+
+    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+        self.0.resolve(alias.as_ref())
+    }
+
+    fn resolve_many<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        aliases: &[T],
+    ) -> Result<Vec<Option<Cid>>> {
+        let aliases: Vec<&[u8]> = aliases.iter().map(AsRef::as_ref).collect();
+        self.0.resolve_many(&aliases)
+    }
+
+    async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
+        self.0.pinned(cid).await
+    }
+
+    async fn remove(&self, cid: &Cid) -> Result<bool> {
+        self.0.remove(cid).await
+    }
+
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+
+    fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()> {
+        self.0.tag_block(cid, tag)
+    }
+
+    fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        self.0.blocks_by_tag(tag)
+    }
+
+    fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>> {
+        self.0.iter_pins(size)
+    }
+
+    fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>> {
+        self.0.aliases()
+    }
+
+    fn blocks(&self) -> BoxStream<'static, Result<Cid>> {
+        self.0.blocks()
+    }
+
+    fn record_provider(&self, peer_id: &PeerId) -> Result<()> {
+        self.0.record_provider(peer_id)
+    }
+
+    fn good_providers(&self, n: usize) -> Result<Vec<PeerId>> {
+        self.0.good_providers(n)
+    }
+
+    fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()> {
+        self.0.record_availability(cid, providers)
+    }
+
+    fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>> {
+        self.0.availability_history(cid)
+    }
+
+    fn subscribe(&self) -> Self::Subscription {
+        self.0.subscribe()
+    }
+
+    fn try_subscribe(&self) -> Result<Self::Subscription> {
+        self.0.try_subscribe()
+    }
+}
+
+/// Object safe version of `Network`.
+#[async_trait]
+trait ErasedNetwork<P: StoreParams>: Send + Sync + 'static {
+    fn local_peer_id(&self) -> PeerId;
+    fn external_addresses(&self) -> Vec<Multiaddr>;
+    async fn rotate_identity(&self, keypair: Keypair) -> Result<()>;
+    fn providers(&self, cid: &Cid);
+    fn provide(&self, cid: &Cid);
+    fn unprovide(&self, cid: &Cid);
+    fn connect(&self, peer_id: PeerId, priority: i32);
+    async fn connect_addr(&self, addr: Multiaddr) -> Result<()>;
+    fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]);
+    fn want(&self, cid: Cid, priority: i32);
+    fn cancel(&self, cid: Cid);
+    fn send_to(&self, peer_id: PeerId, cid: Cid, data: Vec<u8>);
+    fn send(&self, cid: Cid, data: Vec<u8>);
+    fn subscribe(&self) -> BoxStream<'static, NetworkEvent>;
+    fn try_subscribe(&self) -> Result<BoxStream<'static, NetworkEvent>>;
+}
+
+#[async_trait]
+impl<P: StoreParams, N: Network<P>> ErasedNetwork<P> for N {
+    fn local_peer_id(&self) -> PeerId {
+        Network::local_peer_id(self)
+    }
+
+    fn external_addresses(&self) -> Vec<Multiaddr> {
+        Network::external_addresses(self)
+    }
+
+    async fn rotate_identity(&self, keypair: Keypair) -> Result<()> {
+        Network::rotate_identity(self, keypair).await
+    }
+
+    fn providers(&self, cid: &Cid) {
+        Network::providers(self, cid)
+    }
+
+    fn provide(&self, cid: &Cid) {
+        Network::provide(self, cid)
+    }
+
+    fn unprovide(&self, cid: &Cid) {
+        Network::unprovide(self, cid)
+    }
+
+    fn connect(&self, peer_id: PeerId, priority: i32) {
+        Network::connect(self, peer_id, priority)
+    }
+
+    async fn connect_addr(&self, addr: Multiaddr) -> Result<()> {
+        Network::connect_addr(self, addr).await
+    }
+
+    fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]) {
+        Network::bootstrap(self, nodes)
+    }
+
+    fn want(&self, cid: Cid, priority: i32) {
+        Network::want(self, cid, priority)
+    }
+
+    fn cancel(&self, cid: Cid) {
+        Network::cancel(self, cid)
+    }
+
+    fn send_to(&self, peer_id: PeerId, cid: Cid, data: Vec<u8>) {
+        Network::send_to(self, peer_id, cid, data)
+    }
+
+    fn send(&self, cid: Cid, data: Vec<u8>) {
+        Network::send(self, cid, data)
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, NetworkEvent> {
+        Network::subscribe(self).boxed()
+    }
+
+    fn try_subscribe(&self) -> Result<BoxStream<'static, NetworkEvent>> {
+        Ok(Network::try_subscribe(self)?.boxed())
+    }
+}
+
+/// A type erased `Network`.
+pub struct DynNetwork<P: StoreParams>(Box<dyn ErasedNetwork<P>>);
+
+impl<P: StoreParams> DynNetwork<P> {
+    pub fn new<N: Network<P>>(network: N) -> Self {
+        Self(Box::new(network))
+    }
+}
+
+#[async_trait]
+impl<P: StoreParams> Network<P> for DynNetwork<P> {
+    type Subscription = BoxStream<'static, NetworkEvent>;
+
+    fn local_peer_id(&self) -> PeerId {
+        self.0.local_peer_id()
+    }
+
+    fn external_addresses(&self) -> Vec<Multiaddr> {
+        self.0.external_addresses()
+    }
+
+    async fn rotate_identity(&self, keypair: Keypair) -> Result<()> {
+        self.0.rotate_identity(keypair).await
+    }
+
+    fn providers(&self, cid: &Cid) {
+        self.0.providers(cid)
+    }
+
+    fn provide(&self, cid: &Cid) {
+        self.0.provide(cid)
+    }
+
+    fn unprovide(&self, cid: &Cid) {
+        self.0.unprovide(cid)
+    }
+
+    fn connect(&self, peer_id: PeerId, priority: i32) {
+        self.0.connect(peer_id, priority)
+    }
+
+    async fn connect_addr(&self, addr: Multiaddr) -> Result<()> {
+        self.0.connect_addr(addr).await
+    }
+
+    fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]) {
+        self.0.bootstrap(nodes)
+    }
+
+    fn want(&self, cid: Cid, priority: i32) {
+        self.0.want(cid, priority)
+    }
+
+    fn cancel(&self, cid: Cid) {
+        self.0.cancel(cid)
+    }
+
+    fn send_to(&self, peer_id: PeerId, cid: Cid, data: Vec<u8>) {
+        self.0.send_to(peer_id, cid, data)
+    }
+
+    fn send(&self, cid: Cid, data: Vec<u8>) {
+        self.0.send(cid, data)
+    }
+
+    fn subscribe(&self) -> Self::Subscription {
+        self.0.subscribe()
+    }
+
+    fn try_subscribe(&self) -> Result<Self::Subscription> {
+        self.0.try_subscribe()
+    }
+}
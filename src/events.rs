@@ -0,0 +1,111 @@
+//! Bounded buffers of the events reported by `Ipfs::events`.
+use crate::IpfsEvent;
+use futures::stream::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Default)]
+struct Buffer {
+    events: VecDeque<IpfsEvent>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl Buffer {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Inner {
+    max_buffered: usize,
+    buffers: Mutex<Vec<Arc<Mutex<Buffer>>>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for buffer in self.buffers.get_mut().unwrap().drain(..) {
+            let mut buffer = buffer.lock().unwrap();
+            buffer.closed = true;
+            buffer.wake();
+        }
+    }
+}
+
+/// Subscribers of the events. Each subscriber buffers up to `max_buffered`
+/// events, the oldest events are dropped when it falls behind.
+#[derive(Clone)]
+pub(crate) struct Subscribers(Arc<Inner>);
+
+impl Subscribers {
+    pub fn new(max_buffered: usize) -> Self {
+        Self(Arc::new(Inner {
+            max_buffered: max_buffered.max(1),
+            buffers: Default::default(),
+        }))
+    }
+
+    pub fn subscribe(&self) -> Events {
+        let buffer = Arc::new(Mutex::new(Buffer::default()));
+        self.0.buffers.lock().unwrap().push(buffer.clone());
+        Events(buffer)
+    }
+
+    pub fn emit(&self, event: IpfsEvent) {
+        let max_buffered = self.0.max_buffered;
+        self.0.buffers.lock().unwrap().retain(|buffer| {
+            let mut buffer = buffer.lock().unwrap();
+            if buffer.closed {
+                return false;
+            }
+            if buffer.events.len() >= max_buffered {
+                buffer.events.pop_front();
+            }
+            buffer.events.push_back(event.clone());
+            buffer.wake();
+            true
+        });
+    }
+
+    /// Number of events buffered by all subscribers.
+    pub fn buffered(&self) -> usize {
+        self.0
+            .buffers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|buffer| buffer.lock().unwrap().events.len())
+            .sum()
+    }
+}
+
+/// Stream of the events of a subscriber.
+pub(crate) struct Events(Arc<Mutex<Buffer>>);
+
+impl Stream for Events {
+    type Item = IpfsEvent;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut buffer = self.0.lock().unwrap();
+        if let Some(event) = buffer.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if buffer.closed {
+            return Poll::Ready(None);
+        }
+        buffer.waker = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Events {
+    fn drop(&mut self) {
+        let mut buffer = self.0.lock().unwrap();
+        buffer.closed = true;
+        buffer.events.clear();
+    }
+}
@@ -0,0 +1,59 @@
+//! Incremental hashing of data that is read in chunks.
+use libipld::multihash::{
+    Blake2b256, Blake2b512, Sha2_256, Sha2_512, Sha3_256, Sha3_512, StatefulHasher, BLAKE2B_256,
+    BLAKE2B_512, SHA2_256, SHA2_512, SHA3_256, SHA3_512,
+};
+
+/// Hasher that is updated with each chunk of the data, for the hash functions
+/// that support it.
+pub(crate) enum StreamHasher {
+    Sha2_256(Sha2_256),
+    Sha2_512(Sha2_512),
+    Sha3_256(Sha3_256),
+    Sha3_512(Sha3_512),
+    Blake2b256(Blake2b256),
+    Blake2b512(Blake2b512),
+}
+
+impl StreamHasher {
+    /// Returns a hasher for the multihash `code`, or `None` if the data has to
+    /// be hashed in one pass.
+    pub fn new(code: u64) -> Option<Self> {
+        Some(match code {
+            SHA2_256 => Self::Sha2_256(Default::default()),
+            SHA2_512 => Self::Sha2_512(Default::default()),
+            SHA3_256 => Self::Sha3_256(Default::default()),
+            SHA3_512 => Self::Sha3_512(Default::default()),
+            BLAKE2B_256 => Self::Blake2b256(Default::default()),
+            BLAKE2B_512 => Self::Blake2b512(Default::default()),
+            _ => return None,
+        })
+    }
+
+    pub fn update(&mut self, input: &[u8]) {
+        match self {
+            Self::Sha2_256(hasher) => hasher.update(input),
+            Self::Sha2_512(hasher) => hasher.update(input),
+            Self::Sha3_256(hasher) => hasher.update(input),
+            Self::Sha3_512(hasher) => hasher.update(input),
+            Self::Blake2b256(hasher) => hasher.update(input),
+            Self::Blake2b512(hasher) => hasher.update(input),
+        }
+    }
+
+    /// Returns the digest of the data.
+    pub fn finalize(&self) -> Vec<u8> {
+        match self {
+            Self::Sha2_256(hasher) => digest(hasher),
+            Self::Sha2_512(hasher) => digest(hasher),
+            Self::Sha3_256(hasher) => digest(hasher),
+            Self::Sha3_512(hasher) => digest(hasher),
+            Self::Blake2b256(hasher) => digest(hasher),
+            Self::Blake2b512(hasher) => digest(hasher),
+        }
+    }
+}
+
+fn digest<H: StatefulHasher>(hasher: &H) -> Vec<u8> {
+    hasher.finalize().as_ref().to_vec()
+}
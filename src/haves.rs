@@ -0,0 +1,81 @@
+//! Cids that peers are known to have.
+use ipfs_embed_core::{Cid, PeerId};
+use std::collections::{BTreeMap, HashMap};
+
+/// Maximum number of peers whose cids are remembered. The least recently
+/// updated peer is forgotten first.
+const MAX_PEERS: usize = 4096;
+
+/// The cids of a peer, indexed by the sequence number of when they were learned.
+#[derive(Default)]
+struct Haves {
+    // sequence number of the last update of the peer
+    updated: u64,
+    // sequence number -> cid, oldest first
+    cids: BTreeMap<u64, Cid>,
+    // cid -> sequence number
+    seqs: HashMap<Cid, u64>,
+}
+
+/// The most recently learned cids of each peer, up to `max_per_peer` per peer.
+pub(crate) struct PeerHaves {
+    max_per_peer: usize,
+    peers: HashMap<PeerId, Haves>,
+    // sequence number of the last update -> peer, least recently updated first
+    order: BTreeMap<u64, PeerId>,
+    seq: u64,
+}
+
+impl PeerHaves {
+    pub fn new(max_per_peer: usize) -> Self {
+        Self {
+            max_per_peer,
+            peers: Default::default(),
+            order: Default::default(),
+            seq: 0,
+        }
+    }
+
+    pub fn insert(&mut self, peer_id: &PeerId, cid: Cid) {
+        if self.max_per_peer == 0 {
+            return;
+        }
+        self.seq += 1;
+        let seq = self.seq;
+        let haves = self.peers.entry(peer_id.clone()).or_default();
+        self.order.remove(&haves.updated);
+        self.order.insert(seq, peer_id.clone());
+        haves.updated = seq;
+        if let Some(prev) = haves.seqs.insert(cid, seq) {
+            haves.cids.remove(&prev);
+        }
+        haves.cids.insert(seq, cid);
+        if haves.cids.len() > self.max_per_peer {
+            let oldest = haves.cids.keys().next().copied();
+            if let Some(cid) = oldest.and_then(|seq| haves.cids.remove(&seq)) {
+                haves.seqs.remove(&cid);
+            }
+        }
+        while self.peers.len() > MAX_PEERS {
+            let oldest = self.order.keys().next().copied();
+            if let Some(peer_id) = oldest.and_then(|seq| self.order.remove(&seq)) {
+                self.peers.remove(&peer_id);
+            }
+        }
+    }
+
+    /// Estimates the memory used by the remembered cids.
+    pub fn memory_usage(&self) -> usize {
+        let cids: usize = self.peers.values().map(|haves| haves.cids.len()).sum();
+        (self.peers.len() + self.order.len()) * std::mem::size_of::<PeerId>()
+            + cids * 2 * (std::mem::size_of::<Cid>() + std::mem::size_of::<u64>())
+    }
+
+    /// Returns the cids of `peer_id`, oldest first.
+    pub fn get(&self, peer_id: &PeerId) -> Vec<Cid> {
+        self.peers
+            .get(peer_id)
+            .map(|haves| haves.cids.values().copied().collect())
+            .unwrap_or_default()
+    }
+}
@@ -22,24 +22,49 @@ use async_std::stream::{interval, Interval};
 use async_std::task;
 use async_trait::async_trait;
 use futures::channel::{mpsc, oneshot};
-use futures::future::Future;
+use futures::future::{join_all, Future};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use futures::sink::SinkExt;
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
 use ipfs_embed_core::{
-    Block, Cid, Multiaddr, Network, NetworkEvent, PeerId, Result, Storage, StorageEvent,
-    StoreParams,
+    Block, Cid, Keypair, Multiaddr, MultihashDigest, Network, NetworkEvent, PeerId, PinInfo,
+    Result, Storage, StorageEvent, StoreParams, TooManyLinks, Transient,
 };
 use libipld::codec::Decode;
 use libipld::error::BlockNotFound;
 use libipld::ipld::Ipld;
 use libipld::store::Store;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
 use std::time::Duration;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub mod blocking;
+mod cache;
+mod car;
+mod config;
+mod debug;
+#[cfg(feature = "dnslink")]
+pub mod dnslink;
+mod dynamic;
+mod events;
+mod hasher;
+mod haves;
+mod parse;
+mod selector;
+
+pub use cache::CacheStats;
+pub use config::{Config, ReadErrorPolicy, RetryPolicy, UnsolicitedPolicy, VerifyPolicy};
+pub use dynamic::{DynIpfs, DynNetwork, DynStorage};
+pub use parse::{parse_cid, CidParseError};
+pub use selector::{PathNotFound, Selector, UnsupportedCodec};
 
 pub use ipfs_embed_core as core;
 #[cfg(feature = "db")]
@@ -47,11 +72,162 @@ pub use ipfs_embed_db as db;
 #[cfg(feature = "net")]
 pub use ipfs_embed_net as net;
 
+/// Block activity reported by `Ipfs::events`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IpfsEvent {
+    /// A block was written to the store.
+    Inserted(Cid),
+    /// A block started being fetched from the network.
+    WantStarted(Cid),
+    /// A fetched block was received from the peer.
+    WantFulfilled(Cid, PeerId),
+    /// Fetching a block was given up after the timeout.
+    WantTimedOut(Cid),
+}
+
+/// Response of a want handler to a want received from a peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WantResponse {
+    /// Serve the block if it is in the store.
+    Serve,
+    /// Ignore the want.
+    Deny,
+    /// Ask the handler again later, until the want times out.
+    Defer,
+}
+
+/// Blocks and bytes transferred by a single operation.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TransferStats {
+    /// Number of blocks returned.
+    pub blocks: usize,
+    /// Number of bytes received from the network.
+    pub bytes: u64,
+    /// Number of blocks that were already stored.
+    pub from_cache: usize,
+    /// Number of the blocks that were already stored read from the hot cache.
+    pub hot_hits: usize,
+    /// Number of the blocks that were already stored read from disk.
+    pub cold_hits: usize,
+    /// Number of blocks fetched from the network.
+    pub from_network: usize,
+}
+
+/// Estimated number of bytes used by the in-memory structures of the node.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryReport {
+    /// Blocks being fetched and queued wants.
+    pub wanted: usize,
+    /// Events buffered for the subscriptions of `events`.
+    pub event_buffers: usize,
+    /// Cids remembered per peer for `peer_haves`.
+    pub peer_haves: usize,
+}
+
+impl MemoryReport {
+    /// Sum of the estimates.
+    pub fn total(&self) -> usize {
+        self.wanted + self.event_buffers + self.peer_haves
+    }
+}
+
+/// The ipfs task didn't accept a request within the `busy_timeout`.
+#[derive(Debug, Error)]
+#[error("Ipfs task is busy.")]
+pub struct Busy;
+
+/// The wanted blocks exceed the `max_wanted_memory`.
+#[derive(Debug, Error)]
+#[error("Wanted blocks exceed the memory limit of {0} bytes.")]
+pub struct MemoryLimit(pub usize);
+
+/// A block read from the store doesn't match its cid.
+#[derive(Debug, Error)]
+#[error("Block {0} is corrupt.")]
+pub struct CorruptBlock(pub Cid);
+
+/// Pin of a dag created by `pin_lease`, released when dropped.
+pub struct LeaseGuard<P: StoreParams, S: Storage<P>> {
+    _marker: PhantomData<P>,
+    storage: Arc<S>,
+    lease: u64,
+}
+
+impl<P: StoreParams, S: Storage<P>> Drop for LeaseGuard<P, S> {
+    fn drop(&mut self) {
+        let storage = self.storage.clone();
+        let lease = self.lease;
+        task::spawn(async move {
+            if let Err(err) = storage.release(lease).await {
+                log::error!("failed to release lease {} {:?}", lease, err);
+            }
+        });
+    }
+}
+
+/// Data read by `insert_reader` exceeds the maximum block size.
+#[derive(Debug, Error)]
+#[error("Block exceeds the maximum block size of {0} bytes.")]
+pub struct BlockTooLarge(pub usize);
+
+/// Blocks missing from a pinned dag.
+#[derive(Debug, Error)]
+#[error("Pin is missing {} blocks.", .0.len())]
+pub struct IncompletePin(pub Vec<Cid>);
+
+type WantHandler = Box<dyn Fn(&PeerId, &Cid) -> WantResponse + Send + Sync>;
+
 pub struct Ipfs<P, S, N> {
     _marker: PhantomData<P>,
     storage: Arc<S>,
     network: Arc<N>,
-    tx: mpsc::Sender<(Cid, oneshot::Sender<Block<P>>)>,
+    tx: mpsc::Sender<Wants<P>>,
+    shutdown: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    want_handler: Arc<RwLock<Option<WantHandler>>>,
+    retry: RetryPolicy,
+    fetch_retry: RetryPolicy,
+    read_error_policy: ReadErrorPolicy,
+    verify_on_read: VerifyPolicy,
+    remove_corrupt: bool,
+    auto_pin: bool,
+    reads: Arc<AtomicU64>,
+    hot_cache: Arc<Mutex<cache::HotCache<P>>>,
+    peer_haves: Arc<Mutex<haves::PeerHaves>>,
+    subscribers: events::Subscribers,
+    hot_hits: Arc<AtomicU64>,
+    cold_hits: Arc<AtomicU64>,
+    bootstrapped: Arc<AtomicBool>,
+    wanted_count: Arc<AtomicUsize>,
+    wanted_memory: Arc<AtomicUsize>,
+    max_wanted_memory: Option<usize>,
+    busy_timeout: Option<Duration>,
+    pipeline_concurrency: usize,
+    max_links: usize,
+    busy_count: Arc<AtomicUsize>,
+}
+
+/// Runs `f` until it succeeds, fails with an error that isn't transient or runs
+/// out of retries.
+async fn retry<T, F, Fut>(policy: &RetryPolicy, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = policy.backoff;
+    let mut retries = 0;
+    loop {
+        match f().await {
+            Err(err)
+                if retries < policy.max_retries && err.downcast_ref::<Transient>().is_some() =>
+            {
+                log::debug!("retrying after transient error {:?}", err);
+                task::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            res => return res,
+        }
+    }
 }
 
 impl<P, S, N> Clone for Ipfs<P, S, N> {
@@ -61,6 +237,28 @@ impl<P, S, N> Clone for Ipfs<P, S, N> {
             storage: self.storage.clone(),
             network: self.network.clone(),
             tx: self.tx.clone(),
+            shutdown: self.shutdown.clone(),
+            want_handler: self.want_handler.clone(),
+            retry: self.retry,
+            fetch_retry: self.fetch_retry,
+            read_error_policy: self.read_error_policy,
+            verify_on_read: self.verify_on_read,
+            remove_corrupt: self.remove_corrupt,
+            auto_pin: self.auto_pin,
+            reads: self.reads.clone(),
+            hot_cache: self.hot_cache.clone(),
+            peer_haves: self.peer_haves.clone(),
+            subscribers: self.subscribers.clone(),
+            hot_hits: self.hot_hits.clone(),
+            cold_hits: self.cold_hits.clone(),
+            bootstrapped: self.bootstrapped.clone(),
+            wanted_count: self.wanted_count.clone(),
+            wanted_memory: self.wanted_memory.clone(),
+            max_wanted_memory: self.max_wanted_memory,
+            busy_timeout: self.busy_timeout,
+            pipeline_concurrency: self.pipeline_concurrency,
+            max_links: self.max_links,
+            busy_count: self.busy_count.clone(),
         }
     }
 }
@@ -73,373 +271,4075 @@ where
     Ipld: Decode<P::Codecs>,
 {
     pub fn new(storage: Arc<S>, network: Arc<N>, timeout: Duration) -> Self {
+        Self::with_config(storage, network, Config::new(timeout))
+    }
+
+    pub fn with_config(storage: Arc<S>, network: Arc<N>, config: Config) -> Self {
+        match storage.good_providers(config.predial_providers) {
+            Ok(peers) => {
+                for peer_id in peers {
+                    log::debug!("predialing provider {}", peer_id);
+                    network.connect(peer_id, BACKGROUND);
+                }
+            }
+            Err(err) => log::error!("failed to load providers {:?}", err),
+        }
         let (tx, rx) = mpsc::channel(0);
-        task::spawn(IpfsTask::new(storage.clone(), network.clone(), rx, timeout));
+        let (shutdown, shutdown_rx) = mpsc::unbounded();
+        let want_handler = Arc::new(RwLock::new(None));
+        let bootstrapped = Arc::new(AtomicBool::new(false));
+        let wanted_count = Arc::new(AtomicUsize::new(0));
+        let peer_haves = Arc::new(Mutex::new(haves::PeerHaves::new(config.max_peer_haves)));
+        let wanted_memory = Arc::new(AtomicUsize::new(0));
+        let subscribers = events::Subscribers::new(config.max_buffered_events);
+        let hot_cache = Arc::new(Mutex::new(cache::HotCache::new(config.hot_cache_size)));
+        task::spawn(IpfsTask::new(
+            storage.clone(),
+            network.clone(),
+            rx,
+            shutdown_rx,
+            &config,
+            want_handler.clone(),
+            bootstrapped.clone(),
+            wanted_count.clone(),
+            wanted_memory.clone(),
+            peer_haves.clone(),
+            hot_cache.clone(),
+            subscribers.clone(),
+        ));
         Self {
             _marker: PhantomData,
             storage,
             network,
             tx,
+            shutdown,
+            want_handler,
+            retry: config.retry,
+            fetch_retry: config.fetch_retry,
+            read_error_policy: config.read_error_policy,
+            verify_on_read: config.verify_on_read,
+            remove_corrupt: config.remove_corrupt,
+            auto_pin: config.auto_pin,
+            reads: Default::default(),
+            hot_cache,
+            peer_haves,
+            subscribers,
+            hot_hits: Default::default(),
+            cold_hits: Default::default(),
+            bootstrapped,
+            wanted_count,
+            wanted_memory,
+            max_wanted_memory: config.max_wanted_memory,
+            busy_timeout: config.busy_timeout,
+            pipeline_concurrency: config.pipeline_concurrency,
+            max_links: config.max_links,
+            busy_count: Default::default(),
         }
     }
 
-    pub fn local_peer_id(&self) -> &PeerId {
+    /// Stops the ipfs task, canceling the wants in progress. Gets waiting for the
+    /// network fail, as do later gets of other clones that need the network.
+    /// Resolves once the task exited and released its handles to the storage and
+    /// the network.
+    pub fn shutdown(self) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+        // fails if the task already exited
+        let sent = self.shutdown.unbounded_send(tx).is_ok();
+        async move {
+            if sent {
+                rx.await.ok();
+            }
+        }
+    }
+
+    /// Sets a handler that decides how to respond to wants received from peers.
+    /// The handler runs on the ipfs task, so it must be fast and must not block.
+    pub fn set_want_handler(
+        &self,
+        f: impl Fn(&PeerId, &Cid) -> WantResponse + Send + Sync + 'static,
+    ) {
+        *self.want_handler.write().unwrap() = Some(Box::new(f));
+    }
+
+    /// The storage backend. Using it directly bypasses the coordination done by
+    /// `Ipfs`, for example blocks aren't fetched from the network.
+    pub fn storage(&self) -> &Arc<S> {
+        &self.storage
+    }
+
+    /// The network backend. Using it directly bypasses the coordination done by
+    /// `Ipfs`, for example received blocks aren't inserted into the store.
+    pub fn network(&self) -> &Arc<N> {
+        &self.network
+    }
+
+    /// Subscribes to the events of the storage backend. Fails when its
+    /// subscriber limit is reached, the subscription of the node itself counts
+    /// towards it.
+    pub fn block_events(&self) -> Result<S::Subscription> {
+        self.storage.try_subscribe()
+    }
+
+    /// Subscribes to the events of the network backend. Fails when its
+    /// subscriber limit is reached, the subscription of the node itself counts
+    /// towards it.
+    pub fn swarm_events(&self) -> Result<N::Subscription> {
+        self.network.try_subscribe()
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
         self.network.local_peer_id()
     }
 
+    /// Replaces the node identity while keeping the stored data. This is
+    /// disruptive: existing connections are closed and peers have to rediscover
+    /// the node under its new peer id. Blocks that were provided are provided
+    /// again under the new peer id.
+    pub async fn rotate_identity(&self, new_keypair: Keypair) -> Result<()> {
+        self.network.rotate_identity(new_keypair).await
+    }
+
+    /// Dials a peer at an address learned out of band. Resolves once the
+    /// connection is established and fails if the dial fails.
+    pub async fn connect_addr(&self, addr: Multiaddr) -> Result<()> {
+        self.network.connect_addr(addr).await
+    }
+
+    /// Returns true once the network reported that bootstrapping completed.
+    pub fn is_bootstrapped(&self) -> bool {
+        self.bootstrapped.load(Ordering::SeqCst)
+    }
+
+    /// Number of distinct blocks that are currently being fetched from the
+    /// network.
+    pub fn wanted_count(&self) -> usize {
+        self.wanted_count.load(Ordering::SeqCst)
+    }
+
+    /// Returns the most recently learned cids that `peer` has, oldest first. They
+    /// are learned from the provider records of the peer found in the DHT and
+    /// from the blocks the peer sent us.
+    pub fn peer_haves(&self, peer: &PeerId) -> Vec<Cid> {
+        self.peer_haves.lock().unwrap().get(peer)
+    }
+
+    /// Subscribes to the block activity of the node. Events that happen before
+    /// the subscription aren't reported.
+    /// Events are buffered until they are consumed, up to `max_buffered_events`
+    /// per subscription. A subscription that falls further behind misses the
+    /// oldest events.
+    pub fn events(&self) -> impl Stream<Item = IpfsEvent> {
+        self.subscribers.subscribe()
+    }
+
+    /// Estimates the memory used by the wanted blocks, the buffered events and
+    /// the cids remembered per peer.
+    pub fn memory_usage(&self) -> MemoryReport {
+        MemoryReport {
+            wanted: self.wanted_memory.load(Ordering::SeqCst),
+            event_buffers: self.subscribers.buffered() * std::mem::size_of::<IpfsEvent>(),
+            peer_haves: self.peer_haves.lock().unwrap().memory_usage(),
+        }
+    }
+
+    /// Number of requests that failed with `Busy`.
+    pub fn busy_count(&self) -> usize {
+        self.busy_count.load(Ordering::SeqCst)
+    }
+
+    /// Sends wants to the task, failing with `Busy` if it doesn't accept them
+    /// within the `busy_timeout`.
+    async fn send_wants(&self, wants: Wants<P>) -> Result<()> {
+        if let Some(max) = self.max_wanted_memory {
+            if self.wanted_memory.load(Ordering::SeqCst) >= max {
+                log::warn!("wanted blocks exceed the memory limit of {} bytes", max);
+                return Err(MemoryLimit(max).into());
+            }
+        }
+        let mut tx = self.tx.clone();
+        let timeout = match self.busy_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(tx.send(wants).await?),
+        };
+        match async_std::future::timeout(timeout, tx.send(wants)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                log::warn!("ipfs task busy for {:?}", timeout);
+                self.busy_count.fetch_add(1, Ordering::SeqCst);
+                Err(Busy.into())
+            }
+        }
+    }
+
     pub fn external_addresses(&self) -> Vec<Multiaddr> {
         self.network.external_addresses()
     }
 
-    pub async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
-        self.storage.pinned(cid).await
+    /// Like `get`, but callers waiting for the same block from the network share
+    /// a single copy of it. `get` and the other methods returning an owned
+    /// `Block` copy the block for every caller but the last.
+    pub async fn get_shared(&self, cid: &Cid) -> Result<Arc<Block<P>>> {
+        let (block, _) = self.fetch_shared(cid, None, INTERACTIVE).await?;
+        Ok(block)
     }
-}
 
-#[async_trait]
-impl<P, S, N> Store for Ipfs<P, S, N>
-where
-    P: StoreParams + Unpin + 'static,
-    S: Storage<P>,
-    N: Network<P>,
-    Ipld: Decode<P::Codecs>,
-{
-    type Params = P;
+    /// Returns the block and the peer that delivered it, or `None` if the block
+    /// was already stored locally.
+    pub async fn get_with_provenance(&self, cid: &Cid) -> Result<(Block<P>, Option<PeerId>)> {
+        self.fetch(cid, None, INTERACTIVE).await
+    }
 
-    async fn get(&self, cid: &Cid) -> Result<Block<P>> {
-        if let Some(data) = self.storage.get(cid)? {
-            let block = Block::new_unchecked(*cid, data);
-            return Ok(block);
+    /// Like `get`, but gives up fetching the block after `timeout` instead of the
+    /// configured network timeout.
+    pub async fn get_with_timeout(&self, cid: &Cid, timeout: Duration) -> Result<Block<P>> {
+        let (block, _) = self.fetch(cid, Some(timeout), INTERACTIVE).await?;
+        Ok(block)
+    }
+
+    async fn fetch(
+        &self,
+        cid: &Cid,
+        timeout: Option<Duration>,
+        priority: i32,
+    ) -> Result<(Block<P>, Option<PeerId>)> {
+        let (block, peer_id) = self.fetch_shared(cid, timeout, priority).await?;
+        let block = Arc::try_unwrap(block).unwrap_or_else(|block| (*block).clone());
+        Ok((block, peer_id))
+    }
+
+    async fn fetch_shared(
+        &self,
+        cid: &Cid,
+        timeout: Option<Duration>,
+        priority: i32,
+    ) -> Result<(Arc<Block<P>>, Option<PeerId>)> {
+        if let Some((block, _)) = self.read_local(cid)? {
+            return Ok((Arc::new(block), None));
         }
         let (tx, rx) = oneshot::channel();
-        self.tx.clone().send((*cid, tx)).await?;
-        if let Ok(block) = rx.await {
-            self.storage.insert(&block)?;
-            return Ok(block);
+        self.send_wants(vec![(*cid, timeout, priority, tx)]).await?;
+        if let Ok(fetched) = rx.await {
+            return Ok(fetched);
         }
         Err(BlockNotFound(*cid).into())
     }
 
-    async fn insert(&self, block: &Block<P>) -> Result<()> {
-        self.storage.insert(block)?;
-        Ok(())
+    /// Returns the blocks in the order of `cids`. The blocks that aren't stored
+    /// are fetched from the network concurrently, failing if any of them isn't
+    /// received within the network timeout.
+    pub async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Block<P>>> {
+        let (blocks, _) = self.get_many_with_stats(cids).await?;
+        Ok(blocks)
     }
 
-    async fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        loop {
-            if let Err(err) = self.storage.alias(alias.as_ref(), cid).await {
-                if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
-                    self.get(cid).await?;
+    /// Like `get_many`, also returning how many blocks and bytes were fetched
+    /// from the network.
+    pub async fn get_many_with_stats(
+        &self,
+        cids: &[Cid],
+    ) -> Result<(Vec<Block<P>>, TransferStats)> {
+        self.fetch_many(cids, INTERACTIVE).await
+    }
+
+    async fn fetch_many(
+        &self,
+        cids: &[Cid],
+        priority: i32,
+    ) -> Result<(Vec<Block<P>>, TransferStats)> {
+        let mut stats = TransferStats {
+            blocks: cids.len(),
+            ..Default::default()
+        };
+        let mut blocks = Vec::with_capacity(cids.len());
+        let mut wants = Vec::new();
+        let mut pending = Vec::new();
+        for (i, cid) in cids.iter().enumerate() {
+            let block = match self.read_local(cid)? {
+                Some((block, hot)) => {
+                    stats.from_cache += 1;
+                    if hot {
+                        stats.hot_hits += 1;
+                    } else {
+                        stats.cold_hits += 1;
+                    }
+                    Some(block)
+                }
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    wants.push((*cid, None, priority, tx));
+                    pending.push(async move { (i, rx.await) });
+                    None
                 }
+            };
+            blocks.push(block);
+        }
+        if !wants.is_empty() {
+            self.send_wants(wants).await?;
+        }
+        for (i, res) in join_all(pending).await {
+            let (block, peer_id) = res.map_err(|_| BlockNotFound(cids[i]))?;
+            let block = Arc::try_unwrap(block).unwrap_or_else(|block| (*block).clone());
+            if peer_id.is_some() {
+                stats.from_network += 1;
+                stats.bytes += block.data().len() as u64;
             } else {
-                return Ok(());
+                stats.from_cache += 1;
             }
+            blocks[i] = Some(block);
         }
+        let blocks = blocks.into_iter().map(Option::unwrap).collect();
+        Ok((blocks, stats))
     }
 
-    async fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
-        self.storage.resolve(alias.as_ref())
+    /// Fetches the blocks of a stream of cids, yielding them in the order of the
+    /// cids. At most `pipeline_concurrency` blocks are fetched at a time, and no
+    /// further cids are taken from the input while the consumer doesn't poll the
+    /// output, so that a slow consumer throttles the fetching.
+    pub fn get_pipeline<'a>(
+        &'a self,
+        cids: impl Stream<Item = Cid> + 'a,
+    ) -> impl Stream<Item = Result<Block<P>>> + 'a {
+        cids.map(move |cid| async move { self.get(&cid).await })
+            .buffered(self.pipeline_concurrency)
     }
-}
 
-struct Wanted<P: StoreParams> {
-    ch: Vec<oneshot::Sender<Block<P>>>,
-    timestamp: Instant,
-}
+    /// Inserts a block, returning `true` if it wasn't stored before.
+    pub async fn insert_is_new(&self, block: &Block<P>) -> Result<bool> {
+        let inserted = retry(&self.retry, || async { self.storage.insert(block) }).await?;
+        if self.auto_pin && inserted {
+            retry(&self.retry, || self.storage.auto_pin(block.cid())).await?;
+        }
+        Ok(inserted)
+    }
 
-impl<P: StoreParams> Default for Wanted<P> {
-    fn default() -> Self {
-        Self {
-            ch: Default::default(),
-            timestamp: Instant::now(),
+    /// Removes the pin added by `auto_pin` when the block was inserted, so that
+    /// it can be collected unless it is pinned otherwise.
+    pub async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+        self.storage.remove_auto_pin(cid).await
+    }
+
+    /// Reads a block encoded with `codec` from `reader` and inserts it, hashing
+    /// it with `hash`. Sha2, sha3 and blake2b hashes are computed incrementally
+    /// as the data is read, other hashes in one pass once it is read. Reading
+    /// stops with a `BlockTooLarge` error once the data exceeds the maximum block
+    /// size. The data is still collected into a single buffer, which the store
+    /// copies when it writes the block.
+    pub async fn insert_reader<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+        codec: u64,
+        hash: u64,
+    ) -> Result<Cid> {
+        let mut hasher = hasher::StreamHasher::new(hash);
+        let mut data = Vec::new();
+        let mut chunk = vec![0; 1 << 16];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            if data.len() + n > P::MAX_BLOCK_SIZE {
+                return Err(BlockTooLarge(P::MAX_BLOCK_SIZE).into());
+            }
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk[..n]);
+            }
+            data.extend_from_slice(&chunk[..n]);
         }
+        let mh = match hasher {
+            Some(hasher) => P::Hashes::wrap(hash, &hasher.finalize())?,
+            None => P::Hashes::new(hash, &data)?,
+        };
+        let cid = Cid::new_v1(codec, mh.to_raw()?);
+        self.insert(&Block::new_unchecked(cid, data)).await?;
+        Ok(cid)
     }
-}
 
-impl<S: StoreParams> Wanted<S> {
-    fn add_receiver(&mut self, ch: oneshot::Sender<Block<S>>) {
-        self.ch.push(ch);
+    /// Checks if a block is stored locally without reading it.
+    pub fn has(&self, cid: &Cid) -> Result<bool> {
+        self.storage.contains(cid)
     }
 
-    fn received(self, block: &Block<S>) {
-        log::info!("received block");
-        for tx in self.ch {
-            tx.send(block.clone()).ok();
+    /// Returns the block if it is stored locally, without fetching it from the
+    /// network.
+    pub fn get_local(&self, cid: &Cid) -> Result<Option<Block<P>>> {
+        let data = match self.storage.get(cid)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        if !self.should_verify() {
+            return Ok(Some(Block::new_unchecked(*cid, data)));
+        }
+        match Block::<P>::new(*cid, data) {
+            Ok(block) => Ok(Some(block)),
+            Err(err) => {
+                log::error!("block {} is corrupt: {:?}", cid.to_string(), err);
+                if self.remove_corrupt {
+                    let storage = self.storage.clone();
+                    let cid = *cid;
+                    task::spawn(async move {
+                        if let Err(err) = storage.remove(&cid).await {
+                            log::error!("failed to remove corrupt block {:?}", err);
+                        }
+                    });
+                }
+                Err(CorruptBlock(*cid).into())
+            }
         }
     }
-}
 
-struct IpfsTask<P: StoreParams, S: Storage<P>, N: Network<P>> {
-    _marker: PhantomData<P>,
-    storage: Arc<S>,
-    storage_events: S::Subscription,
-    network: Arc<N>,
-    network_events: N::Subscription,
-    rx: mpsc::Receiver<(Cid, oneshot::Sender<Block<P>>)>,
-    wanted: HashMap<Cid, Wanted<P>>,
-    interval: Interval,
-    timeout: Duration,
-    bootstrap_complete: bool,
-}
+    /// Decides if a read is verified according to the `verify_on_read` policy.
+    fn should_verify(&self) -> bool {
+        match self.verify_on_read {
+            VerifyPolicy::Never => false,
+            VerifyPolicy::Always => true,
+            VerifyPolicy::Sample(fraction) => {
+                let n = self.reads.fetch_add(1, Ordering::Relaxed) as f64;
+                ((n + 1.0) * fraction).floor() > (n * fraction).floor()
+            }
+        }
+    }
 
-impl<P, S, N> IpfsTask<P, S, N>
-where
-    P: StoreParams + Unpin + 'static,
-    S: Storage<P>,
-    N: Network<P>,
-    Ipld: Decode<P::Codecs>,
-{
-    pub fn new(
-        storage: Arc<S>,
-        network: Arc<N>,
-        rx: mpsc::Receiver<(Cid, oneshot::Sender<Block<P>>)>,
-        timeout: Duration,
-    ) -> Self {
-        let storage_events = storage.subscribe();
-        let network_events = network.subscribe();
-        Self {
-            _marker: PhantomData,
-            storage,
-            network,
-            storage_events,
-            network_events,
-            rx,
-            wanted: Default::default(),
-            timeout,
-            interval: interval(timeout),
-            bootstrap_complete: true,
+    /// Returns the number of local reads served from the hot cache and from disk.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hot_hits: self.hot_hits.load(Ordering::Relaxed),
+            cold_hits: self.cold_hits.load(Ordering::Relaxed),
         }
     }
-}
 
-impl<P, S, N> Future for IpfsTask<P, S, N>
-where
-    P: StoreParams + Unpin + 'static,
-    S: Storage<P>,
-    N: Network<P>,
-    Ipld: Decode<P::Codecs>,
-{
-    type Output = ();
+    /// Reads a block from the hot cache or the store, returning `None` if it
+    /// needs to be fetched. The flag is `true` for hot cache hits.
+    fn read_local(&self, cid: &Cid) -> Result<Option<(Block<P>, bool)>> {
+        let hot = self.hot_cache.lock().unwrap().get(cid);
+        if let Some(block) = hot {
+            self.hot_hits.fetch_add(1, Ordering::Relaxed);
+            // keep the block from becoming the first candidate for eviction
+            if let Err(err) = self.storage.touch(cid) {
+                log::debug!("failed to touch {}: {:?}", cid.to_string(), err);
+            }
+            return Ok(Some((block, true)));
+        }
+        match self.get_local(cid) {
+            Ok(Some(block)) => {
+                self.cold_hits.fetch_add(1, Ordering::Relaxed);
+                self.hot_cache.lock().unwrap().insert(block.clone());
+                Ok(Some((block, false)))
+            }
+            Ok(None) => Ok(None),
+            Err(err)
+                if self.read_error_policy == ReadErrorPolicy::FetchOnTransient
+                    && err.downcast_ref::<Transient>().is_some() =>
+            {
+                log::warn!("failed to read {}, fetching it: {:?}", cid.to_string(), err);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
 
-    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+    pub async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
+        self.storage.pinned(cid).await
+    }
+
+    /// Pins the dag rooted at `cid` without an alias, fetching missing blocks
+    /// from the network. Pins are counted, the dag stays pinned until `unpin_cid`
+    /// was called as often as `pin_cid`.
+    pub async fn pin_cid(&self, cid: &Cid) -> Result<()> {
         loop {
-            match Pin::new(&mut self.rx).poll_next(ctx) {
-                Poll::Ready(Some((cid, tx))) => {
-                    let entry = self.wanted.entry(cid).or_default();
-                    entry.add_receiver(tx);
-                    self.network.providers(&cid);
-                    self.network.want(cid, 1000);
-                }
-                Poll::Ready(None) => return Poll::Ready(()),
-                Poll::Pending => break,
+            let err = match self.storage.pin(cid).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
+                self.fetch_missing(cid).await?;
+            } else {
+                return Err(err);
             }
         }
+    }
 
+    /// Removes a pin added with `pin_cid`.
+    pub async fn unpin_cid(&self, cid: &Cid) -> Result<()> {
+        self.storage.unpin(cid).await
+    }
+
+    /// Pins the dag rooted at `cid` until the guard is dropped, fetching missing
+    /// blocks from the network. The pin is released after `ttl` even if the guard
+    /// is leaked or the process exits without dropping it.
+    pub async fn pin_lease(&self, cid: &Cid, ttl: Duration) -> Result<LeaseGuard<P, S>> {
         loop {
-            let event = match Pin::new(&mut self.network_events).poll_next(ctx) {
-                Poll::Ready(Some(event)) => event,
-                Poll::Ready(None) => return Poll::Ready(()),
-                Poll::Pending => break,
-            };
-            log::trace!("{:?}", event);
-            match event {
-                NetworkEvent::Providers(_cid, providers) => {
-                    // TODO: smarter querying
-                    if let Some(peer_id) = providers.into_iter().next() {
-                        self.network.connect(peer_id);
-                    }
-                }
-                NetworkEvent::GetProvidersFailed(cid) => {
-                    log::trace!("get providers for {} failed", cid.to_string());
-                }
-                NetworkEvent::Providing(cid) => {
-                    log::trace!("providing {}", cid.to_string());
+            let err = match self.storage.lease(cid, ttl).await {
+                Ok(lease) => {
+                    return Ok(LeaseGuard {
+                        _marker: PhantomData,
+                        storage: self.storage.clone(),
+                        lease,
+                    })
                 }
-                NetworkEvent::StartProvidingFailed(cid) => {
-                    log::trace!("providing {} failed", cid.to_string());
+                Err(err) => err,
+            };
+            if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
+                self.fetch_missing(cid).await?;
+            } else {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Returns the number of providers found for `cid` over time, oldest first.
+    /// The providers of aliased cids are looked up every `availability_interval`.
+    pub async fn availability_history(&self, cid: &Cid) -> Result<Vec<(Instant, usize)>> {
+        let now = Instant::now();
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_millis() as u64)
+            .unwrap_or_default();
+        Ok(self
+            .storage
+            .availability_history(cid)?
+            .into_iter()
+            .filter_map(|(timestamp, providers)| {
+                let age = Duration::from_millis(millis.saturating_sub(timestamp));
+                Some((now.checked_sub(age)?, providers))
+            })
+            .collect())
+    }
+
+    /// Removes an unpinned block from the store and stops providing it. Returns
+    /// `false` if the block isn't stored and fails if it is pinned.
+    pub async fn remove(&self, cid: &Cid) -> Result<bool> {
+        self.hot_cache.lock().unwrap().remove(cid);
+        self.storage.remove(cid).await
+    }
+
+    /// Fetches a block and renders it in a human readable form for debugging.
+    pub async fn debug_block(&self, cid: &Cid) -> Result<String> {
+        let block = self.get(cid).await?;
+        let ipld = block.ipld()?;
+        Ok(debug::render(&ipld))
+    }
+
+    /// Tags a stored block with an application defined tag. Tags are advisory,
+    /// they don't keep the block alive and are removed together with the block.
+    pub async fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()> {
+        self.storage.tag_block(cid, tag)
+    }
+
+    /// Returns the cids of the stored blocks with the tag.
+    pub async fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+        self.storage.blocks_by_tag(tag)
+    }
+
+    /// Points the alias to `cid`, fetching missing blocks from the network. With
+    /// `verify_complete` the dag is walked and its missing blocks are fetched
+    /// before the alias is written. If any block can't be fetched the alias is
+    /// left unchanged and an `IncompletePin` error listing the missing blocks is
+    /// returned.
+    pub async fn pin<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: &Cid,
+        verify_complete: bool,
+    ) -> Result<()> {
+        if verify_complete {
+            let mut failed = Vec::new();
+            loop {
+                let missing: Vec<Cid> = self
+                    .storage
+                    .missing_blocks(cid)?
+                    .into_iter()
+                    .filter(|cid| !failed.contains(cid))
+                    .collect();
+                if missing.is_empty() {
+                    break;
                 }
-                NetworkEvent::ReceivedBlock(_, cid, data) => {
-                    let block = Block::new_unchecked(cid, data.to_vec());
-                    if let Some(wanted) = self.wanted.remove(block.cid()) {
-                        wanted.received(&block);
+                for cid in missing {
+                    if let Err(err) = self.fetch_missing(&cid).await {
+                        if err.downcast_ref::<BlockNotFound>().is_none() {
+                            return Err(err);
+                        }
+                        failed.push(cid);
                     }
                 }
-                NetworkEvent::ReceivedWant(peer_id, cid, _) => match self.storage.get(&cid) {
-                    Ok(Some(data)) => self.network.send_to(peer_id, cid, data),
-                    Ok(None) => log::trace!("don't have local block {}", cid.to_string()),
-                    Err(err) => log::error!("failed to get local block {:?}", err),
-                },
-                NetworkEvent::BootstrapComplete => self.bootstrap_complete = true,
+            }
+            if !failed.is_empty() {
+                return Err(IncompletePin(failed).into());
             }
         }
+        self.alias(alias.as_ref(), Some(cid)).await
+    }
 
-        while self.bootstrap_complete {
-            let event = match Pin::new(&mut self.storage_events).poll_next(ctx) {
-                Poll::Ready(Some(event)) => event,
-                Poll::Ready(None) => return Poll::Ready(()),
-                Poll::Pending => break,
+    /// Pins the dag rooted at `cid` up to `depth` links deep, fetching missing
+    /// blocks from the network.
+    pub async fn pin_depth<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: &Cid,
+        depth: usize,
+    ) -> Result<()> {
+        loop {
+            let err = match self.storage.pin_depth(alias.as_ref(), cid, depth).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
             };
-            log::trace!("{:?}", event);
-            match event {
-                StorageEvent::Insert(cid) => match self.storage.get(&cid) {
-                    Ok(Some(data)) => {
-                        self.network.provide(&cid);
-                        self.network.send(cid, data);
-                    }
-                    Ok(None) => {
-                        log::warn!("block {} not in store", cid.to_string());
-                    }
-                    Err(e) => {
-                        log::warn!("error {:?} retrieving block {}", e, cid.to_string());
-                    }
-                },
-                StorageEvent::Remove(cid) => self.network.unprovide(&cid),
+            if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
+                self.fetch_missing(cid).await?;
+            } else {
+                return Err(err);
             }
         }
+    }
 
+    /// Fetches a block missing from a dag that is being pinned, retrying
+    /// according to the `fetch_retry` policy.
+    async fn fetch_missing(&self, cid: &Cid) -> Result<()> {
+        let mut backoff = self.fetch_retry.backoff;
+        let mut retries = 0;
         loop {
-            match Pin::new(&mut self.interval).poll_next(ctx) {
-                Poll::Ready(Some(())) => {}
-                Poll::Ready(None) => return Poll::Ready(()),
-                Poll::Pending => break,
+            match self.fetch(cid, None, BACKGROUND).await {
+                Err(err)
+                    if retries < self.fetch_retry.max_retries
+                        && err.downcast_ref::<BlockNotFound>().is_some() =>
+                {
+                    log::debug!("retrying fetch of {}", cid.to_string());
+                    task::sleep(backoff).await;
+                    backoff *= 2;
+                    retries += 1;
+                }
+                res => return res.map(drop),
             }
-            let timedout = Instant::now() - self.timeout;
-            let mut wanted = std::mem::replace(&mut self.wanted, HashMap::with_capacity(0));
-            wanted.retain(|cid, wanted| {
-                if wanted.timestamp > timedout {
-                    true
+        }
+    }
+
+    /// Fetches the dag rooted at `root` and pins it. If the dag can't be fetched
+    /// within `timeout` an error is returned and the alias is left unchanged.
+    pub async fn fetch_and_pin<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        root: &Cid,
+        timeout: Duration,
+    ) -> Result<()> {
+        async_std::future::timeout(timeout, self.alias(alias, Some(root))).await?
+    }
+
+    /// Points each alias to its root in a single transaction. Missing blocks are
+    /// fetched from the network when `fetch` is true, otherwise a `BlockNotFound`
+    /// error is returned and no alias is changed.
+    pub async fn pin_many(&self, pins: &[(Vec<u8>, Cid)], fetch: bool) -> Result<()> {
+        loop {
+            let err = match self.storage.pin_many(pins).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            match err.downcast_ref::<BlockNotFound>() {
+                Some(BlockNotFound(cid)) if fetch => {
+                    self.fetch_missing(cid).await?;
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Resolves several aliases at once. This is a convenience over calling
+    /// `resolve` for each alias, the aliases aren't read from one snapshot.
+    pub async fn resolve_many<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        aliases: &[T],
+    ) -> Result<Vec<Option<Cid>>> {
+        self.storage.resolve_many(aliases)
+    }
+
+    /// Streams all aliases with their root cid. Computing the total size of each
+    /// pinned dag requires reading all its blocks, so it is only done when `size`
+    /// is true.
+    pub fn iter_pins(&self, size: bool) -> impl Stream<Item = Result<PinInfo>> {
+        self.storage.iter_pins(size)
+    }
+
+    /// Streams all aliases and the cids they point to.
+    pub fn aliases(&self) -> impl Stream<Item = Result<(Vec<u8>, Cid)>> {
+        self.storage.aliases()
+    }
+
+    /// Streams the cids of all stored blocks, pinned or not. The blocks aren't
+    /// read.
+    pub fn blocks(&self) -> impl Stream<Item = Result<Cid>> {
+        self.storage.blocks()
+    }
+
+    /// Writes the blocks of the dag rooted at `root` that are matched by `selector`
+    /// to `w` as a CAR file. Missing blocks are fetched from the network.
+    pub async fn export_selective<W: AsyncWrite + Unpin>(
+        &self,
+        root: &Cid,
+        selector: Selector,
+        mut w: W,
+    ) -> Result<()> {
+        car::write_header(&mut w, root).await?;
+        let mut written = HashSet::new();
+        let mut todo = vec![(*root, selector)];
+        while let Some((cid, selector)) = todo.pop() {
+            let depth = match selector {
+                Selector::All => None,
+                Selector::Depth(depth) => Some(depth),
+                Selector::Path(path, inner) => {
+                    let block = self.get(&cid).await?;
+                    if written.insert(cid) {
+                        car::write_block(&mut w, &cid, block.data()).await?;
+                    }
+                    let mut ipld = block.ipld()?;
+                    for (i, segment) in path.iter().enumerate() {
+                        let next = selector::step(&ipld, segment)
+                            .ok_or_else(|| PathNotFound(segment.clone()))?
+                            .clone();
+                        ipld = match next {
+                            // links at the end of the path are handled by the inner selector
+                            Ipld::Link(cid) if i + 1 < path.len() => {
+                                let block = self.get(&cid).await?;
+                                if written.insert(cid) {
+                                    car::write_block(&mut w, &cid, block.data()).await?;
+                                }
+                                block.ipld()?
+                            }
+                            next => next,
+                        };
+                    }
+                    for cid in self.references(&cid, &ipld)? {
+                        todo.push((cid, (*inner).clone()));
+                    }
+                    continue;
+                }
+            };
+            let mut seen = HashSet::new();
+            let mut queue = VecDeque::new();
+            queue.push_back((cid, 0));
+            while let Some((cid, level)) = queue.pop_front() {
+                if !seen.insert(cid) {
+                    continue;
+                }
+                let block = self.get(&cid).await?;
+                if depth.map(|depth| level < depth).unwrap_or(true) {
+                    let refs = self.references(&cid, &block.ipld()?)?;
+                    queue.extend(refs.into_iter().map(|cid| (cid, level + 1)));
+                }
+                if written.insert(cid) {
+                    car::write_block(&mut w, &cid, block.data()).await?;
+                }
+            }
+        }
+        w.flush().await?;
+        Ok(())
+    }
+
+    /// Resolves a path of map keys and list indices starting at the block `root`,
+    /// following the links on the path and fetching missing blocks. Fails with
+    /// `UnsupportedCodec` before fetching a block that can't be decoded.
+    pub async fn resolve_path(&self, root: &Cid, path: &[String]) -> Result<Ipld> {
+        let mut ipld = self.get_ipld(root).await?;
+        for segment in path {
+            let next = selector::step(&ipld, segment)
+                .ok_or_else(|| PathNotFound(segment.clone()))?
+                .clone();
+            ipld = match next {
+                Ipld::Link(cid) => self.get_ipld(&cid).await?,
+                next => next,
+            };
+        }
+        Ok(ipld)
+    }
+
+    async fn get_ipld(&self, cid: &Cid) -> Result<Ipld> {
+        if P::Codecs::try_from(cid.codec()).is_err() {
+            return Err(UnsupportedCodec(cid.codec()).into());
+        }
+        Ok(self.get(cid).await?.ipld()?)
+    }
+
+    /// Returns the cids of the blocks in the dag rooted at `target` that aren't
+    /// reachable from `base`. Both dags are walked over local blocks only, a
+    /// missing block results in a `BlockNotFound` error.
+    pub async fn dag_diff(&self, base: &Cid, target: &Cid) -> Result<Vec<Cid>> {
+        let local_refs = |cid: &Cid| -> Result<HashSet<Cid>> {
+            let data = self.storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+            let block = Block::<P>::new_unchecked(*cid, data);
+            self.references(cid, &block.ipld()?)
+        };
+        let mut reachable = HashSet::new();
+        let mut todo = vec![*base];
+        while let Some(cid) = todo.pop() {
+            if reachable.insert(cid) {
+                todo.extend(local_refs(&cid)?);
+            }
+        }
+        let mut diff = Vec::new();
+        let mut seen = HashSet::new();
+        let mut todo = vec![*target];
+        while let Some(cid) = todo.pop() {
+            // shared subtrees are reachable from base and aren't walked
+            if reachable.contains(&cid) || !seen.insert(cid) {
+                continue;
+            }
+            todo.extend(local_refs(&cid)?);
+            diff.push(cid);
+        }
+        Ok(diff)
+    }
+
+    /// Imports the blocks from a directory of files named by the cid of the block
+    /// they contain, returning the number of imported blocks. Files that aren't
+    /// named by a cid or whose content doesn't match their cid are skipped.
+    pub async fn import_blockdir(&self, dir: &Path) -> Result<usize> {
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name();
+            let cid = match name.to_str().map(parse_cid) {
+                Some(Ok(cid)) => cid,
+                _ => {
+                    log::warn!("skipping {:?}, not named by a cid", name);
+                    continue;
+                }
+            };
+            let data = std::fs::read(entry.path())?;
+            let block = match Block::<P>::new(cid, data) {
+                Ok(block) => block,
+                Err(err) => {
+                    log::warn!("skipping {:?}: {:?}", name, err);
+                    continue;
+                }
+            };
+            self.insert(&block).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Fetches all blocks of the dag rooted at `root` that aren't stored locally,
+    /// returning the number of fetched blocks. The blocks are fetched level by
+    /// level, with the blocks of a level fetched concurrently.
+    pub async fn sync(&self, root: &Cid) -> Result<usize> {
+        let mut fetched = 0;
+        let mut seen = HashSet::new();
+        seen.insert(*root);
+        let mut level = vec![*root];
+        while !level.is_empty() {
+            let (blocks, stats) = self.fetch_many(&level, BACKGROUND).await?;
+            fetched += stats.from_network;
+            level.clear();
+            for block in blocks {
+                for cid in self.references(block.cid(), &block.ipld()?)? {
+                    if seen.insert(cid) {
+                        level.push(cid);
+                    }
+                }
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Checks that all blocks of the dag rooted at `root` are stored locally,
+    /// stopping at the first missing block.
+    pub async fn is_complete(&self, root: &Cid) -> Result<bool> {
+        let mut seen = HashSet::new();
+        let mut todo = vec![*root];
+        while let Some(cid) = todo.pop() {
+            if !seen.insert(cid) {
+                continue;
+            }
+            let data = match self.storage.get(&cid)? {
+                Some(data) => data,
+                None => return Ok(false),
+            };
+            let block = Block::<P>::new_unchecked(cid, data);
+            todo.extend(self.references(&cid, &block.ipld()?)?);
+        }
+        Ok(true)
+    }
+
+    /// Returns the links of the block `cid` to follow in a dag walk, failing when
+    /// it has more than `max_links` of them.
+    fn references(&self, cid: &Cid, ipld: &Ipld) -> Result<HashSet<Cid>> {
+        let refs = ipld.references();
+        if refs.len() > self.max_links {
+            return Err(TooManyLinks(*cid).into());
+        }
+        Ok(refs)
+    }
+
+    /// Returns the blocks of the dag rooted at `root` that aren't stored locally.
+    pub async fn missing_blocks(&self, root: &Cid) -> Result<Vec<Cid>> {
+        self.storage.missing_blocks(root)
+    }
+
+    /// Resolves the `_dnslink` txt record of `domain` to a cid using the system
+    /// dns configuration.
+    #[cfg(feature = "dnslink")]
+    pub async fn resolve_dnslink(&self, domain: &str) -> Result<Cid> {
+        let resolver = dnslink::SystemResolver::new().await?;
+        dnslink::resolve_dnslink(&resolver, domain).await
+    }
+}
+
+#[async_trait]
+impl<P, S, N> Store for Ipfs<P, S, N>
+where
+    P: StoreParams + Unpin + 'static,
+    S: Storage<P>,
+    N: Network<P>,
+    Ipld: Decode<P::Codecs>,
+{
+    type Params = P;
+
+    async fn get(&self, cid: &Cid) -> Result<Block<P>> {
+        let (block, _) = self.get_with_provenance(cid).await?;
+        Ok(block)
+    }
+
+    async fn insert(&self, block: &Block<P>) -> Result<()> {
+        self.insert_is_new(block).await?;
+        Ok(())
+    }
+
+    async fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
+        loop {
+            let res = retry(&self.retry, || self.storage.alias(alias.as_ref(), cid)).await;
+            let err = match res {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if let Some(BlockNotFound(cid)) = err.downcast_ref::<BlockNotFound>() {
+                self.fetch_missing(cid).await?;
+            } else {
+                return Err(err);
+            }
+        }
+    }
+
+    async fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+        self.storage.resolve(alias.as_ref())
+    }
+}
+
+/// A fetched block and the peer that sent it, or `None` if the block was stored
+/// by the time the task handled the want.
+type Fetched<P> = (Arc<Block<P>>, Option<PeerId>);
+
+/// Priority of fetches that a caller is waiting for.
+const INTERACTIVE: i32 = 1000;
+/// Priority of fetches for syncing and pinning dags, and of predials.
+const BACKGROUND: i32 = 0;
+
+/// Blocks to fetch from the network, sent to the task in one message, with
+/// optional timeouts overriding the configured one and their priority.
+type Wants<P> = Vec<(Cid, Option<Duration>, i32, oneshot::Sender<Fetched<P>>)>;
+
+struct Wanted<P: StoreParams> {
+    /// Receivers with their deadline when they override the timeout, and the
+    /// time they were added.
+    ch: Vec<(oneshot::Sender<Fetched<P>>, Option<Instant>, Instant)>,
+    /// Highest priority of the receivers. Used for the want and for dialing
+    /// the providers.
+    priority: i32,
+    timestamp: Instant,
+    /// Number of retries after timeouts.
+    retries: usize,
+    /// Providers that were dialed, so that later provider queries don't dial
+    /// them again.
+    dialed: HashSet<PeerId>,
+    /// Providers that weren't dialed yet. One is dialed for every failed dial.
+    candidates: VecDeque<PeerId>,
+}
+
+impl<P: StoreParams> Default for Wanted<P> {
+    fn default() -> Self {
+        Self {
+            ch: Default::default(),
+            priority: BACKGROUND,
+            timestamp: Instant::now(),
+            retries: 0,
+            dialed: Default::default(),
+            candidates: Default::default(),
+        }
+    }
+}
+
+impl<S: StoreParams> Wanted<S> {
+    /// Estimates the memory used by the entry in the wanted map.
+    fn memory_usage(&self) -> usize {
+        let receiver =
+            std::mem::size_of::<(oneshot::Sender<Fetched<S>>, Option<Instant>, Instant)>();
+        std::mem::size_of::<(Cid, Self)>()
+            + self.ch.len() * receiver
+            + (self.dialed.len() + self.candidates.len()) * std::mem::size_of::<PeerId>()
+    }
+
+    fn add_receiver(&mut self, ch: oneshot::Sender<Fetched<S>>, deadline: Option<Instant>) {
+        self.ch.push((ch, deadline, Instant::now()));
+    }
+
+    /// Drops the senders whose receiver was dropped, registering the task to be
+    /// woken when a receiver is dropped.
+    fn poll_canceled(&mut self, ctx: &mut Context) {
+        let ch = std::mem::take(&mut self.ch);
+        self.ch = ch
+            .into_iter()
+            .filter_map(|(mut tx, deadline, added)| {
+                if tx.poll_canceled(ctx).is_pending() {
+                    Some((tx, deadline, added))
                 } else {
-                    self.network.cancel(*cid);
-                    false
+                    None
                 }
-            });
-            let _ = std::mem::replace(&mut self.wanted, wanted);
+            })
+            .collect();
+    }
+
+    /// Drops the receivers whose deadline passed.
+    fn expire(&mut self, now: Instant) {
+        self.ch
+            .retain(|(_, deadline, _)| deadline.map(|d| d > now).unwrap_or(true));
+    }
+
+    /// Keeps only the receivers with a deadline in the future and the receivers
+    /// without one that were added less than `timeout` ago, returning `false` if
+    /// there are none. A caller joining a want late waits for its full timeout.
+    fn extend_past_timeout(&mut self, now: Instant, timeout: Duration) -> bool {
+        self.ch.retain(|(_, deadline, added)| match deadline {
+            Some(deadline) => *deadline > now,
+            None => now - *added < timeout,
+        });
+        !self.ch.is_empty()
+    }
+
+    /// All receivers share the same block, so that a large block wanted by many
+    /// callers is only held in memory once.
+    fn received(self, block: Arc<Block<S>>, peer_id: PeerId) {
+        log::info!("received block");
+        for (tx, _, _) in self.ch {
+            tx.send((block.clone(), Some(peer_id.clone()))).ok();
+        }
+    }
+}
+
+type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct IpfsTask<P: StoreParams, S: Storage<P>, N: Network<P>> {
+    _marker: PhantomData<P>,
+    storage: Arc<S>,
+    storage_events: S::Subscription,
+    network: Arc<N>,
+    network_events: N::Subscription,
+    rx: mpsc::Receiver<Wants<P>>,
+    shutdown: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+    wanted: HashMap<Cid, Wanted<P>>,
+    interval: Interval,
+    timeout: Duration,
+    /// Set once the bootstrap completed or `wait_for_bootstrap` passed.
+    bootstrap_complete: bool,
+    bootstrap_timer: Option<Delay>,
+    min_peers_to_provide: usize,
+    connected: HashSet<PeerId>,
+    /// Provides waiting for `min_peers_to_provide` peers to be connected.
+    deferred_provides: Vec<Cid>,
+    bootstrapped: Arc<AtomicBool>,
+    wanted_count: Arc<AtomicUsize>,
+    peer_haves: Arc<Mutex<haves::PeerHaves>>,
+    hot_cache: Arc<Mutex<cache::HotCache<P>>>,
+    wanted_memory: Arc<AtomicUsize>,
+    subscribers: events::Subscribers,
+    want_handler: Arc<RwLock<Option<WantHandler>>>,
+    deferred: Vec<(PeerId, Cid, Instant)>,
+    provided: HashMap<Cid, Instant>,
+    provide_dedup_window: Duration,
+    unsolicited_policy: UnsolicitedPolicy,
+    penalized: HashSet<PeerId>,
+    /// Wants received before this instant are ignored.
+    serve_after: Option<Instant>,
+    fallback: Option<(Vec<(Multiaddr, PeerId)>, Delay)>,
+    persist_in_background: bool,
+    /// Blocks that are being inserted in the background.
+    persisting: HashMap<Cid, Arc<Block<P>>>,
+    persisted_tx: mpsc::UnboundedSender<Cid>,
+    persisted_rx: mpsc::UnboundedReceiver<Cid>,
+    local_first_timeout: Option<Duration>,
+    lookups: Vec<(Cid, Delay)>,
+    deadlines: Vec<(Cid, Delay)>,
+    max_concurrent_wants: Option<usize>,
+    /// Wants waiting for a slot when `max_concurrent_wants` blocks are wanted.
+    queued_wants: VecDeque<(Cid, Option<Duration>, i32, oneshot::Sender<Fetched<P>>)>,
+    max_provider_queries: Option<usize>,
+    provider_queries: HashSet<Cid>,
+    queued_provider_queries: VecDeque<Cid>,
+    reprovide_on_start: bool,
+    want_retries: Vec<Duration>,
+    min_provide_size: usize,
+    parallel_providers: usize,
+    eager_connect: usize,
+    verify_received: bool,
+    availability_interval: Option<Interval>,
+    /// Aliased cids whose providers are looked up to record their availability.
+    probes: HashSet<Cid>,
+    probe_tx: mpsc::UnboundedSender<Cid>,
+    probe_rx: mpsc::UnboundedReceiver<Cid>,
+    // must be the last field, see `ShutdownAcks`
+    acks: ShutdownAcks,
+}
+
+/// Notifies the callers of `shutdown` when dropped. Fields are dropped in the
+/// order they are declared, so as the last field of the task the storage and
+/// the network are released by then.
+#[derive(Default)]
+struct ShutdownAcks(Vec<oneshot::Sender<()>>);
+
+impl Drop for ShutdownAcks {
+    fn drop(&mut self) {
+        for ack in self.0.drain(..) {
+            ack.send(()).ok();
+        }
+    }
+}
+
+impl<P, S, N> IpfsTask<P, S, N>
+where
+    P: StoreParams + Unpin + 'static,
+    S: Storage<P>,
+    N: Network<P>,
+    Ipld: Decode<P::Codecs>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        storage: Arc<S>,
+        network: Arc<N>,
+        rx: mpsc::Receiver<Wants<P>>,
+        shutdown: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+        config: &Config,
+        want_handler: Arc<RwLock<Option<WantHandler>>>,
+        bootstrapped: Arc<AtomicBool>,
+        wanted_count: Arc<AtomicUsize>,
+        wanted_memory: Arc<AtomicUsize>,
+        peer_haves: Arc<Mutex<haves::PeerHaves>>,
+        hot_cache: Arc<Mutex<cache::HotCache<P>>>,
+        subscribers: events::Subscribers,
+    ) -> Self {
+        let storage_events = storage.subscribe();
+        let network_events = network.subscribe();
+        let fallback = if config.fallback_boot_nodes.is_empty() {
+            None
+        } else {
+            let delay: Delay = Box::pin(task::sleep(config.fallback_timeout));
+            Some((config.fallback_boot_nodes.clone(), delay))
+        };
+        let (persisted_tx, persisted_rx) = mpsc::unbounded();
+        let (probe_tx, probe_rx) = mpsc::unbounded();
+        Self {
+            _marker: PhantomData,
+            storage,
+            network,
+            storage_events,
+            network_events,
+            rx,
+            shutdown,
+            wanted: Default::default(),
+            timeout: config.timeout,
+            interval: interval(config.timeout),
+            bootstrap_complete: config.wait_for_bootstrap.is_none(),
+            bootstrap_timer: config
+                .wait_for_bootstrap
+                .map(|timeout| Box::pin(task::sleep(timeout)) as Delay),
+            min_peers_to_provide: config.min_peers_to_provide,
+            connected: Default::default(),
+            deferred_provides: Default::default(),
+            bootstrapped,
+            wanted_count,
+            peer_haves,
+            hot_cache,
+            wanted_memory,
+            subscribers,
+            want_handler,
+            deferred: Default::default(),
+            provided: Default::default(),
+            provide_dedup_window: config.provide_dedup_window,
+            unsolicited_policy: config.unsolicited_policy,
+            penalized: Default::default(),
+            serve_after: config.serve_after.map(|warmup| Instant::now() + warmup),
+            fallback,
+            persist_in_background: config.persist_in_background,
+            persisting: Default::default(),
+            persisted_tx,
+            persisted_rx,
+            local_first_timeout: config.local_first_timeout,
+            lookups: Default::default(),
+            deadlines: Default::default(),
+            max_concurrent_wants: config.max_concurrent_wants,
+            queued_wants: Default::default(),
+            max_provider_queries: config.max_provider_queries,
+            provider_queries: Default::default(),
+            queued_provider_queries: Default::default(),
+            reprovide_on_start: config.reprovide_on_start,
+            want_retries: config.want_retries.clone(),
+            min_provide_size: config.min_provide_size,
+            parallel_providers: config.parallel_providers,
+            eager_connect: config.eager_connect,
+            verify_received: config.verify_received,
+            availability_interval: config.availability_interval.map(interval),
+            probes: Default::default(),
+            probe_tx,
+            probe_rx,
+            acks: Default::default(),
+        }
+    }
+
+    fn received_unsolicited(&mut self, peer_id: PeerId, cid: Cid, data: Vec<u8>) {
+        log::trace!("unsolicited block {} from {}", cid.to_string(), peer_id);
+        match self.unsolicited_policy {
+            UnsolicitedPolicy::Drop => {}
+            UnsolicitedPolicy::Cache => match Block::<P>::new(cid, data) {
+                Ok(block) => {
+                    if let Err(err) = self.storage.insert(&block) {
+                        log::error!("failed to cache block {:?}", err);
+                    }
+                }
+                Err(err) => log::debug!("invalid block from {}: {:?}", peer_id, err),
+            },
+            UnsolicitedPolicy::Penalize => {
+                self.penalized.insert(peer_id);
+            }
+        }
+    }
+
+    fn want_response(&self, peer_id: &PeerId, cid: &Cid) -> WantResponse {
+        match self.want_handler.read().unwrap().as_ref() {
+            Some(handler) => handler(peer_id, cid),
+            None => WantResponse::Serve,
+        }
+    }
+
+    /// Looks up the providers of `cid`, or queues the query when
+    /// `max_provider_queries` are running.
+    fn query_providers(&mut self, cid: Cid) {
+        if self.provider_queries.contains(&cid) {
+            return;
+        }
+        match self.max_provider_queries {
+            Some(max) if self.provider_queries.len() >= max => {
+                if !self.queued_provider_queries.contains(&cid) {
+                    self.queued_provider_queries.push_back(cid);
+                }
+            }
+            _ => {
+                self.provider_queries.insert(cid);
+                self.network.providers(&cid);
+            }
+        }
+    }
+
+    /// Starts the next queued query that is still wanted.
+    fn provider_query_done(&mut self, cid: &Cid) {
+        if !self.provider_queries.remove(cid) {
+            return;
+        }
+        while let Some(cid) = self.queued_provider_queries.pop_front() {
+            if self.wanted.contains_key(&cid) || self.probes.contains(&cid) {
+                self.query_providers(cid);
+                break;
+            }
+        }
+    }
+
+    /// Dials the next provider of the wants that were waiting for `peer_id`.
+    fn connect_failed(&mut self, peer_id: &PeerId) {
+        for wanted in self.wanted.values_mut() {
+            if !wanted.dialed.contains(peer_id) {
+                continue;
+            }
+            while let Some(next) = wanted.candidates.pop_front() {
+                if wanted.dialed.insert(next.clone()) {
+                    self.network.connect(next, wanted.priority);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn record_availability(&self, cid: &Cid, providers: usize) {
+        log::trace!("{} has {} providers", cid.to_string(), providers);
+        if let Err(err) = self.storage.record_availability(cid, providers) {
+            log::error!("failed to record availability {:?}", err);
+        }
+    }
+
+    /// Returns a wanted block if it was received or stored in the meantime.
+    fn available(&self, cid: &Cid) -> Option<Arc<Block<P>>> {
+        if let Some(block) = self.persisting.get(cid) {
+            return Some(block.clone());
+        }
+        match self.storage.contains(cid) {
+            Ok(true) => {}
+            _ => return None,
+        }
+        match self.storage.get(cid) {
+            Ok(Some(data)) => Some(Arc::new(Block::new_unchecked(*cid, data))),
+            _ => None,
+        }
+    }
+
+    /// Checks that a stored block isn't below the `min_provide_size`.
+    fn should_provide(&self, cid: &Cid) -> bool {
+        if self.min_provide_size == 0 {
+            return true;
+        }
+        match self.storage.get(cid) {
+            Ok(Some(data)) => data.len() >= self.min_provide_size,
+            _ => false,
+        }
+    }
+
+    fn can_provide(&self) -> bool {
+        self.bootstrap_complete && self.connected.len() >= self.min_peers_to_provide
+    }
+
+    /// Provides `cid`, or queues it while the bootstrap didn't complete or too
+    /// few peers are connected.
+    fn provide(&mut self, cid: Cid) {
+        if self.can_provide() {
+            self.network.provide(&cid);
+        } else {
+            log::trace!("deferring provide of {}", cid.to_string());
+            self.deferred_provides.push(cid);
+        }
+    }
+
+    /// Provides the queued cids once they can be provided.
+    fn flush_provides(&mut self) {
+        if !self.can_provide() || self.deferred_provides.is_empty() {
+            return;
+        }
+        log::debug!("providing {} deferred cids", self.deferred_provides.len());
+        for cid in std::mem::take(&mut self.deferred_provides) {
+            self.network.provide(&cid);
+        }
+    }
+
+    fn emit(&self, event: IpfsEvent) {
+        self.subscribers.emit(event);
+    }
+
+    /// Estimates the memory used by the wanted and queued blocks.
+    fn wanted_memory(&self) -> usize {
+        let queued =
+            std::mem::size_of::<(Cid, Option<Duration>, i32, oneshot::Sender<Fetched<P>>)>();
+        self.wanted
+            .values()
+            .map(|wanted| wanted.memory_usage())
+            .sum::<usize>()
+            + self.queued_wants.len() * queued
+    }
+
+    /// Cancels all wants, dropping the senders so that the waiting callers fail.
+    fn cancel_all(&mut self) {
+        log::info!("shutting down, canceling {} wants", self.wanted.len());
+        for cid in self.wanted.keys() {
+            self.network.cancel(*cid);
+        }
+        self.wanted.clear();
+        self.queued_wants.clear();
+        self.wanted_count.store(0, Ordering::SeqCst);
+        self.wanted_memory.store(0, Ordering::SeqCst);
+    }
+
+    fn add_want(
+        &mut self,
+        cid: Cid,
+        timeout: Option<Duration>,
+        priority: i32,
+        tx: oneshot::Sender<Fetched<P>>,
+    ) {
+        // the block may have been received since the caller checked the store
+        if let Some(block) = self.available(&cid) {
+            tx.send((block, None)).ok();
+            return;
+        }
+        if let Some(max) = self.max_concurrent_wants {
+            if !self.wanted.contains_key(&cid) && self.wanted.len() >= max {
+                log::trace!("queueing want {}", cid.to_string());
+                self.queued_wants.push_back((cid, timeout, priority, tx));
+                return;
+            }
+        }
+        if !self.wanted.contains_key(&cid) {
+            self.emit(IpfsEvent::WantStarted(cid));
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let entry = self.wanted.entry(cid).or_default();
+        entry.add_receiver(tx, deadline);
+        entry.priority = entry.priority.max(priority);
+        let priority = entry.priority;
+        if let Some(timeout) = timeout {
+            let delay: Delay = Box::pin(task::sleep(timeout));
+            self.deadlines.push((cid, delay));
+        }
+        if let Some(timeout) = self.local_first_timeout {
+            let delay: Delay = Box::pin(task::sleep(timeout));
+            self.lookups.push((cid, delay));
+        } else {
+            self.query_providers(cid);
+        }
+        self.network.want(cid, priority);
+    }
+
+    /// Starts queued wants while there are free slots, returning `true` if any
+    /// were started.
+    fn start_queued_wants(&mut self) -> bool {
+        let max = match self.max_concurrent_wants {
+            Some(max) => max,
+            None => return false,
+        };
+        let mut started = false;
+        while self.wanted.len() < max {
+            let (cid, timeout, priority, tx) = match self.queued_wants.pop_front() {
+                Some(want) => want,
+                None => break,
+            };
+            // the caller gave up while the want was queued
+            if tx.is_canceled() {
+                continue;
+            }
+            self.add_want(cid, timeout, priority, tx);
+            started = true;
+        }
+        started
+    }
+
+    /// Returns `false` if the want was deferred.
+    fn received_want(&self, peer_id: &PeerId, cid: &Cid) -> bool {
+        if self.penalized.contains(peer_id) {
+            log::trace!("ignoring want from penalized peer {}", peer_id);
+            return true;
+        }
+        if let Some(serve_after) = self.serve_after {
+            if Instant::now() < serve_after {
+                log::trace!("ignoring want from {} during warmup", peer_id);
+                return true;
+            }
+        }
+        match self.want_response(peer_id, cid) {
+            WantResponse::Serve => {}
+            WantResponse::Deny => {
+                log::trace!("denied want {} from {}", cid.to_string(), peer_id);
+                return true;
+            }
+            WantResponse::Defer => return false,
+        }
+        match self.storage.get(cid) {
+            Ok(Some(data)) => self.network.send_to(peer_id.clone(), *cid, data),
+            Ok(None) => log::trace!("don't have local block {}", cid.to_string()),
+            Err(err) => log::error!("failed to get local block {:?}", err),
+        }
+        true
+    }
+}
+
+impl<P, S, N> Future for IpfsTask<P, S, N>
+where
+    P: StoreParams + Unpin + 'static,
+    S: Storage<P>,
+    N: Network<P>,
+    Ipld: Decode<P::Codecs>,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        while let Poll::Ready(Some(ack)) = Pin::new(&mut self.shutdown).poll_next(ctx) {
+            self.acks.0.push(ack);
+        }
+        if !self.acks.0.is_empty() {
+            self.cancel_all();
+            return Poll::Ready(());
+        }
+
+        while let Poll::Ready(Some(cid)) = Pin::new(&mut self.persisted_rx).poll_next(ctx) {
+            self.persisting.remove(&cid);
+        }
+
+        loop {
+            match Pin::new(&mut self.rx).poll_next(ctx) {
+                Poll::Ready(Some(wants)) => {
+                    for (cid, timeout, priority, tx) in wants {
+                        self.add_want(cid, timeout, priority, tx);
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            let event = match Pin::new(&mut self.network_events).poll_next(ctx) {
+                Poll::Ready(Some(event)) => event,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            };
+            log::trace!("{:?}", event);
+            match event {
+                NetworkEvent::Providers(cid, providers) => {
+                    self.provider_query_done(&cid);
+                    if self.probes.remove(&cid) {
+                        self.record_availability(&cid, providers.len());
+                    }
+                    {
+                        let mut haves = self.peer_haves.lock().unwrap();
+                        for peer_id in &providers {
+                            haves.insert(peer_id, cid);
+                        }
+                    }
+                    let n = self.parallel_providers.max(1) + self.eager_connect;
+                    let mut providers: Vec<_> = providers.into_iter().collect();
+                    let priority = match self.wanted.get_mut(&cid) {
+                        Some(wanted) => {
+                            providers.retain(|peer_id| !wanted.dialed.contains(peer_id));
+                            let rest = providers.split_off(n.min(providers.len()));
+                            for peer_id in rest {
+                                if !wanted.candidates.contains(&peer_id) {
+                                    wanted.candidates.push_back(peer_id);
+                                }
+                            }
+                            wanted.dialed.extend(providers.iter().cloned());
+                            wanted.priority
+                        }
+                        None => BACKGROUND,
+                    };
+                    for peer_id in providers.into_iter().take(n) {
+                        self.network.connect(peer_id, priority);
+                    }
+                }
+                NetworkEvent::GetProvidersFailed(cid) => {
+                    log::trace!("get providers for {} failed", cid.to_string());
+                    self.provider_query_done(&cid);
+                    if self.probes.remove(&cid) {
+                        self.record_availability(&cid, 0);
+                    }
+                }
+                NetworkEvent::Providing(cid) => {
+                    log::trace!("providing {}", cid.to_string());
+                }
+                NetworkEvent::StartProvidingFailed(cid) => {
+                    log::trace!("providing {} failed", cid.to_string());
+                }
+                NetworkEvent::ReceivedBlock(peer_id, cid, data) => {
+                    if !self.wanted.contains_key(&cid) {
+                        self.received_unsolicited(peer_id, cid, data);
+                        continue;
+                    }
+                    let block = if self.verify_received {
+                        match Block::<P>::new(cid, data) {
+                            Ok(block) => block,
+                            Err(err) => {
+                                // the want stays, so that other peers can serve it
+                                log::warn!("invalid block from {}: {:?}", peer_id, err);
+                                if self.unsolicited_policy == UnsolicitedPolicy::Penalize {
+                                    self.penalized.insert(peer_id);
+                                }
+                                continue;
+                            }
+                        }
+                    } else {
+                        Block::new_unchecked(cid, data)
+                    };
+                    self.peer_haves.lock().unwrap().insert(&peer_id, cid);
+                    if let Some(wanted) = self.wanted.remove(&cid) {
+                        self.emit(IpfsEvent::WantFulfilled(cid, peer_id.clone()));
+                        // stops the providers racing the one that served the block
+                        if wanted.dialed.len() > 1 {
+                            self.network.cancel(cid);
+                        }
+                        if let Err(err) = self.storage.record_provider(&peer_id) {
+                            log::error!("failed to record provider {:?}", err);
+                        }
+                        // the received data is moved into the block without a copy.
+                        // It is copied once into the store and shared with the
+                        // waiters, which `get_shared` hands out without copying.
+                        let block = Arc::new(block);
+                        if self.persist_in_background {
+                            wanted.received(block.clone(), peer_id);
+                            self.persisting.insert(cid, block.clone());
+                            let storage = self.storage.clone();
+                            let persisted = self.persisted_tx.clone();
+                            task::spawn_blocking(move || {
+                                if let Err(err) = storage.insert(&block) {
+                                    log::error!("failed to insert block {:?}", err);
+                                }
+                                persisted.unbounded_send(cid).ok();
+                            });
+                        } else {
+                            if let Err(err) = self.storage.insert(&block) {
+                                log::error!("failed to insert block {:?}", err);
+                            }
+                            wanted.received(block, peer_id);
+                        }
+                    }
+                }
+                NetworkEvent::ReceivedWant(peer_id, cid, _) => {
+                    if !self.received_want(&peer_id, &cid) {
+                        self.deferred.push((peer_id, cid, Instant::now()));
+                    }
+                }
+                NetworkEvent::BootstrapComplete => {
+                    self.bootstrap_complete = true;
+                    self.bootstrap_timer = None;
+                    self.bootstrapped.store(true, Ordering::SeqCst);
+                    self.flush_provides();
+                }
+                NetworkEvent::Discovered(peer_id) => {
+                    log::trace!("discovered {}", peer_id);
+                    self.fallback = None;
+                }
+                NetworkEvent::ConnectFailed(peer_id) => {
+                    log::trace!("connecting to {} failed", peer_id);
+                    self.connect_failed(&peer_id);
+                }
+                NetworkEvent::PeerConnected(peer_id) => {
+                    self.connected.insert(peer_id);
+                    self.flush_provides();
+                }
+                NetworkEvent::PeerDisconnected(peer_id) => {
+                    self.connected.remove(&peer_id);
+                }
+            }
+        }
+
+        if let Some(delay) = self.bootstrap_timer.as_mut() {
+            if delay.as_mut().poll(ctx).is_ready() {
+                log::info!("bootstrap didn't complete in time, providing anyway");
+                self.bootstrap_timer = None;
+                self.bootstrap_complete = true;
+                self.flush_provides();
+            }
+        }
+
+        if let Some((_, delay)) = self.fallback.as_mut() {
+            if delay.as_mut().poll(ctx).is_ready() {
+                let (nodes, _) = self.fallback.take().unwrap();
+                log::info!("no peers discovered, bootstrapping from fallback nodes");
+                self.network.bootstrap(&nodes);
+            }
+        }
+
+        let lookups = std::mem::take(&mut self.lookups);
+        for (cid, mut delay) in lookups {
+            if delay.as_mut().poll(ctx).is_pending() {
+                self.lookups.push((cid, delay));
+            } else if self.wanted.contains_key(&cid) {
+                log::trace!("no connected peer has {}", cid.to_string());
+                self.query_providers(cid);
+            }
+        }
+
+        let deadlines = std::mem::take(&mut self.deadlines);
+        for (cid, mut delay) in deadlines {
+            if delay.as_mut().poll(ctx).is_pending() {
+                self.deadlines.push((cid, delay));
+                continue;
+            }
+            if let Some(wanted) = self.wanted.get_mut(&cid) {
+                wanted.expire(Instant::now());
+                if wanted.ch.is_empty() {
+                    log::trace!("want {} timed out", cid.to_string());
+                    self.wanted.remove(&cid);
+                    self.network.cancel(cid);
+                    self.emit(IpfsEvent::WantTimedOut(cid));
+                }
+            }
+        }
+
+        loop {
+            let event = match Pin::new(&mut self.storage_events).poll_next(ctx) {
+                Poll::Ready(Some(event)) => event,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            };
+            log::trace!("{:?}", event);
+            match event {
+                StorageEvent::Stored(cid) => {
+                    if self.reprovide_on_start && self.should_provide(&cid) {
+                        self.provided.insert(cid, Instant::now());
+                        self.provide(cid);
+                    }
+                }
+                StorageEvent::Insert(cid) => match self.storage.get(&cid) {
+                    Ok(Some(data)) => {
+                        self.emit(IpfsEvent::Inserted(cid));
+                        let now = Instant::now();
+                        let window = self.provide_dedup_window;
+                        match self.provided.get(&cid) {
+                            Some(provided) if now - *provided < window => {
+                                log::trace!("already providing {}", cid.to_string());
+                            }
+                            _ => {
+                                self.provided.insert(cid, now);
+                                if data.len() >= self.min_provide_size {
+                                    self.provide(cid);
+                                } else {
+                                    log::trace!("not providing small block {}", cid.to_string());
+                                }
+                                self.network.send(cid, data);
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        log::warn!("block {} not in store", cid.to_string());
+                    }
+                    Err(e) => {
+                        log::warn!("error {:?} retrieving block {}", e, cid.to_string());
+                    }
+                },
+                StorageEvent::Remove(cid) => {
+                    self.hot_cache.lock().unwrap().remove(&cid);
+                    self.provided.remove(&cid);
+                    self.deferred_provides.retain(|deferred| *deferred != cid);
+                    self.network.unprovide(&cid);
+                }
+                StorageEvent::Lagged(n) => {
+                    log::warn!("missed {} storage events, blocks may not be provided", n);
+                    // removals may have been missed
+                    self.hot_cache.lock().unwrap().clear();
+                }
+            }
+        }
+
+        loop {
+            match Pin::new(&mut self.interval).poll_next(ctx) {
+                Poll::Ready(Some(())) => {}
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+            let now = Instant::now();
+            let timedout = now - self.timeout;
+            let mut wanted = std::mem::replace(&mut self.wanted, HashMap::with_capacity(0));
+            let mut retry = Vec::new();
+            wanted.retain(|cid, wanted| {
+                let timeout = match wanted.retries {
+                    0 => self.timeout,
+                    n => self.want_retries[n - 1],
+                };
+                if now - wanted.timestamp < timeout {
+                    true
+                } else if wanted.retries < self.want_retries.len() {
+                    wanted.retries += 1;
+                    wanted.timestamp = now;
+                    retry.push((*cid, wanted.priority));
+                    true
+                } else if wanted.extend_past_timeout(now, self.timeout) {
+                    true
+                } else {
+                    self.network.cancel(*cid);
+                    self.emit(IpfsEvent::WantTimedOut(*cid));
+                    false
+                }
+            });
+            let _ = std::mem::replace(&mut self.wanted, wanted);
+            for (cid, priority) in retry {
+                log::debug!("retrying want {}", cid.to_string());
+                self.query_providers(cid);
+                self.network.want(cid, priority);
+            }
+
+            let mut deferred = std::mem::take(&mut self.deferred);
+            deferred.retain(|(peer_id, cid, timestamp)| {
+                *timestamp > timedout && !self.received_want(peer_id, cid)
+            });
+            self.deferred = deferred;
+
+            let window = self.provide_dedup_window;
+            self.provided
+                .retain(|_, provided| provided.elapsed() < window);
+        }
+
+        if let Some(availability) = self.availability_interval.as_mut() {
+            let mut probe = false;
+            while let Poll::Ready(Some(())) = Pin::new(&mut *availability).poll_next(ctx) {
+                probe = true;
+            }
+            if probe {
+                // reading the aliases may take a while, so it doesn't block the task
+                let mut aliases = self.storage.aliases();
+                let tx = self.probe_tx.clone();
+                task::spawn(async move {
+                    while let Some(res) = aliases.next().await {
+                        match res {
+                            Ok((_, cid)) => {
+                                tx.unbounded_send(cid).ok();
+                            }
+                            Err(err) => {
+                                log::error!("failed to read aliases {:?}", err);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        while let Poll::Ready(Some(cid)) = Pin::new(&mut self.probe_rx).poll_next(ctx) {
+            if self.probes.insert(cid) {
+                self.query_providers(cid);
+            }
+        }
+
+        // wants are canceled as soon as all callers waiting for them gave up
+        let mut canceled = Vec::new();
+        for (cid, wanted) in self.wanted.iter_mut() {
+            wanted.poll_canceled(ctx);
+            if wanted.ch.is_empty() {
+                canceled.push(*cid);
+            }
+        }
+        for cid in canceled {
+            log::trace!("want {} canceled", cid.to_string());
+            self.wanted.remove(&cid);
+            self.network.cancel(cid);
+        }
+
+        if self.start_queued_wants() {
+            // poll again to watch the started wants for cancellation
+            ctx.waker().wake_by_ref();
+        }
+
+        self.wanted_count.store(self.wanted.len(), Ordering::SeqCst);
+        self.wanted_memory
+            .store(self.wanted_memory(), Ordering::SeqCst);
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use futures::stream::TryStreamExt;
+    use ipfs_embed_core::{BoxStream, Network as _, Storage as _};
+    use ipfs_embed_db::{
+        BlockPinned, StorageConfig, StorageService, TooManySubscribers as StorageSubscribers,
+    };
+    use ipfs_embed_net::{AddressFilter, NetworkConfig, NetworkService, TooManySubscribers};
+    use libipld::block::Block;
+    use libipld::cbor::DagCborCodec;
+    use libipld::multihash::{BLAKE2S_256, SHA2_256};
+    use libipld::raw::RawCodec;
+    use libipld::store::DefaultStoreParams;
+    use libipld::{alias, ipld};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    type Storage = StorageService<DefaultStoreParams>;
+    type Network = NetworkService<DefaultStoreParams>;
+    type DefaultIpfs = Ipfs<DefaultStoreParams, Storage, Network>;
+    type MockIpfs = Ipfs<DefaultStoreParams, Storage, MockNetwork>;
+
+    fn create_store(bootstrap: Vec<(Multiaddr, PeerId)>) -> DefaultIpfs {
+        let sled_config = sled::Config::new().temporary(true);
+        let cache_size = 10;
+        let sweep_interval = Duration::from_millis(10000);
+        let network_timeout = Duration::from_secs(5);
+
+        let mut net_config = NetworkConfig::new();
+        net_config.enable_mdns = bootstrap.is_empty();
+        net_config.boot_nodes = bootstrap;
+        net_config.allow_non_globals_in_dht = true;
+
+        let storage =
+            Arc::new(StorageService::open(&sled_config, cache_size, sweep_interval).unwrap());
+        let network = Arc::new(NetworkService::new(net_config).unwrap());
+        Ipfs::new(storage, network, network_timeout)
+    }
+
+    fn create_block(bytes: &[u8]) -> Block<DefaultStoreParams> {
+        Block::encode(RawCodec, SHA2_256, bytes).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_address_filter() {
+        env_logger::try_init().ok();
+        let mut config = NetworkConfig::new_local();
+        config.enable_mdns = false;
+        let network = NetworkService::<DefaultStoreParams>::new(config.clone()).unwrap();
+        assert_eq!(network.external_addresses().len(), 1);
+        config.address_filter = AddressFilter::GlobalOnly;
+        let network = NetworkService::<DefaultStoreParams>::new(config).unwrap();
+        assert!(network.external_addresses().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_local_store() {
+        env_logger::try_init().ok();
+        let store = create_store(vec![]);
+        let block = create_block(b"test_local_store");
+        store.insert(&block).await.unwrap();
+        let block2 = store.get(block.cid()).await.unwrap();
+        assert_eq!(block.data(), block2.data());
+    }
+
+    #[async_std::test]
+    async fn test_backend_accessors() {
+        env_logger::try_init().ok();
+        let store = create_store(vec![]);
+        let block = create_block(b"test_backend_accessors");
+        store.insert(&block).await.unwrap();
+        store.storage().evict().await.unwrap();
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(false));
+        assert_eq!(store.network().local_peer_id(), store.local_peer_id());
+    }
+
+    #[async_std::test]
+    async fn test_subscriber_limit() {
+        env_logger::try_init().ok();
+        let mut config = NetworkConfig::new();
+        config.max_subscribers = Some(3);
+        let network = NetworkService::<DefaultStoreParams>::new(config).unwrap();
+        let subscriptions: Vec<_> = (0..3).map(|_| network.try_subscribe().unwrap()).collect();
+        assert_eq!(network.subscriber_count(), 3);
+        let err = network.try_subscribe().unwrap_err();
+        assert!(err.downcast_ref::<TooManySubscribers>().is_some());
+        let _subscription = network.subscribe();
+        assert_eq!(network.subscriber_count(), 4);
+        drop(subscriptions);
+        assert_eq!(network.subscriber_count(), 1);
+        network.try_subscribe().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_storage_subscriber_limit() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let mut config = StorageConfig::new(10, Duration::from_millis(10000));
+        config.max_subscribers = Some(2);
+        let storage = Arc::new(StorageService::open_with_config(&sled_config, config).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage.clone(), network, Duration::from_secs(5));
+        assert_eq!(storage.subscriber_count(), 1);
+        let events = store.block_events().unwrap();
+        assert_eq!(storage.subscriber_count(), 2);
+        let err = store.block_events().unwrap_err();
+        assert!(err.downcast_ref::<StorageSubscribers>().is_some());
+        drop(events);
+        assert_eq!(storage.subscriber_count(), 1);
+        store.block_events().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_rotate_identity_disconnects() {
+        env_logger::try_init().ok();
+        let mut config = NetworkConfig::new_local();
+        config.enable_mdns = false;
+        let a = NetworkService::<DefaultStoreParams>::new(config.clone()).unwrap();
+        let b = NetworkService::<DefaultStoreParams>::new(config).unwrap();
+        let mut events = a.subscribe();
+        let addr = a.external_addresses()[0].clone();
+        b.connect_addr(addr).await.unwrap();
+        let b_peer_id = b.local_peer_id();
+        loop {
+            match events.next().await.unwrap() {
+                NetworkEvent::PeerConnected(peer_id) if peer_id == b_peer_id => break,
+                _ => {}
+            }
+        }
+        a.rotate_identity(Keypair::generate_ed25519())
+            .await
+            .unwrap();
+        assert_eq!(a.external_addresses().len(), 1);
+        // peers of the old identity are reported as disconnected
+        loop {
+            match events.next().await.unwrap() {
+                NetworkEvent::PeerDisconnected(peer_id) if peer_id == b_peer_id => break,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_wanted_shares_block() {
+        let block = Arc::new(create_block(&[0u8; 1 << 16]));
+        let mut wanted = Wanted::<DefaultStoreParams>::default();
+        let mut rxs = Vec::new();
+        for _ in 0..100 {
+            let (tx, rx) = oneshot::channel();
+            wanted.add_receiver(tx, None);
+            rxs.push(rx);
+        }
+        wanted.received(block.clone(), PeerId::random());
+        for mut rx in rxs {
+            let (received, _) = rx.try_recv().unwrap().unwrap();
+            assert!(Arc::ptr_eq(&block, &received));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_get_shared() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(&[1u8; 1 << 16]);
+        let receiver = std::mem::size_of::<(
+            oneshot::Sender<Fetched<DefaultStoreParams>>,
+            Option<Instant>,
+            Instant,
+        )>();
+        let spawn_get = || {
+            let store = store.clone();
+            let cid = *block.cid();
+            task::spawn(async move { store.get_shared(&cid).await })
+        };
+        let mut gets = vec![spawn_get()];
+        while store.memory_usage().wanted == 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let first = store.memory_usage().wanted;
+        for _ in 1..100 {
+            gets.push(spawn_get());
+        }
+        // all callers joined the want
+        while store.memory_usage().wanted < first + 99 * receiver {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        let blocks = join_all(gets).await;
+        let first = blocks[0].as_ref().unwrap();
+        assert_eq!(first.data(), block.data());
+        for fetched in &blocks {
+            assert!(Arc::ptr_eq(first, fetched.as_ref().unwrap()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_get_with_provenance() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let block = create_block(b"test_get_with_provenance");
+        let provider = PeerId::random();
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (provider.clone(), block.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_secs(5));
+        let (fetched, peer_id) = store.get_with_provenance(block.cid()).await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+        assert_eq!(peer_id, Some(provider));
+        let (_, peer_id) = store.get_with_provenance(block.cid()).await.unwrap();
+        assert_eq!(peer_id, None);
+    }
+
+    #[async_std::test]
+    async fn test_verify_received() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let block = create_block(b"test_verify_received");
+        let other = create_block(b"test_verify_received_other");
+        let provider = PeerId::random();
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (provider, other.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_millis(200));
+        assert!(store.get(block.cid()).await.is_err());
+        assert!(store.get_local(block.cid()).unwrap().is_none());
+    }
+
+    #[async_std::test]
+    #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github actions
+    async fn test_exchange_mdns() {
+        env_logger::try_init().ok();
+        let store1 = create_store(vec![]);
+        let store2 = create_store(vec![]);
+        let block = create_block(b"test_exchange_mdns");
+        store1.insert(&block).await.unwrap();
+        let block2 = store2.get(block.cid()).await.unwrap();
+        assert_eq!(block.data(), block2.data());
+    }
+
+    #[async_std::test]
+    #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github action
+    async fn test_received_want_before_insert() {
+        env_logger::try_init().ok();
+        let store1 = create_store(vec![]);
+        let store2 = create_store(vec![]);
+        let block = create_block(b"test_received_want_before_insert");
+
+        let get_cid = *block.cid();
+        let get = task::spawn(async move { store2.get(&get_cid).await });
+
+        task::sleep(Duration::from_millis(100)).await;
+
+        store1.insert(&block).await.unwrap();
+
+        let block2 = get.await.unwrap();
+        assert_eq!(block.data(), block2.data());
+    }
+
+    #[async_std::test]
+    async fn test_exchange_kad() {
+        let logger = env_logger::Builder::from_default_env().build();
+        async_log::Logger::wrap(logger, || {
+            let task_id = async_std::task::current().id();
+            format!("{}", task_id).parse().unwrap()
+        })
+        .start(log::LevelFilter::Trace)
+        .ok();
+
+        let store = create_store(vec![]);
+        // make sure bootstrap node has started
+        task::sleep(Duration::from_millis(1000)).await;
+        let bootstrap = vec![(store.external_addresses()[0].clone(), store.local_peer_id())];
+        let store1 = create_store(bootstrap.clone());
+        let store2 = create_store(bootstrap);
+        let block = create_block(b"test_exchange_kad");
+        store1.insert(&block).await.unwrap();
+        // wait for entry to propagate
+        task::sleep(Duration::from_millis(1000)).await;
+        let block2 = store2.get(block.cid()).await.unwrap();
+        assert_eq!(block.data(), block2.data());
+    }
+
+    fn create_restricted_store(
+        node_key: Keypair,
+        allowed: &[&Keypair],
+        bootstrap: Vec<(Multiaddr, PeerId)>,
+    ) -> DefaultIpfs {
+        let sled_config = sled::Config::new().temporary(true);
+        let mut net_config = NetworkConfig::new_local();
+        net_config.enable_mdns = false;
+        net_config.node_key = node_key;
+        net_config.boot_nodes = bootstrap;
+        net_config.allowed_peer_keys = Some(allowed.iter().map(|key| key.public()).collect());
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_secs(10)).unwrap());
+        let network = Arc::new(NetworkService::new(net_config).unwrap());
+        Ipfs::new(storage, network, Duration::from_secs(2))
+    }
+
+    #[async_std::test]
+    async fn test_allowed_peer_keys() {
+        env_logger::try_init().ok();
+        let key1 = Keypair::generate_ed25519();
+        let key2 = Keypair::generate_ed25519();
+        let key3 = Keypair::generate_ed25519();
+        let store1 = create_restricted_store(key1.clone(), &[&key2], vec![]);
+        let bootstrap = vec![(
+            store1.external_addresses()[0].clone(),
+            store1.local_peer_id(),
+        )];
+        let block = create_block(b"test_allowed_peer_keys");
+        store1.insert(&block).await.unwrap();
+
+        // the key of store3 isn't allowed by store1
+        let store3 = create_restricted_store(key3, &[&key1], bootstrap.clone());
+        assert!(store3.get(block.cid()).await.is_err());
+
+        let store2 = create_restricted_store(key2, &[&key1], bootstrap);
+        let block2 = store2.get(block.cid()).await.unwrap();
+        assert_eq!(block.data(), block2.data());
+    }
+
+    #[async_std::test]
+    async fn test_provider_not_found() {
+        env_logger::try_init().ok();
+        let store1 = create_store(vec![]);
+        let block = create_block(b"test_provider_not_found");
+        if store1
+            .get(block.cid())
+            .await
+            .unwrap_err()
+            .downcast_ref::<BlockNotFound>()
+            .is_none()
+        {
+            panic!("expected block not found error");
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[async_std::test]
+    async fn test_export_selective() {
+        env_logger::try_init().ok();
+        let store = create_store(vec![]);
+        let c = create_ipld_block(&ipld!({ "c": "leaf of a" }));
+        let a = create_ipld_block(&ipld!({ "a": c.cid() }));
+        let b = create_ipld_block(&ipld!({ "b": "leaf of b" }));
+        let root = create_ipld_block(&ipld!({ "branches": { "a": a.cid(), "b": b.cid() } }));
+        for block in &[&a, &b, &c, &root] {
+            store.insert(block).await.unwrap();
+        }
+
+        let mut car = Vec::new();
+        let selector = Selector::Path(vec!["branches".into(), "a".into()], Box::new(Selector::All));
+        store
+            .export_selective(root.cid(), selector, &mut car)
+            .await
+            .unwrap();
+        assert!(contains(&car, root.data()));
+        assert!(contains(&car, a.data()));
+        assert!(contains(&car, c.data()));
+        assert!(!contains(&car, b.data()));
+
+        let mut car = Vec::new();
+        store
+            .export_selective(root.cid(), Selector::Depth(1), &mut car)
+            .await
+            .unwrap();
+        assert!(contains(&car, a.data()));
+        assert!(contains(&car, b.data()));
+        assert!(!contains(&car, c.data()));
+    }
+
+    #[async_std::test]
+    async fn test_fetch_and_pin_timeout() {
+        env_logger::try_init().ok();
+        let store = create_store(vec![]);
+        let block = create_block(b"test_fetch_and_pin_timeout");
+        let x = alias!(x);
+        let res = store
+            .fetch_and_pin(x, block.cid(), Duration::from_millis(100))
+            .await;
+        assert!(res.is_err());
+        assert_eq!(store.resolve(x).await.unwrap(), None);
+    }
+
+    /// Network that serves its blocks to anyone wanting them and records which
+    /// peers were dialed, bootstrapped from or sent blocks and which blocks were
+    /// provided.
+    struct MockNetwork {
+        peer_id: Mutex<PeerId>,
+        blocks: HashMap<Cid, (PeerId, Vec<u8>)>,
+        dialed: Mutex<Vec<PeerId>>,
+        dialed_addrs: Mutex<Vec<Multiaddr>>,
+        bootstrapped: Mutex<Vec<PeerId>>,
+        sent: Mutex<Vec<(PeerId, Cid)>>,
+        provided: Mutex<Vec<(PeerId, Cid)>>,
+        unprovided: Mutex<Vec<Cid>>,
+        queried: Mutex<Vec<Cid>>,
+        wants: Mutex<Vec<Cid>>,
+        canceled: Mutex<Vec<Cid>>,
+        subscriptions: Mutex<Vec<mpsc::UnboundedSender<NetworkEvent>>>,
+    }
+
+    impl MockNetwork {
+        fn new(blocks: HashMap<Cid, (PeerId, Vec<u8>)>) -> Self {
+            Self {
+                peer_id: Mutex::new(PeerId::random()),
+                blocks,
+                dialed: Default::default(),
+                dialed_addrs: Default::default(),
+                bootstrapped: Default::default(),
+                sent: Default::default(),
+                provided: Default::default(),
+                unprovided: Default::default(),
+                queried: Default::default(),
+                wants: Default::default(),
+                canceled: Default::default(),
+                subscriptions: Default::default(),
+            }
+        }
+
+        fn emit(&self, event: NetworkEvent) {
+            for tx in self.subscriptions.lock().unwrap().iter() {
+                tx.unbounded_send(event.clone()).ok();
+            }
+        }
+    }
+
+    #[async_trait]
+    impl<P: StoreParams> ipfs_embed_core::Network<P> for MockNetwork {
+        type Subscription = mpsc::UnboundedReceiver<NetworkEvent>;
+
+        fn local_peer_id(&self) -> PeerId {
+            self.peer_id.lock().unwrap().clone()
+        }
+
+        fn external_addresses(&self) -> Vec<Multiaddr> {
+            vec![]
+        }
+
+        async fn rotate_identity(&self, keypair: Keypair) -> Result<()> {
+            *self.peer_id.lock().unwrap() = keypair.public().into_peer_id();
+            Ok(())
+        }
+
+        fn providers(&self, cid: &Cid) {
+            self.queried.lock().unwrap().push(*cid);
+        }
+
+        fn provide(&self, cid: &Cid) {
+            let peer_id = self.peer_id.lock().unwrap().clone();
+            self.provided.lock().unwrap().push((peer_id, *cid));
+        }
+
+        fn unprovide(&self, cid: &Cid) {
+            self.unprovided.lock().unwrap().push(*cid);
+        }
+
+        fn connect(&self, peer_id: PeerId, _: i32) {
+            self.dialed.lock().unwrap().push(peer_id);
+        }
+
+        async fn connect_addr(&self, addr: Multiaddr) -> Result<()> {
+            self.dialed_addrs.lock().unwrap().push(addr);
+            Ok(())
+        }
+
+        fn bootstrap(&self, nodes: &[(Multiaddr, PeerId)]) {
+            let mut bootstrapped = self.bootstrapped.lock().unwrap();
+            bootstrapped.extend(nodes.iter().map(|(_, peer_id)| peer_id.clone()));
+        }
+
+        fn want(&self, cid: Cid, _: i32) {
+            self.wants.lock().unwrap().push(cid);
+            if let Some((peer_id, data)) = self.blocks.get(&cid) {
+                self.emit(NetworkEvent::ReceivedBlock(
+                    peer_id.clone(),
+                    cid,
+                    data.clone(),
+                ));
+            }
+        }
+
+        fn cancel(&self, cid: Cid) {
+            self.canceled.lock().unwrap().push(cid);
+        }
+
+        fn send_to(&self, peer_id: PeerId, cid: Cid, _: Vec<u8>) {
+            self.sent.lock().unwrap().push((peer_id, cid));
+        }
+
+        fn send(&self, _: Cid, _: Vec<u8>) {}
+
+        fn subscribe(&self) -> Self::Subscription {
+            let (tx, rx) = mpsc::unbounded();
+            self.subscriptions.lock().unwrap().push(tx);
+            rx
+        }
+
+        fn try_subscribe(&self) -> Result<Self::Subscription> {
+            Ok(self.subscribe())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_predial_providers() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let block = create_block(b"test_predial_providers");
+        let provider = PeerId::random();
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (provider.clone(), block.data().to_vec()));
+
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage.clone(),
+            network.clone(),
+            Duration::from_secs(5),
+        );
+        assert!(network.dialed.lock().unwrap().is_empty());
+        store.get(block.cid()).await.unwrap();
+        drop(store);
+
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let _store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network.clone(), Duration::from_secs(5));
+        assert_eq!(*network.dialed.lock().unwrap(), vec![provider]);
+    }
+
+    /// Storage whose inserts fail with a transient error a number of times.
+    struct FlakyStorage {
+        inner: Storage,
+        failures: AtomicUsize,
+        read_failures: AtomicUsize,
+        insert_delay: Duration,
+    }
+
+    #[async_trait]
+    impl ipfs_embed_core::Storage<DefaultStoreParams> for FlakyStorage {
+        type Subscription = <Storage as ipfs_embed_core::Storage<DefaultStoreParams>>::Subscription;
+
+        fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            if self.read_failures.load(Ordering::SeqCst) > 0 {
+                self.read_failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(ipfs_embed_core::Error::msg("disk busy").context(Transient));
+            }
+            self.inner.get(cid)
+        }
+
+        fn contains(&self, cid: &Cid) -> Result<bool> {
+            self.inner.contains(cid)
+        }
+
+        fn touch(&self, cid: &Cid) -> Result<()> {
+            self.inner.touch(cid)
+        }
+
+        fn insert(&self, block: &Block<DefaultStoreParams>) -> Result<bool> {
+            std::thread::sleep(self.insert_delay);
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(ipfs_embed_core::Error::msg("disk busy").context(Transient));
+            }
+            self.inner.insert(block)
+        }
+
+        async fn alias<T: AsRef<[u8]> + Send + Sync>(
+            &self,
+            alias: T,
+            cid: Option<&Cid>,
+        ) -> Result<()> {
+            self.inner.alias(alias, cid).await
+        }
+
+        async fn pin_depth<T: AsRef<[u8]> + Send + Sync>(
+            &self,
+            alias: T,
+            cid: &Cid,
+            depth: usize,
+        ) -> Result<()> {
+            self.inner.pin_depth(alias, cid, depth).await
+        }
+
+        async fn pin_many(&self, pins: &[(Vec<u8>, Cid)]) -> Result<()> {
+            self.inner.pin_many(pins).await
+        }
+
+        async fn pin(&self, cid: &Cid) -> Result<()> {
+            self.inner.pin(cid).await
+        }
+
+        async fn unpin(&self, cid: &Cid) -> Result<()> {
+            self.inner.unpin(cid).await
+        }
+
+        async fn auto_pin(&self, cid: &Cid) -> Result<()> {
+            self.inner.auto_pin(cid).await
+        }
+
+        async fn remove_auto_pin(&self, cid: &Cid) -> Result<()> {
+            self.inner.remove_auto_pin(cid).await
+        }
+
+        async fn lease(&self, cid: &Cid, ttl: Duration) -> Result<u64> {
+            self.inner.lease(cid, ttl).await
+        }
+
+        async fn release(&self, lease: u64) -> Result<()> {
+            self.inner.release(lease).await
+        }
+
+        fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+            self.inner.resolve(alias)
+        }
+
+        fn resolve_many<T: AsRef<[u8]> + Send + Sync>(
+            &self,
+            aliases: &[T],
+        ) -> Result<Vec<Option<Cid>>> {
+            self.inner.resolve_many(aliases)
+        }
+
+        async fn pinned(&self, cid: &Cid) -> Result<Option<bool>> {
+            self.inner.pinned(cid).await
+        }
+
+        async fn remove(&self, cid: &Cid) -> Result<bool> {
+            self.inner.remove(cid).await
+        }
+
+        fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+            self.inner.missing_blocks(cid)
+        }
+
+        fn tag_block(&self, cid: &Cid, tag: &str) -> Result<()> {
+            self.inner.tag_block(cid, tag)
+        }
+
+        fn blocks_by_tag(&self, tag: &str) -> Result<Vec<Cid>> {
+            self.inner.blocks_by_tag(tag)
+        }
+
+        fn iter_pins(&self, size: bool) -> BoxStream<'static, Result<PinInfo>> {
+            self.inner.iter_pins(size)
+        }
+
+        fn aliases(&self) -> BoxStream<'static, Result<(Vec<u8>, Cid)>> {
+            self.inner.aliases()
+        }
+
+        fn blocks(&self) -> BoxStream<'static, Result<Cid>> {
+            self.inner.blocks()
+        }
+
+        fn record_provider(&self, peer_id: &PeerId) -> Result<()> {
+            self.inner.record_provider(peer_id)
+        }
+
+        fn good_providers(&self, n: usize) -> Result<Vec<PeerId>> {
+            self.inner.good_providers(n)
+        }
+
+        fn record_availability(&self, cid: &Cid, providers: usize) -> Result<()> {
+            self.inner.record_availability(cid, providers)
+        }
+
+        fn availability_history(&self, cid: &Cid) -> Result<Vec<(u64, usize)>> {
+            self.inner.availability_history(cid)
+        }
+
+        fn subscribe(&self) -> Self::Subscription {
+            self.inner.subscribe()
+        }
+
+        fn try_subscribe(&self) -> Result<Self::Subscription> {
+            self.inner.try_subscribe()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_insert_retry() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = Arc::new(FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(2),
+            read_failures: AtomicUsize::new(0),
+            insert_delay: Duration::from_millis(0),
+        });
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage.clone(),
+            network.clone(),
+            Duration::from_secs(5),
+        );
+        let block = create_block(b"test_insert_retry");
+        store.insert(&block).await.unwrap();
+        assert_eq!(storage.failures.load(Ordering::SeqCst), 0);
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(false));
+
+        storage.failures.store(10, Ordering::SeqCst);
+        let block = create_block(b"test_insert_retry_fails");
+        let err = store.insert(&block).await.unwrap_err();
+        assert!(err.downcast_ref::<Transient>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_persist_in_background() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = Arc::new(FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(0),
+            read_failures: AtomicUsize::new(0),
+            insert_delay: Duration::from_millis(500),
+        });
+        let block = create_block(b"test_persist_in_background");
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (PeerId::random(), block.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.persist_in_background = true;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage.clone(), network, config);
+        let fetched = store.get(block.cid()).await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+        assert!(storage.get(block.cid()).unwrap().is_none());
+        while storage.get(block.cid()).unwrap().is_none() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_busy_timeout() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = Arc::new(FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(0),
+            read_failures: AtomicUsize::new(0),
+            insert_delay: Duration::from_millis(1000),
+        });
+        let slow = create_block(b"test_busy_timeout_slow");
+        let other = create_block(b"test_busy_timeout_other");
+        let mut blocks = HashMap::new();
+        blocks.insert(*slow.cid(), (PeerId::random(), slow.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.busy_timeout = Some(Duration::from_millis(100));
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network, config);
+        // the task blocks on inserting the fetched block
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *slow.cid();
+            async move { store.get(&cid).await }
+        });
+        task::sleep(Duration::from_millis(200)).await;
+        let start = Instant::now();
+        let err = store.get(other.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<Busy>().is_some());
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(store.busy_count(), 1);
+        assert_eq!(get.await.unwrap().data(), slow.data());
+    }
+
+    #[async_std::test]
+    async fn test_dyn_ipfs() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let store1: DynIpfs<DefaultStoreParams> = Ipfs::new(
+            Arc::new(DynStorage::new(storage)),
+            Arc::new(DynNetwork::new(MockNetwork::new(HashMap::new()))),
+            Duration::from_secs(5),
+        );
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(1),
+            read_failures: AtomicUsize::new(0),
+            insert_delay: Duration::from_millis(0),
+        };
+        let mut config = Config::new(Duration::from_secs(1));
+        config.unsolicited_policy = UnsolicitedPolicy::Cache;
+        let store2: DynIpfs<DefaultStoreParams> = Ipfs::with_config(
+            Arc::new(DynStorage::new(storage)),
+            Arc::new(DynNetwork::new(MockNetwork::new(HashMap::new()))),
+            config,
+        );
+        let stores = vec![store1, store2];
+        let block = create_block(b"test_dyn_ipfs");
+        for store in &stores {
+            store.insert(&block).await.unwrap();
+            store.alias(b"block", Some(block.cid())).await.unwrap();
+            assert_eq!(store.get(block.cid()).await.unwrap().data(), block.data());
+            assert_eq!(store.resolve(b"block").await.unwrap(), Some(*block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_read_error_policy() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = Arc::new(FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(0),
+            read_failures: AtomicUsize::new(1),
+            insert_delay: Duration::from_millis(0),
+        });
+        let block = create_block(b"test_read_error_policy");
+        let provider = PeerId::random();
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (provider.clone(), block.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let config = Config::new(Duration::from_secs(5));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::with_config(storage.clone(), network.clone(), config);
+        let err = store.get(block.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<Transient>().is_some());
+
+        storage.read_failures.store(1, Ordering::SeqCst);
+        let mut config = Config::new(Duration::from_secs(5));
+        config.read_error_policy = ReadErrorPolicy::FetchOnTransient;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network, config);
+        let (fetched, peer_id) = store.get_with_provenance(block.cid()).await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+        assert_eq!(peer_id, Some(provider));
+    }
+
+    #[async_std::test]
+    async fn test_max_provider_queries() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.max_provider_queries = Some(2);
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let blocks: Vec<_> = (0..5u8).map(|i| create_block(&[i])).collect();
+        for block in &blocks {
+            let store = store.clone();
+            let cid = *block.cid();
+            task::spawn(async move { store.get(&cid).await });
+        }
+        while store.wanted_count() < 5 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(network.queried.lock().unwrap().len(), 2);
+
+        let cid = network.queried.lock().unwrap()[0];
+        network.emit(NetworkEvent::GetProvidersFailed(cid));
+        while network.queried.lock().unwrap().len() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(network.queried.lock().unwrap().len(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_eager_connect() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.parallel_providers = 1;
+        config.eager_connect = 2;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let block = create_block(b"test_eager_connect");
+        task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        while network.queried.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let providers: HashSet<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        network.emit(NetworkEvent::Providers(*block.cid(), providers.clone()));
+        while network.dialed.lock().unwrap().len() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let dialed = network.dialed.lock().unwrap();
+        assert_eq!(dialed.len(), 3);
+        assert!(dialed.iter().all(|peer_id| providers.contains(peer_id)));
+    }
+
+    #[async_std::test]
+    async fn test_auto_pin() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 1, Duration::from_millis(100)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.auto_pin = true;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network, config);
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(&[i])).collect();
+        for block in &blocks {
+            store.insert(block).await.unwrap();
+        }
+        // wait for a sweep
+        task::sleep(Duration::from_millis(300)).await;
+        for block in &blocks {
+            assert!(store.has(block.cid()).unwrap());
+            assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(true));
+        }
+        // auto-pins don't show up as aliases
+        let aliases: Vec<_> = store.aliases().try_collect().await.unwrap();
+        assert!(aliases.is_empty());
+        // inserting a block again doesn't add another pin
+        store.insert(&blocks[0]).await.unwrap();
+        store.remove_auto_pin(blocks[0].cid()).await.unwrap();
+        assert_eq!(store.pinned(blocks[0].cid()).await.unwrap(), Some(false));
+        // only the block itself is pinned, so it can be inserted before its links
+        let child = create_ipld_block(&ipld!({ "child": [] }));
+        let parent = create_ipld_block(&ipld!({ "parent": child.cid() }));
+        store.insert(&parent).await.unwrap();
+        assert_eq!(store.pinned(parent.cid()).await.unwrap(), Some(true));
+        store.insert(&child).await.unwrap();
+        assert_eq!(store.pinned(child.cid()).await.unwrap(), Some(true));
+        store.remove_auto_pin(parent.cid()).await.unwrap();
+        assert_eq!(store.pinned(child.cid()).await.unwrap(), Some(true));
+    }
+
+    #[async_std::test]
+    async fn test_max_concurrent_wants() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.max_concurrent_wants = Some(2);
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(&[i])).collect();
+        let gets: Vec<_> = blocks
+            .iter()
+            .map(|block| {
+                let store = store.clone();
+                let cid = *block.cid();
+                task::spawn(async move { store.get(&cid).await })
+            })
+            .collect();
+        while network.wants.lock().unwrap().len() < 2 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(network.wants.lock().unwrap().len(), 2);
+        assert_eq!(store.wanted_count(), 2);
+
+        let first = network.wants.lock().unwrap()[0];
+        let block = blocks.iter().find(|block| *block.cid() == first).unwrap();
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            first,
+            block.data().to_vec(),
+        ));
+        while network.wants.lock().unwrap().len() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let wanted: HashSet<Cid> = network.wants.lock().unwrap().iter().copied().collect();
+        assert_eq!(wanted, blocks.iter().map(|block| *block.cid()).collect());
+        for (block, get) in blocks.iter().zip(gets) {
+            if *block.cid() == first {
+                assert_eq!(get.await.unwrap().data(), block.data());
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_peer_haves() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let a = create_block(b"test_peer_haves_a");
+        let b = create_block(b"test_peer_haves_b");
+        let peer_id = PeerId::random();
+        assert!(store.peer_haves(&peer_id).is_empty());
+        let mut providers = HashSet::new();
+        providers.insert(peer_id.clone());
+        network.emit(NetworkEvent::Providers(*a.cid(), providers));
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *b.cid();
+            async move { store.get(&cid).await }
+        });
+        while network.wants.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        network.emit(NetworkEvent::ReceivedBlock(
+            peer_id.clone(),
+            *b.cid(),
+            b.data().to_vec(),
+        ));
+        get.await.unwrap();
+        assert_eq!(store.peer_haves(&peer_id), vec![*a.cid(), *b.cid()]);
+        assert!(store.peer_haves(&PeerId::random()).is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_events() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let mut events = store.events();
+        let a = create_block(b"test_events_a");
+        let b = create_block(b"test_events_b");
+        store.insert(&a).await.unwrap();
+        assert_eq!(events.next().await, Some(IpfsEvent::Inserted(*a.cid())));
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *b.cid();
+            async move { store.get(&cid).await }
+        });
+        assert_eq!(events.next().await, Some(IpfsEvent::WantStarted(*b.cid())));
+        let peer_id = PeerId::random();
+        network.emit(NetworkEvent::ReceivedBlock(
+            peer_id.clone(),
+            *b.cid(),
+            b.data().to_vec(),
+        ));
+        get.await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(IpfsEvent::WantFulfilled(*b.cid(), peer_id))
+        );
+        assert_eq!(events.next().await, Some(IpfsEvent::Inserted(*b.cid())));
+    }
+
+    #[async_std::test]
+    async fn test_memory_limits() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.max_wanted_memory = Some(1);
+        config.max_buffered_events = 2;
+        config.wait_for_bootstrap = None;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        assert_eq!(store.memory_usage(), MemoryReport::default());
+
+        let mut events = store.events();
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(&[i])).collect();
+        for block in &blocks {
+            store.insert(block).await.unwrap();
+        }
+        while network.provided.lock().unwrap().len() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            store.memory_usage().event_buffers,
+            2 * std::mem::size_of::<IpfsEvent>()
+        );
+        assert_eq!(
+            events.next().await,
+            Some(IpfsEvent::Inserted(*blocks[1].cid()))
+        );
+        assert_eq!(
+            events.next().await,
+            Some(IpfsEvent::Inserted(*blocks[2].cid()))
+        );
+        drop(events);
+
+        let a = create_block(b"test_memory_limits_a");
+        let b = create_block(b"test_memory_limits_b");
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *a.cid();
+            async move { store.get(&cid).await }
+        });
+        while store.memory_usage().wanted == 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let err = store.get(b.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<MemoryLimit>().is_some());
+
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *a.cid(),
+            a.data().to_vec(),
+        ));
+        get.await.unwrap();
+        while store.memory_usage().wanted > 0 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(store.memory_usage().total() > 0);
+    }
+
+    #[async_std::test]
+    async fn test_parallel_providers() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_parallel_providers");
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        while network.queried.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let providers: Vec<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        network.emit(NetworkEvent::Providers(
+            *block.cid(),
+            providers[..2].iter().cloned().collect(),
+        ));
+        while network.dialed.lock().unwrap().len() < 2 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        // providers that were already dialed are skipped
+        network.emit(NetworkEvent::Providers(
+            *block.cid(),
+            providers.iter().cloned().collect(),
+        ));
+        while network.dialed.lock().unwrap().len() < 5 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let dialed: HashSet<PeerId> = network.dialed.lock().unwrap().iter().cloned().collect();
+        assert_eq!(network.dialed.lock().unwrap().len(), 5);
+        assert_eq!(dialed, providers.iter().cloned().collect());
+
+        network.emit(NetworkEvent::ReceivedBlock(
+            providers[0].clone(),
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        assert_eq!(get.await.unwrap().data(), block.data());
+        assert_eq!(*network.canceled.lock().unwrap(), vec![*block.cid()]);
+    }
+
+    #[async_std::test]
+    async fn test_connect_failed() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_connect_failed");
+        task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        while network.queried.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let providers: HashSet<PeerId> = (0..5).map(|_| PeerId::random()).collect();
+        network.emit(NetworkEvent::Providers(*block.cid(), providers.clone()));
+        while network.dialed.lock().unwrap().len() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        // a failed dial of a peer that isn't a provider doesn't advance
+        network.emit(NetworkEvent::ConnectFailed(PeerId::random()));
+        let failed = network.dialed.lock().unwrap()[0].clone();
+        network.emit(NetworkEvent::ConnectFailed(failed));
+        while network.dialed.lock().unwrap().len() < 4 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let dialed = network.dialed.lock().unwrap();
+        assert_eq!(dialed.len(), 4);
+        let unique: HashSet<_> = dialed.iter().cloned().collect();
+        assert_eq!(unique.len(), 4);
+        assert!(unique.is_subset(&providers));
+    }
+
+    #[async_std::test]
+    async fn test_availability_history() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.availability_interval = Some(Duration::from_millis(50));
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let block = create_block(b"test_availability_history");
+        store.insert(&block).await.unwrap();
+        store.alias(b"block", Some(block.cid())).await.unwrap();
+        for providers in &[2, 1] {
+            while !network.queried.lock().unwrap().contains(block.cid()) {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            network.queried.lock().unwrap().clear();
+            let peers = (0..*providers).map(|_| PeerId::random()).collect();
+            network.emit(NetworkEvent::Providers(*block.cid(), peers));
+        }
+        while store.availability_history(block.cid()).await.unwrap().len() < 2 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let history = store.availability_history(block.cid()).await.unwrap();
+        assert_eq!(history[0].1, 2);
+        assert_eq!(history[1].1, 1);
+        assert!(history[1].0 <= Instant::now());
+    }
+
+    #[async_std::test]
+    async fn test_reprovide_on_start() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let block = create_block(b"test_reprovide_on_start");
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage.clone(), network, Duration::from_secs(5));
+        store.insert(&block).await.unwrap();
+        store.alias(b"block", Some(block.cid())).await.unwrap();
+        drop(store);
+
+        let mut config = Config::new(Duration::from_secs(5));
+        config.reprovide_on_start = false;
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let _store =
+            Ipfs::<DefaultStoreParams, _, _>::with_config(storage.clone(), network.clone(), config);
+        network.emit(NetworkEvent::BootstrapComplete);
+        task::sleep(Duration::from_millis(100)).await;
+        assert!(network.provided.lock().unwrap().is_empty());
+
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network.clone(), Duration::from_secs(5));
+        network.emit(NetworkEvent::BootstrapComplete);
+        while network.provided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let peer_id = store.local_peer_id();
+        assert_eq!(
+            *network.provided.lock().unwrap(),
+            vec![(peer_id, *block.cid())]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_min_provide_size() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.min_provide_size = 64;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        network.emit(NetworkEvent::BootstrapComplete);
+        let small = create_block(b"small");
+        let large = create_block(&[0u8; 64]);
+        store.insert(&small).await.unwrap();
+        store.insert(&large).await.unwrap();
+        while network.provided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let peer_id = store.local_peer_id();
+        assert_eq!(
+            *network.provided.lock().unwrap(),
+            vec![(peer_id, *large.cid())]
+        );
+        assert_eq!(store.get(small.cid()).await.unwrap().data(), small.data());
+    }
+
+    #[async_std::test]
+    async fn test_want_retries() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_millis(200));
+        config.want_retries = vec![Duration::from_secs(2)];
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let block = create_block(b"test_want_retries");
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        // the block only becomes available after the first timeout
+        task::sleep(Duration::from_millis(600)).await;
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        let fetched = get.await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_local_first() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let local = create_block(b"test_local_first");
+        let remote = create_block(b"test_local_first_remote");
+        let mut blocks = HashMap::new();
+        blocks.insert(*local.cid(), (PeerId::random(), local.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let mut config = Config::new(Duration::from_secs(1));
+        config.local_first_timeout = Some(Duration::from_millis(200));
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+
+        // served by a connected peer, the dht is never queried
+        store.get(local.cid()).await.unwrap();
+        task::sleep(Duration::from_millis(300)).await;
+        assert!(network.queried.lock().unwrap().is_empty());
+
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *remote.cid();
+            async move { store.get(&cid).await }
+        });
+        task::sleep(Duration::from_millis(100)).await;
+        assert!(network.queried.lock().unwrap().is_empty());
+        task::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*network.queried.lock().unwrap(), vec![*remote.cid()]);
+        assert!(get.await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_want_handler_deny() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network.clone(), Duration::from_secs(5));
+        let block = create_block(b"test_want_handler_deny");
+        store.insert(&block).await.unwrap();
+
+        let denied = PeerId::random();
+        let allowed = PeerId::random();
+        let peer_id = denied.clone();
+        store.set_want_handler(move |p, _| {
+            if *p == peer_id {
+                WantResponse::Deny
+            } else {
+                WantResponse::Serve
+            }
+        });
+        network.emit(NetworkEvent::ReceivedWant(denied, *block.cid(), 1000));
+        network.emit(NetworkEvent::ReceivedWant(
+            allowed.clone(),
+            *block.cid(),
+            1000,
+        ));
+        while network.sent.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*network.sent.lock().unwrap(), vec![(allowed, *block.cid())]);
+    }
+
+    #[async_std::test]
+    async fn test_serve_after() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.serve_after = Some(Duration::from_millis(500));
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let block = create_block(b"test_serve_after");
+        store.insert(&block).await.unwrap();
+
+        let peer_id = PeerId::random();
+        network.emit(NetworkEvent::ReceivedWant(
+            peer_id.clone(),
+            *block.cid(),
+            1000,
+        ));
+        task::sleep(Duration::from_millis(200)).await;
+        assert!(network.sent.lock().unwrap().is_empty());
+
+        task::sleep(Duration::from_millis(400)).await;
+        network.emit(NetworkEvent::ReceivedWant(
+            peer_id.clone(),
+            *block.cid(),
+            1000,
+        ));
+        while network.sent.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*network.sent.lock().unwrap(), vec![(peer_id, *block.cid())]);
+    }
+
+    #[async_std::test]
+    async fn test_provide_dedup() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network.clone(), Duration::from_secs(5));
+        network.emit(NetworkEvent::BootstrapComplete);
+        let block = create_block(b"test_provide_dedup");
+        for _ in 0..5 {
+            store.insert(&block).await.unwrap();
+        }
+        while network.provided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        let peer_id = store.local_peer_id();
+        assert_eq!(
+            *network.provided.lock().unwrap(),
+            vec![(peer_id, *block.cid())]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_min_peers_to_provide() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.min_peers_to_provide = 2;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        network.emit(NetworkEvent::BootstrapComplete);
+        let block = create_block(b"test_min_peers_to_provide");
+        store.insert(&block).await.unwrap();
+        network.emit(NetworkEvent::PeerConnected(PeerId::random()));
+        task::sleep(Duration::from_millis(100)).await;
+        assert!(network.provided.lock().unwrap().is_empty());
+        network.emit(NetworkEvent::PeerConnected(PeerId::random()));
+        while network.provided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let peer_id = store.local_peer_id();
+        assert_eq!(
+            *network.provided.lock().unwrap(),
+            vec![(peer_id, *block.cid())]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_connect_addr() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        store.connect_addr(addr.clone()).await.unwrap();
+        assert_eq!(*network.dialed_addrs.lock().unwrap(), vec![addr]);
+    }
+
+    #[async_std::test]
+    async fn test_rotate_identity() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let old_peer_id = store.local_peer_id();
+        let keypair = Keypair::generate_ed25519();
+        let new_peer_id = keypair.public().into_peer_id();
+        store.rotate_identity(keypair).await.unwrap();
+        assert_eq!(store.local_peer_id(), new_peer_id);
+        assert_ne!(old_peer_id, new_peer_id);
+        network.emit(NetworkEvent::BootstrapComplete);
+        let block = create_block(b"test_rotate_identity");
+        store.insert(&block).await.unwrap();
+        while network.provided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *network.provided.lock().unwrap(),
+            vec![(new_peer_id, *block.cid())]
+        );
+    }
+
+    fn create_mock_store(policy: UnsolicitedPolicy) -> (Arc<MockNetwork>, MockIpfs) {
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.unsolicited_policy = policy;
+        let store = Ipfs::with_config(storage, network.clone(), config);
+        (network, store)
+    }
+
+    #[test]
+    fn test_blocking() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_block(b"test_blocking_a");
+        let b = create_block(b"test_blocking_b");
+        let mut blocks = HashMap::new();
+        blocks.insert(*b.cid(), (PeerId::random(), b.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_secs(5));
+        let store = blocking::BlockingIpfs::new(store);
+        store.insert(&a).unwrap();
+        assert_eq!(store.get(a.cid()).unwrap().data(), a.data());
+        assert_eq!(store.get(b.cid()).unwrap().data(), b.data());
+        store.alias(b"a", Some(a.cid())).unwrap();
+        assert_eq!(store.resolve(b"a").unwrap(), Some(*a.cid()));
+        assert_eq!(store.pinned(a.cid()).unwrap(), Some(true));
+        store.alias(b"a", None).unwrap();
+        assert_eq!(store.resolve(b"a").unwrap(), None);
+        assert_eq!(store.pinned(a.cid()).unwrap(), Some(false));
+    }
+
+    #[async_std::test]
+    async fn test_get_with_timeout() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage,
+            network.clone(),
+            Duration::from_millis(200),
+        );
+        let block = create_block(b"test_get_with_timeout");
+
+        let start = Instant::now();
+        let err = store
+            .get_with_timeout(block.cid(), Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get_with_timeout(&cid, Duration::from_secs(5)).await }
+        });
+        // the block only becomes available after the default timeout
+        task::sleep(Duration::from_millis(600)).await;
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        let fetched = get.await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_get_local() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_get_local");
+        assert!(store.get_local(block.cid()).unwrap().is_none());
+        assert!(network.queried.lock().unwrap().is_empty());
+        assert_eq!(store.wanted_count(), 0);
+        store.insert(&block).await.unwrap();
+        let local = store.get_local(block.cid()).unwrap().unwrap();
+        assert_eq!(local.data(), block.data());
+    }
+
+    #[async_std::test]
+    async fn test_is_complete() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let a = create_ipld_block(&ipld!({ "a": [] }));
+        let b = create_ipld_block(&ipld!({ "b": [] }));
+        let c = create_ipld_block(&ipld!({ "c": [a.cid(), b.cid()] }));
+        let d = create_ipld_block(&ipld!({ "d": [c.cid(), a.cid()] }));
+        store.insert(&d).await.unwrap();
+        store.insert(&c).await.unwrap();
+        store.insert(&a).await.unwrap();
+        assert!(!store.is_complete(d.cid()).await.unwrap());
+        assert_eq!(store.missing_blocks(d.cid()).await.unwrap(), vec![*b.cid()]);
+        store.insert(&b).await.unwrap();
+        assert!(store.is_complete(d.cid()).await.unwrap());
+        assert!(store.missing_blocks(d.cid()).await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_has() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_has");
+        assert!(!store.has(block.cid()).unwrap());
+        store.insert(&block).await.unwrap();
+        assert!(store.has(block.cid()).unwrap());
+        assert!(network.queried.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_remove() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let a = create_block(b"test_remove_a");
+        let b = create_block(b"test_remove_b");
+        store.insert(&a).await.unwrap();
+        store.insert(&b).await.unwrap();
+        store.alias(b"b", Some(b.cid())).await.unwrap();
+
+        assert!(store.remove(a.cid()).await.unwrap());
+        assert!(!store.has(a.cid()).unwrap());
+        assert!(!store.remove(a.cid()).await.unwrap());
+        while network.unprovided.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(*network.unprovided.lock().unwrap(), vec![*a.cid()]);
 
-        Poll::Pending
+        let err = store.remove(b.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<BlockPinned>().is_some());
+        assert!(store.has(b.cid()).unwrap());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use ipfs_embed_db::StorageService;
-    use ipfs_embed_net::{NetworkConfig, NetworkService};
-    use libipld::block::Block;
-    use libipld::cbor::DagCborCodec;
-    use libipld::multihash::SHA2_256;
-    use libipld::raw::RawCodec;
-    use libipld::store::DefaultStoreParams;
-    use libipld::{alias, ipld};
-    use std::time::Duration;
+    #[async_std::test]
+    async fn test_pin() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let block = create_block(b"test_pin");
+        let mut blocks = HashMap::new();
+        blocks.insert(*block.cid(), (PeerId::random(), block.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_secs(5));
+        // the block is fetched from the network
+        store.pin_cid(block.cid()).await.unwrap();
+        store.pin_cid(block.cid()).await.unwrap();
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(true));
+        store.unpin_cid(block.cid()).await.unwrap();
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(true));
+        store.unpin_cid(block.cid()).await.unwrap();
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(false));
+    }
 
-    type Storage = StorageService<DefaultStoreParams>;
-    type Network = NetworkService<DefaultStoreParams>;
-    type DefaultIpfs = Ipfs<DefaultStoreParams, Storage, Network>;
+    #[async_std::test]
+    async fn test_pin_verify_complete() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_ipld_block(&ipld!({ "a": [] }));
+        let b = create_ipld_block(&ipld!({ "b": [] }));
+        let root = create_ipld_block(&ipld!({ "root": [a.cid(), b.cid()] }));
+        // b can't be fetched
+        let mut blocks = HashMap::new();
+        for block in &[&root, &a] {
+            blocks.insert(*block.cid(), (PeerId::random(), block.data().to_vec()));
+        }
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_millis(200));
+        let x = alias!(x);
+        let err = store.pin(x, root.cid(), true).await.unwrap_err();
+        let missing = &err.downcast_ref::<IncompletePin>().unwrap().0;
+        assert_eq!(missing, &vec![*b.cid()]);
+        assert_eq!(store.resolve(x).await.unwrap(), None);
 
-    fn create_store(bootstrap: Vec<(Multiaddr, PeerId)>) -> DefaultIpfs {
+        store.insert(&b).await.unwrap();
+        store.pin(x, root.cid(), true).await.unwrap();
+        assert_eq!(store.resolve(x).await.unwrap(), Some(*root.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_pin_lease() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_pin_lease");
+        store.insert(&block).await.unwrap();
+        let guard = store
+            .pin_lease(block.cid(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(true));
+        drop(guard);
+        while store.pinned(block.cid()).await.unwrap() == Some(true) {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_insert_is_new() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_insert_is_new");
+        assert!(store.insert_is_new(&block).await.unwrap());
+        assert!(!store.insert_is_new(&block).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_import_blockdir() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let tmp = tempdir::TempDir::new("test_import_blockdir").unwrap();
+        let a = create_block(b"test_import_blockdir_a");
+        let b = create_block(b"test_import_blockdir_b");
+        let c = create_block(b"test_import_blockdir_c");
+        std::fs::write(tmp.path().join(a.cid().to_string()), a.data()).unwrap();
+        std::fs::write(tmp.path().join(b.cid().to_string()), b.data()).unwrap();
+        // content of a different block
+        std::fs::write(tmp.path().join(c.cid().to_string()), a.data()).unwrap();
+        std::fs::write(tmp.path().join("README"), b"not a block").unwrap();
+        assert_eq!(store.import_blockdir(tmp.path()).await.unwrap(), 2);
+        assert!(store.has(a.cid()).unwrap());
+        assert!(store.has(b.cid()).unwrap());
+        assert!(!store.has(c.cid()).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_insert_reader() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let data = vec![7u8; (1 << 17) + 5];
+        let block = create_block(&data);
+        let codec = block.cid().codec();
+        let hash = block.cid().hash().code();
+        let cid = store
+            .insert_reader(Cursor::new(data.clone()), codec, hash)
+            .await
+            .unwrap();
+        assert_eq!(cid, *block.cid());
+        assert_eq!(store.get_local(&cid).unwrap().unwrap().data(), block.data());
+
+        let block = Block::<DefaultStoreParams>::encode(RawCodec, BLAKE2S_256, &data[..]).unwrap();
+        let cid = store
+            .insert_reader(Cursor::new(data), codec, BLAKE2S_256)
+            .await
+            .unwrap();
+        assert_eq!(cid, *block.cid());
+
+        let data = vec![7u8; DefaultStoreParams::MAX_BLOCK_SIZE + 1];
+        let err = store
+            .insert_reader(Cursor::new(data), codec, hash)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<BlockTooLarge>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_fetch_retry() {
+        env_logger::try_init().ok();
         let sled_config = sled::Config::new().temporary(true);
-        let cache_size = 10;
-        let sweep_interval = Duration::from_millis(10000);
-        let network_timeout = Duration::from_secs(5);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_ipld_block(&ipld!({ "a": [] }));
+        let b = create_ipld_block(&ipld!({ "b": [] }));
+        let root = create_ipld_block(&ipld!({ "a": a.cid(), "b": b.cid() }));
+        let mut blocks = HashMap::new();
+        for block in &[&root, &a] {
+            blocks.insert(*block.cid(), (PeerId::random(), block.data().to_vec()));
+        }
+        let network = Arc::new(MockNetwork::new(blocks));
+        let mut config = Config::new(Duration::from_millis(200));
+        config.fetch_retry = RetryPolicy {
+            max_retries: 1,
+            backoff: Duration::from_millis(10),
+        };
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let pin = task::spawn({
+            let store = store.clone();
+            let cid = *root.cid();
+            async move { store.alias(b"root", Some(&cid)).await }
+        });
+        // b is only available once the first fetch timed out
+        let count = || {
+            let wants = network.wants.lock().unwrap();
+            wants.iter().filter(|cid| *cid == b.cid()).count()
+        };
+        while count() < 2 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *b.cid(),
+            b.data().to_vec(),
+        ));
+        pin.await.unwrap();
+        assert_eq!(store.pinned(b.cid()).await.unwrap(), Some(true));
+        let wants = network.wants.lock().unwrap();
+        assert_eq!(wants.iter().filter(|cid| *cid == a.cid()).count(), 1);
+    }
 
-        let mut net_config = NetworkConfig::new();
-        net_config.enable_mdns = bootstrap.is_empty();
-        net_config.boot_nodes = bootstrap;
-        net_config.allow_non_globals_in_dht = true;
+    #[async_std::test]
+    async fn test_sync_fetches_missing_dag() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_ipld_block(&ipld!({ "a": [] }));
+        let b = create_ipld_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_ipld_block(&ipld!({ "c": [a.cid()] }));
+        let root = create_ipld_block(&ipld!({ "root": [b.cid(), c.cid()] }));
+        let mut blocks = HashMap::new();
+        for block in &[&root, &a, &b] {
+            blocks.insert(*block.cid(), (PeerId::random(), block.data().to_vec()));
+        }
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::new(storage, network.clone(), Duration::from_secs(5));
+        store.insert(&c).await.unwrap();
+        assert_eq!(store.sync(root.cid()).await.unwrap(), 3);
+        assert!(store.is_complete(root.cid()).await.unwrap());
+        // the shared child is only fetched once
+        let wants = network.wants.lock().unwrap();
+        assert_eq!(wants.iter().filter(|cid| *cid == a.cid()).count(), 1);
+        drop(wants);
+        assert_eq!(store.sync(root.cid()).await.unwrap(), 0);
+    }
 
+    #[async_std::test]
+    async fn test_late_receiver_keeps_want() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
         let storage =
-            Arc::new(StorageService::open(&sled_config, cache_size, sweep_interval).unwrap());
-        let network = Arc::new(NetworkService::new(net_config).unwrap());
-        Ipfs::new(storage, network, network_timeout)
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage,
+            network.clone(),
+            Duration::from_millis(300),
+        );
+        let block = create_block(b"test_late_receiver_keeps_want");
+        let first = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        task::sleep(Duration::from_millis(400)).await;
+        let second = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        assert!(first.await.is_err());
+        // the second caller joined later and is still waiting
+        assert_eq!(store.wanted_count(), 1);
+        network.emit(NetworkEvent::ReceivedBlock(
+            PeerId::random(),
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        assert_eq!(second.await.unwrap().data(), block.data());
     }
 
-    fn create_block(bytes: &[u8]) -> Block<DefaultStoreParams> {
-        Block::encode(RawCodec, SHA2_256, bytes).unwrap()
+    #[async_std::test]
+    async fn test_shutdown() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let block = create_block(b"test_shutdown");
+        let get = task::spawn({
+            let store = store.clone();
+            let cid = *block.cid();
+            async move { store.get(&cid).await }
+        });
+        while network.wants.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        let other = store.clone();
+        store.shutdown().await;
+        assert!(get.await.is_err());
+        assert_eq!(*network.canceled.lock().unwrap(), vec![*block.cid()]);
+        assert_eq!(other.wanted_count(), 0);
+        assert!(other.get(block.cid()).await.is_err());
+        // shutting down again resolves right away
+        other.shutdown().await;
     }
 
     #[async_std::test]
-    async fn test_local_store() {
+    async fn test_cancel_get() {
         env_logger::try_init().ok();
-        let store = create_store(vec![]);
-        let block = create_block(b"test_local_store");
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage,
+            network.clone(),
+            Duration::from_secs(60),
+        );
+        let block = create_block(b"test_cancel_get");
+        let get = store.get(block.cid());
+        // dropping the future gives up the get
+        assert!(async_std::future::timeout(Duration::from_millis(100), get)
+            .await
+            .is_err());
+        let start = Instant::now();
+        while store.wanted_count() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*network.canceled.lock().unwrap(), vec![*block.cid()]);
+    }
+
+    #[async_std::test]
+    async fn test_want_after_receipt() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let inner = StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap();
+        let storage = Arc::new(FlakyStorage {
+            inner,
+            failures: AtomicUsize::new(0),
+            read_failures: AtomicUsize::new(0),
+            insert_delay: Duration::from_millis(0),
+        });
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.read_error_policy = ReadErrorPolicy::FetchOnTransient;
+        let store =
+            Ipfs::<DefaultStoreParams, _, _>::with_config(storage.clone(), network.clone(), config);
+        let block = create_block(b"test_want_after_receipt");
         store.insert(&block).await.unwrap();
-        let block2 = store.get(block.cid()).await.unwrap();
-        assert_eq!(block.data(), block2.data());
+        // the caller misses the block, which is stored by the time the task
+        // handles the want
+        storage.read_failures.store(1, Ordering::SeqCst);
+        let (fetched, peer_id) = store.get_with_provenance(block.cid()).await.unwrap();
+        assert_eq!(fetched.data(), block.data());
+        assert_eq!(peer_id, None);
+        assert!(network.wants.lock().unwrap().is_empty());
     }
 
     #[async_std::test]
-    #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github actions
-    async fn test_exchange_mdns() {
+    async fn test_aliases() {
         env_logger::try_init().ok();
-        let store1 = create_store(vec![]);
-        let store2 = create_store(vec![]);
-        let block = create_block(b"test_exchange_mdns");
-        store1.insert(&block).await.unwrap();
-        let block2 = store2.get(block.cid()).await.unwrap();
-        assert_eq!(block.data(), block2.data());
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let a = create_block(b"test_aliases_a");
+        let b = create_block(b"test_aliases_b");
+        store.insert(&a).await.unwrap();
+        store.insert(&b).await.unwrap();
+        store.alias(b"a", Some(a.cid())).await.unwrap();
+        store.alias(b"b", Some(b.cid())).await.unwrap();
+        store.alias(b"c", Some(a.cid())).await.unwrap();
+        store.alias(b"c", None).await.unwrap();
+        let aliases: Vec<_> = store.aliases().try_collect().await.unwrap();
+        assert_eq!(
+            aliases,
+            vec![(b"a".to_vec(), *a.cid()), (b"b".to_vec(), *b.cid())]
+        );
     }
 
     #[async_std::test]
-    #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github action
-    async fn test_received_want_before_insert() {
+    async fn test_blocks() {
         env_logger::try_init().ok();
-        let store1 = create_store(vec![]);
-        let store2 = create_store(vec![]);
-        let block = create_block(b"test_received_want_before_insert");
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let a = create_block(b"test_blocks_a");
+        let b = create_block(b"test_blocks_b");
+        store.insert(&a).await.unwrap();
+        store.insert(&b).await.unwrap();
+        let mut blocks: Vec<_> = store.blocks().try_collect().await.unwrap();
+        blocks.sort_by_key(|cid| cid.to_bytes());
+        let mut expected = vec![*a.cid(), *b.cid()];
+        expected.sort_by_key(|cid| cid.to_bytes());
+        assert_eq!(blocks, expected);
+    }
 
-        let get_cid = *block.cid();
-        let get = task::spawn(async move { store2.get(&get_cid).await });
+    #[async_std::test]
+    async fn test_verify_on_read() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.verify_on_read = VerifyPolicy::Always;
+        config.remove_corrupt = true;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage.clone(), network, config);
+        let a = create_block(b"test_verify_on_read_a");
+        let b = create_block(b"test_verify_on_read_b");
+        store.insert(&a).await.unwrap();
+        assert_eq!(store.get(a.cid()).await.unwrap().data(), a.data());
+        // the stored bytes don't match the cid
+        let corrupt = Block::new_unchecked(*b.cid(), a.data().to_vec());
+        storage.insert(&corrupt).unwrap();
+        let err = store.get(b.cid()).await.unwrap_err();
+        assert!(err.downcast_ref::<CorruptBlock>().is_some());
+        while store.has(b.cid()).unwrap() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
 
-        task::sleep(Duration::from_millis(100)).await;
+    #[async_std::test]
+    async fn test_get_pipeline() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.pipeline_concurrency = 2;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let blocks: Vec<_> = (0..6u8)
+            .map(|i| create_block(&[b't', b'e', b's', b't', i]))
+            .collect();
+        let cids: Vec<_> = blocks.iter().map(|block| *block.cid()).collect();
+        let mut stream = store
+            .get_pipeline(futures::stream::iter(cids.clone()))
+            .boxed();
+        let peer_id = PeerId::random();
+        let mut served = 0;
+        let mut fetched = Vec::new();
+        while fetched.len() < blocks.len() {
+            let timeout = Duration::from_millis(50);
+            if let Ok(block) = async_std::future::timeout(timeout, stream.next()).await {
+                fetched.push(*block.unwrap().unwrap().cid());
+                continue;
+            }
+            // nothing is fetched while the fetches in flight aren't consumed
+            let wants = network.wants.lock().unwrap().clone();
+            assert!(wants.len() - served <= 2);
+            for cid in &wants[served..] {
+                let block = blocks.iter().find(|block| block.cid() == cid).unwrap();
+                let data = block.data().to_vec();
+                network.emit(NetworkEvent::ReceivedBlock(peer_id.clone(), *cid, data));
+            }
+            served = wants.len();
+        }
+        assert_eq!(fetched, cids);
+    }
 
-        store1.insert(&block).await.unwrap();
+    #[async_std::test]
+    async fn test_get_many() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_block(b"test_get_many_a");
+        let b = create_block(b"test_get_many_b");
+        let c = create_block(b"test_get_many_c");
+        let missing = create_block(b"test_get_many_missing");
+        let mut blocks = HashMap::new();
+        blocks.insert(*a.cid(), (PeerId::random(), a.data().to_vec()));
+        blocks.insert(*b.cid(), (PeerId::random(), b.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(
+            storage,
+            network.clone(),
+            Duration::from_millis(200),
+        );
+        store.insert(&c).await.unwrap();
+        let cids = [*a.cid(), *c.cid(), *b.cid()];
+        let fetched = store.get_many(&cids).await.unwrap();
+        let fetched: Vec<Cid> = fetched.iter().map(|block| *block.cid()).collect();
+        assert_eq!(fetched, cids);
+        assert!(!network.queried.lock().unwrap().contains(c.cid()));
 
-        let block2 = get.await.unwrap();
-        assert_eq!(block.data(), block2.data());
+        let start = Instant::now();
+        let err = store
+            .get_many(&[*missing.cid(), *a.cid()])
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        assert!(start.elapsed() < Duration::from_secs(1));
     }
 
     #[async_std::test]
-    async fn test_exchange_kad() {
-        let logger = env_logger::Builder::from_default_env().build();
-        async_log::Logger::wrap(logger, || {
-            let task_id = async_std::task::current().id();
-            format!("{}", task_id).parse().unwrap()
-        })
-        .start(log::LevelFilter::Trace)
-        .ok();
+    async fn test_transfer_stats() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let a = create_block(&[1u8; 100]);
+        let b = create_block(&[2u8; 50]);
+        let c = create_block(&[3u8; 10]);
+        let mut blocks = HashMap::new();
+        blocks.insert(*a.cid(), (PeerId::random(), a.data().to_vec()));
+        blocks.insert(*b.cid(), (PeerId::random(), b.data().to_vec()));
+        let network = Arc::new(MockNetwork::new(blocks));
+        let store = Ipfs::<DefaultStoreParams, _, _>::new(storage, network, Duration::from_secs(5));
+        store.insert(&c).await.unwrap();
+        let (_, stats) = store
+            .get_many_with_stats(&[*a.cid(), *b.cid(), *c.cid()])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            TransferStats {
+                blocks: 3,
+                bytes: 150,
+                from_cache: 1,
+                hot_hits: 0,
+                cold_hits: 1,
+                from_network: 2,
+            }
+        );
+    }
 
-        let store = create_store(vec![]);
-        // make sure bootstrap node has started
-        task::sleep(Duration::from_millis(1000)).await;
-        let bootstrap = vec![(
-            store.external_addresses()[0].clone(),
-            store.local_peer_id().clone(),
-        )];
-        let store1 = create_store(bootstrap.clone());
-        let store2 = create_store(bootstrap);
-        let block = create_block(b"test_exchange_kad");
-        store1.insert(&block).await.unwrap();
-        // wait for entry to propagate
-        task::sleep(Duration::from_millis(1000)).await;
-        let block2 = store2.get(block.cid()).await.unwrap();
-        assert_eq!(block.data(), block2.data());
+    #[async_std::test]
+    async fn test_hot_cache() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.hot_cache_size = 1;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network, config);
+        let block = create_block(b"test_hot_cache");
+        store.insert(&block).await.unwrap();
+        let (_, stats) = store.get_many_with_stats(&[*block.cid()]).await.unwrap();
+        assert_eq!((stats.hot_hits, stats.cold_hits), (0, 1));
+        let (_, stats) = store.get_many_with_stats(&[*block.cid()]).await.unwrap();
+        assert_eq!((stats.hot_hits, stats.cold_hits), (1, 0));
+        assert_eq!(
+            store.cache_stats(),
+            CacheStats {
+                hot_hits: 1,
+                cold_hits: 1,
+            }
+        );
+        // blocks removed from the store are dropped from the hot cache
+        assert!(store.storage().remove(block.cid()).await.unwrap());
+        while store.hot_cache.lock().unwrap().get(block.cid()).is_some() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
     }
 
     #[async_std::test]
-    async fn test_provider_not_found() {
+    async fn test_wanted_count() {
         env_logger::try_init().ok();
-        let store1 = create_store(vec![]);
-        let block = create_block(b"test_provider_not_found");
-        if store1
-            .get(block.cid())
-            .await
-            .unwrap_err()
-            .downcast_ref::<BlockNotFound>()
-            .is_none()
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        assert_eq!(store.wanted_count(), 0);
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(&[i])).collect();
+        for cid in blocks
+            .iter()
+            .map(|block| *block.cid())
+            .chain(Some(*blocks[0].cid()))
         {
-            panic!("expected block not found error");
+            let store = store.clone();
+            task::spawn(async move { store.get(&cid).await });
+        }
+        while store.wanted_count() < 3 {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.wanted_count(), 3);
+    }
+
+    #[async_std::test]
+    async fn test_is_bootstrapped() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        assert!(!store.is_bootstrapped());
+        network.emit(NetworkEvent::BootstrapComplete);
+        while !store.is_bootstrapped() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_fallback_bootstrap() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let boot_node = PeerId::random();
+        let mut config = Config::new(Duration::from_secs(5));
+        config.fallback_boot_nodes = vec![(
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            boot_node.clone(),
+        )];
+        config.fallback_timeout = Duration::from_millis(100);
+        let _store =
+            Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        assert!(network.bootstrapped.lock().unwrap().is_empty());
+        task::sleep(Duration::from_millis(500)).await;
+        assert_eq!(*network.bootstrapped.lock().unwrap(), vec![boot_node]);
+    }
+
+    #[async_std::test]
+    async fn test_unsolicited_cache() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Cache);
+        let block = create_block(b"test_unsolicited_cache");
+        let peer_id = PeerId::random();
+        network.emit(NetworkEvent::ReceivedBlock(
+            peer_id,
+            *block.cid(),
+            block.data().to_vec(),
+        ));
+        while store.pinned(block.cid()).await.unwrap().is_none() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(store.pinned(block.cid()).await.unwrap(), Some(false));
+    }
+
+    #[async_std::test]
+    async fn test_unsolicited_penalize() {
+        env_logger::try_init().ok();
+        let (network, store) = create_mock_store(UnsolicitedPolicy::Penalize);
+        let block = create_block(b"test_unsolicited_penalize");
+        store.insert(&block).await.unwrap();
+        let spammer = PeerId::random();
+        let peer_id = PeerId::random();
+        let unsolicited = create_block(b"unsolicited");
+        network.emit(NetworkEvent::ReceivedBlock(
+            spammer.clone(),
+            *unsolicited.cid(),
+            unsolicited.data().to_vec(),
+        ));
+        network.emit(NetworkEvent::ReceivedWant(spammer, *block.cid(), 1000));
+        network.emit(NetworkEvent::ReceivedWant(
+            peer_id.clone(),
+            *block.cid(),
+            1000,
+        ));
+        while network.sent.lock().unwrap().is_empty() {
+            task::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(*network.sent.lock().unwrap(), vec![(peer_id, *block.cid())]);
+        assert_eq!(store.pinned(unsolicited.cid()).await.unwrap(), None);
     }
 
     macro_rules! assert_pinned {
@@ -458,6 +4358,112 @@ mod tests {
         Block::encode(DagCborCodec, SHA2_256, ipld).unwrap()
     }
 
+    #[async_std::test]
+    async fn test_resolve_path_unsupported_codec() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        // 0x90 is the eth-block codec, which isn't in the default store params
+        let data = b"test_resolve_path_unsupported_codec".to_vec();
+        let unsupported = store
+            .insert_reader(&data[..], 0x90, SHA2_256)
+            .await
+            .unwrap();
+        let root = create_ipld_block(&ipld!({ "a": &unsupported, "b": { "c": 1 } }));
+        store.insert(&root).await.unwrap();
+        let path = |segments: &[&str]| segments.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            store
+                .resolve_path(root.cid(), &path(&["b", "c"]))
+                .await
+                .unwrap(),
+            Ipld::Integer(1)
+        );
+        let err = store
+            .resolve_path(root.cid(), &path(&["a"]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<UnsupportedCodec>().unwrap().0, 0x90);
+    }
+
+    #[async_std::test]
+    async fn test_dag_diff() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let shared_leaf = create_ipld_block(&ipld!({ "leaf": "shared" }));
+        let shared = create_ipld_block(&ipld!({ "subtree": [shared_leaf.cid()] }));
+        let old_leaf = create_ipld_block(&ipld!({ "leaf": "old" }));
+        let new_leaf = create_ipld_block(&ipld!({ "leaf": "new" }));
+        let base = create_ipld_block(&ipld!({ "a": shared.cid(), "b": old_leaf.cid() }));
+        let target = create_ipld_block(&ipld!({ "a": shared.cid(), "b": new_leaf.cid() }));
+        for block in &[&shared_leaf, &shared, &old_leaf, &new_leaf, &base, &target] {
+            store.insert(block).await.unwrap();
+        }
+        let diff: HashSet<Cid> = store
+            .dag_diff(base.cid(), target.cid())
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            diff,
+            [*target.cid(), *new_leaf.cid()].iter().copied().collect()
+        );
+        assert!(store
+            .dag_diff(target.cid(), target.cid())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_max_links() {
+        env_logger::try_init().ok();
+        let sled_config = sled::Config::new().temporary(true);
+        let storage =
+            Arc::new(StorageService::open(&sled_config, 10, Duration::from_millis(10000)).unwrap());
+        let network = Arc::new(MockNetwork::new(HashMap::new()));
+        let mut config = Config::new(Duration::from_secs(5));
+        config.max_links = 2;
+        let store = Ipfs::<DefaultStoreParams, _, _>::with_config(storage, network.clone(), config);
+        let a = create_ipld_block(&ipld!({ "leaf": "a" }));
+        let b = create_ipld_block(&ipld!({ "leaf": "b" }));
+        let c = create_ipld_block(&ipld!({ "leaf": "c" }));
+        let root = create_ipld_block(&ipld!([a.cid(), b.cid(), c.cid()]));
+        store.insert(&root).await.unwrap();
+        let err = store.sync(root.cid()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TooManyLinks>(),
+            Some(&TooManyLinks(*root.cid()))
+        );
+        // no fetches were enqueued for the links
+        assert!(network.wants.lock().unwrap().is_empty());
+        let err = store
+            .export_selective(root.cid(), Selector::All, Vec::new())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<TooManyLinks>().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_debug_block() {
+        env_logger::try_init().ok();
+        let (_, store) = create_mock_store(UnsolicitedPolicy::Drop);
+        let raw = create_block(b"test_debug_block");
+        let block = create_ipld_block(&ipld!({
+            "name": "test",
+            "size": 16,
+            "links": [raw.cid()],
+        }));
+        store.insert(&raw).await.unwrap();
+        store.insert(&block).await.unwrap();
+        let dump = store.debug_block(block.cid()).await.unwrap();
+        assert!(dump.contains("\"name\": \"test\""));
+        assert!(dump.contains("\"size\": 16"));
+        assert!(dump.contains(&format!("{{\"/\": \"{}\"}}", raw.cid())));
+        let dump = store.debug_block(raw.cid()).await.unwrap();
+        assert!(dump.starts_with("<16 bytes: 746573745f"));
+    }
+
     #[async_std::test]
     async fn test_sync() {
         env_logger::try_init().ok();
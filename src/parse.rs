@@ -0,0 +1,88 @@
+use ipfs_embed_core::Cid;
+use multibase::Base;
+use std::convert::TryFrom;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum CidParseError {
+    #[error("Empty cid.")]
+    Empty,
+    #[error("Invalid multibase encoding.")]
+    InvalidBase,
+    #[error("Unsupported cid version {0}.")]
+    InvalidVersion(u64),
+    #[error("Invalid codec.")]
+    InvalidCodec,
+    #[error("Invalid multihash.")]
+    InvalidMultihash,
+}
+
+/// Reads an unsigned varint, returning it and the remaining bytes.
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut n = 0u64;
+    for (i, b) in bytes.iter().enumerate().take(9) {
+        n |= ((b & 0x7f) as u64) << (i * 7);
+        if b & 0x80 == 0 {
+            return Some((n, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+/// Parses a cid from its string representation. CIDv0 is expected to be base58btc
+/// encoded without a multibase prefix, CIDv1 can use any multibase.
+pub fn parse_cid(s: &str) -> Result<Cid, CidParseError> {
+    if s.is_empty() {
+        return Err(CidParseError::Empty);
+    }
+    if s.len() == 46 && s.starts_with("Qm") {
+        let bytes = Base::Base58Btc
+            .decode(s)
+            .map_err(|_| CidParseError::InvalidBase)?;
+        return Cid::try_from(bytes).map_err(|_| CidParseError::InvalidMultihash);
+    }
+    let (_, bytes) = multibase::decode(s).map_err(|_| CidParseError::InvalidBase)?;
+    let (version, rest) = read_varint(&bytes).ok_or(CidParseError::InvalidVersion(0))?;
+    if version != 1 {
+        return Err(CidParseError::InvalidVersion(version));
+    }
+    read_varint(rest).ok_or(CidParseError::InvalidCodec)?;
+    Cid::try_from(bytes).map_err(|_| CidParseError::InvalidMultihash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIDV0: &str = "QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u";
+    const CIDV1: &str = "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+
+    #[test]
+    fn test_parse_cid() {
+        let cid = parse_cid(CIDV0).unwrap();
+        assert_eq!(cid.to_string(), CIDV0);
+        let cid = parse_cid(CIDV1).unwrap();
+        assert_eq!(cid.to_string(), CIDV1);
+    }
+
+    #[test]
+    fn test_parse_cid_errors() {
+        assert_eq!(parse_cid(""), Err(CidParseError::Empty));
+        // 0 isn't a base58 character
+        assert_eq!(
+            parse_cid("Qm0ATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u"),
+            Err(CidParseError::InvalidBase)
+        );
+        // unknown multibase prefix
+        assert_eq!(parse_cid("!abc"), Err(CidParseError::InvalidBase));
+        // version 2
+        let s = multibase::encode(Base::Base32Lower, &[2, 0x55, 0x12, 0x00]);
+        assert_eq!(parse_cid(&s), Err(CidParseError::InvalidVersion(2)));
+        // unterminated codec varint
+        let s = multibase::encode(Base::Base32Lower, &[1, 0xff]);
+        assert_eq!(parse_cid(&s), Err(CidParseError::InvalidCodec));
+        // truncated digest
+        let s = multibase::encode(Base::Base32Lower, &[1, 0x55, 0x12, 0x20, 0xff]);
+        assert_eq!(parse_cid(&s), Err(CidParseError::InvalidMultihash));
+    }
+}
@@ -0,0 +1,32 @@
+use libipld::ipld::Ipld;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Path segment {0:?} not found.")]
+pub struct PathNotFound(pub String);
+
+/// A block on a path has a codec that the store params can't decode.
+#[derive(Debug, Error)]
+#[error("Unsupported codec {0:#x}.")]
+pub struct UnsupportedCodec(pub u64);
+
+/// Selects a part of a dag.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Selector {
+    /// The whole dag.
+    All,
+    /// All blocks at most `n` links away from the root.
+    Depth(usize),
+    /// The blocks along a path of map keys and list indices, and the part of the
+    /// dag below the end of the path selected by the inner selector.
+    Path(Vec<String>, Box<Selector>),
+}
+
+/// Follows a path segment inside a block.
+pub(crate) fn step<'a>(ipld: &'a Ipld, segment: &str) -> Option<&'a Ipld> {
+    match ipld {
+        Ipld::Map(map) => map.get(segment),
+        Ipld::List(list) => list.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    }
+}